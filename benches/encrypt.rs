@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Cost of the per-message ephemeral key agreement compared to the AEAD.
+//! "ephemeral" is `Secret::encrypt`, which does a new X25519 agreement
+//! and key derivation for each message. "session" encrypts with a
+//! `SessionKey` derived once from `AlphaSecret::agree`, so it only pays
+//! for the AEAD. "agree" is the key agreement alone, the difference to
+//! "ephemeral" is mostly the key derivation (PBKDF2 unless `Kdf::Hkdf`
+//! is chosen). For small messages these fixed costs dominate, which is
+//! when caching a session pays off.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use stackment_crypto_mod::crypto::alpha::AlphaSecret;
+use stackment_crypto_mod::crypto::{Secret, SessionKey, SessionRole};
+
+const SIZES: &[usize] = &[0, 64, 1024, 1024 * 1024];
+
+fn encrypt(c: &mut Criterion) {
+    let sender = AlphaSecret::new();
+    let recipient = AlphaSecret::new();
+    let mut session = SessionKey::from_shared(
+        &sender.agree(recipient.public_key()),
+        SessionRole::Initiator,
+    )
+    .unwrap();
+
+    let mut group = c.benchmark_group("encrypt");
+    for size in SIZES {
+        let plain = vec![0x42; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::new("ephemeral", size), &plain, |b, plain| {
+            b.iter(|| sender.encrypt(plain, recipient.public_key()).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("session", size), &plain, |b, plain| {
+            b.iter(|| session.encrypt_next(plain).unwrap())
+        });
+    }
+    group.finish();
+
+    c.bench_function("agree", |b| b.iter(|| sender.agree(recipient.public_key())));
+}
+
+criterion_group!(benches, encrypt);
+criterion_main!(benches);
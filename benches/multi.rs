@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use stackment_crypto_mod::crypto::alpha::{AlphaPublic, AlphaSecret};
+
+fn multi(c: &mut Criterion) {
+    let sender = AlphaSecret::new();
+    let recipients: Vec<AlphaSecret> = (0..100).map(|_| AlphaSecret::new()).collect();
+    let peers: Vec<&AlphaPublic> = recipients.iter().map(|r| r.public_key()).collect();
+    let msg = b"benchmark message";
+
+    c.bench_function("encrypt for 100 recipients", |b| {
+        b.iter(|| sender.encrypt_to_many(msg, &peers).unwrap())
+    });
+    let enc = sender.encrypt_to_many(msg, &peers).unwrap();
+    let last = recipients.last().unwrap();
+    c.bench_function("decrypt as last of 100 recipients", |b| {
+        b.iter(|| last.decrypt_from_many(&enc).unwrap())
+    });
+}
+
+criterion_group!(benches, multi);
+criterion_main!(benches);
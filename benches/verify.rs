@@ -0,0 +1,33 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! "per call" is `AlphaPublic::verify_raw`, which parses the key for
+//! each signature. "cached" reuses a `Verifier`, which decompressed the
+//! key once. "verifier" is the cost of constructing the `Verifier`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use stackment_crypto_mod::crypto::alpha::AlphaSecret;
+use stackment_crypto_mod::crypto::Secret;
+
+fn verify(c: &mut Criterion) {
+    let secret = AlphaSecret::new();
+    let msg = b"benchmark message";
+    let signature = secret.sign(&msg);
+    let public = secret.public_key();
+
+    c.bench_function("verify per call", |b| {
+        b.iter(|| public.verify_raw(msg, signature.as_bytes()).unwrap())
+    });
+    let verifier = public.verifier().unwrap();
+    c.bench_function("verify cached", |b| {
+        b.iter(|| verifier.verify(msg, signature.as_bytes()).unwrap())
+    });
+    c.bench_function("verifier", |b| b.iter(|| public.verifier().unwrap()));
+}
+
+criterion_group!(benches, verify);
+criterion_main!(benches);
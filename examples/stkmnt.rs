@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Command line tool using the public API end to end.
+//! Keys are stored as PEM files, signatures and ciphertexts are written
+//! as base64 to stdout.
+//!
+//! ```text
+//! stkmnt keygen <secret.pem>
+//! stkmnt pubkey <secret.pem>
+//! stkmnt sign <secret.pem> <file>
+//! stkmnt verify <public.pem> <file> <signature>
+//! stkmnt encrypt <secret.pem> <public.pem> <file>
+//! stkmnt decrypt <secret.pem> <file>
+//! ```
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Write;
+use std::process;
+
+use data_encoding::BASE64;
+
+use stackment_crypto_mod::crypto::alpha::{AlphaPublic, AlphaSecret};
+use stackment_crypto_mod::crypto::{pem, Encrypted, ParseOptions, Secret};
+
+const USAGE: &str = "usage: stkmnt keygen|pubkey|sign|verify|encrypt|decrypt <args>";
+
+fn read_secret(path: &str) -> Result<AlphaSecret, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let der = pem::decode(pem::SECRET_KEY_LABEL, &text).map_err(|e| e.to_string())?;
+    AlphaSecret::deserialize(&der, ParseOptions::strict()).map_err(|e| e.to_string())
+}
+
+fn read_public(path: &str) -> Result<AlphaPublic, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let raw = pem::decode(pem::PUBLIC_KEY_LABEL, &text).map_err(|e| e.to_string())?;
+    AlphaPublic::try_from(&raw[..]).map_err(|e| e.to_string())
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Runs the subcommand in `args` and returns the output for stdout
+fn run(args: &[String]) -> Result<Vec<u8>, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["keygen", secret_path] => {
+            let mut der = Vec::new();
+            AlphaSecret::new().serialize(&mut der);
+            fs::write(secret_path, pem::encode(pem::SECRET_KEY_LABEL, &der))
+                .map_err(|e| format!("{}: {}", secret_path, e))?;
+            Ok(Vec::new())
+        }
+        ["pubkey", secret_path] => {
+            let secret = read_secret(secret_path)?;
+            Ok(pem::encode(pem::PUBLIC_KEY_LABEL, &secret.public_bytes()).into_bytes())
+        }
+        ["sign", secret_path, path] => {
+            let signature = read_secret(secret_path)?.sign(&read_file(path)?);
+            Ok(format!("{}\n", BASE64.encode(signature.as_bytes())).into_bytes())
+        }
+        ["verify", public_path, path, signature] => {
+            let signature = BASE64
+                .decode(signature.as_bytes())
+                .map_err(|e| e.to_string())?;
+            read_public(public_path)?
+                .verify_raw(&read_file(path)?, &signature)
+                .map_err(|e| e.to_string())?;
+            Ok(b"OK\n".to_vec())
+        }
+        ["encrypt", secret_path, public_path, path] => {
+            let encrypted = read_secret(secret_path)?
+                .encrypt(&read_file(path)?, &read_public(public_path)?)
+                .map_err(|e| e.to_string())?;
+            let mut der = Vec::new();
+            encrypted.serialize(&mut der);
+            Ok(format!("{}\n", BASE64.encode(&der)).into_bytes())
+        }
+        ["decrypt", secret_path, path] => {
+            let text = read_file(path)?;
+            let der = BASE64
+                .decode(String::from_utf8_lossy(&text).trim().as_bytes())
+                .map_err(|e| e.to_string())?;
+            let encrypted =
+                Encrypted::deserialize(&der, ParseOptions::strict()).map_err(|e| e.to_string())?;
+            read_secret(secret_path)?
+                .decrypt(&encrypted)
+                .map_err(|e| e.to_string())
+        }
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(output) => std::io::stdout().write_all(&output).unwrap(),
+        Err(message) => {
+            eprintln!("stkmnt: {}", message);
+            process::exit(1);
+        }
+    }
+}
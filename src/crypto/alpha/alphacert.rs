@@ -4,26 +4,17 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-use std::ops::Deref;
-
-use std::fmt;
 use std::io::Read;
 use std::io::Write;
 
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
-};
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
+use chrono::{DateTime, Duration, Utc};
+use yasna::{self, models::GeneralizedTime};
 
-use super::alphasecret::AlphaSecret;
+use super::alphasecret::{AlphaPublic, AlphaSecret};
 
 use crate::crypto::{
-    validate_signature, Cert, DeviceCert, Fingerprint, IdentCert, Public, Secret, Trusted,
-    Untrusted,
+    validate_signature, Cert, Clock, DeviceCert, Fingerprint, IdentCert, ParseError, Public,
+    Secret, SystemClock, Untrusted, VerifyError,
 };
 
 /// Alpha certificate
@@ -31,11 +22,15 @@ use crate::crypto::{
 #[derive(Clone)]
 pub struct AlphaCert {
     raw: Vec<u8>,
-    signature_pubkey: Vec<u8>,
-    encryption_pubkey: Vec<u8>,
+    pubkey: AlphaPublic,
     issuer: Fingerprint,
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
 }
 
+/// Validity period of certificates created by `AlphaCert::new`
+pub const DEFAULT_VALIDITY_DAYS: i64 = 5 * 365;
+
 impl AsRef<[u8]> for AlphaCert {
     fn as_ref(&self) -> &[u8] {
         &self.raw
@@ -45,11 +40,57 @@ impl AsRef<[u8]> for AlphaCert {
 impl AlphaCert {
     /// Constructs a new AlphaCert from the given secret and issuer_secret.
     /// If the `issuer` is None, this generateds a selfsigned certificate.
+    /// The certificate is valid from now for `DEFAULT_VALIDITY_DAYS`.
     /// TODO: Maybe add purpose flags to the certificate
     pub fn new(
         secret: &AlphaSecret,
         issuer_secret: &AlphaSecret,
         issuer: Option<&AlphaCert>,
+    ) -> Self {
+        Self::new_with_clock(secret, issuer_secret, issuer, &SystemClock)
+    }
+
+    /// Like `new`, but valid from the current time of `clock`
+    pub fn new_with_clock(
+        secret: &AlphaSecret,
+        issuer_secret: &AlphaSecret,
+        issuer: Option<&AlphaCert>,
+        clock: &dyn Clock,
+    ) -> Self {
+        let not_before = clock.now();
+        let not_after = not_before + Duration::days(DEFAULT_VALIDITY_DAYS);
+        Self::encode(secret, issuer_secret, issuer, not_before, not_after)
+    }
+
+    /// Like `new`, but valid from `not_before` until `not_after`.
+    /// Fails if `not_after` is before `not_before`.
+    pub fn new_with_validity(
+        secret: &AlphaSecret,
+        issuer_secret: &AlphaSecret,
+        issuer: Option<&AlphaCert>,
+        not_before: DateTime<Utc>,
+        not_after: DateTime<Utc>,
+    ) -> Result<Self, ParseError> {
+        if not_after < not_before {
+            return Err(ParseError::InvalidValidity);
+        }
+        Ok(Self::encode(
+            secret,
+            issuer_secret,
+            issuer,
+            not_before,
+            not_after,
+        ))
+    }
+
+    /// Build and sign the certificate, the validity period is part of
+    /// the signed subject sequence.
+    fn encode(
+        secret: &AlphaSecret,
+        issuer_secret: &AlphaSecret,
+        issuer: Option<&AlphaCert>,
+        not_before: DateTime<Utc>,
+        not_after: DateTime<Utc>,
     ) -> Self {
         // TODO: fail if issuer is None and secret and issuer_secret differ!
         let ed25519_pubkey = secret.public_key().signing_public_key(); //secret.ed25519_keypair.public_key().as_ref();
@@ -58,10 +99,12 @@ impl AlphaCert {
         let cert_subject_der = yasna::construct_der(|writer| {
             writer.write_sequence(|writer| {
                 // subject sequence, to be signed
-                writer.next().write_generalized_time(
-                    // creation time
-                    &GeneralizedTime::from_datetime(&Utc::now()),
-                );
+                writer
+                    .next()
+                    .write_generalized_time(&GeneralizedTime::from_datetime(&not_before));
+                writer
+                    .next()
+                    .write_generalized_time(&GeneralizedTime::from_datetime(&not_after));
                 writer.next().write_bytes(ed25519_pubkey); // public key
                 writer.next().write_bytes(x25519_pubkey); // public key
                 if let Some(issuer) = issuer {
@@ -86,53 +129,128 @@ impl AlphaCert {
 
         Self {
             raw: cert_signed_der,
-            signature_pubkey: Vec::from(ed25519_pubkey),
-            encryption_pubkey: Vec::from(x25519_pubkey),
+            pubkey: *secret.public_key(),
             issuer: fingerprint,
+            not_before,
+            not_after,
         }
     }
 
+    /// Parse a certificate written by `serialize`.
+    /// Certificates which expire before they become valid are rejected.
+    /// The signature is not checked, use `is_valid` for this.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ParseError> {
+        let cert_data = yasna::parse_der(bytes, |reader| {
+            reader.read_sequence(|reader| {
+                let _version = reader.next().read_i64()?;
+                let cert_data = reader.next().read_der()?;
+                let _cert_signature = reader.next().read_bytes()?;
+                Ok(cert_data)
+            })
+        })?;
+        let (not_before, not_after, sign_pubkey, crypt_pubkey, issuer) =
+            yasna::parse_der(&cert_data, |reader| {
+                reader.read_sequence(|reader| {
+                    let not_before = *reader.next().read_generalized_time()?.datetime();
+                    let not_after = *reader.next().read_generalized_time()?.datetime();
+                    let sign_pubkey = reader.next().read_bytes()?;
+                    let crypt_pubkey = reader.next().read_bytes()?;
+                    let issuer = reader.read_optional(|reader| reader.read_bytes())?;
+                    Ok((not_before, not_after, sign_pubkey, crypt_pubkey, issuer))
+                })
+            })?;
+        if not_after < not_before {
+            return Err(ParseError::InvalidValidity);
+        }
+        if sign_pubkey.len() != 32
+            || crypt_pubkey.len() != 32
+            || issuer.as_ref().is_some_and(|i| i.len() != 32)
+        {
+            return Err(ParseError::InvalidKey(String::from("Invalid key length")));
+        }
+        let mut ed25519_pubkey = [0; 32];
+        ed25519_pubkey.copy_from_slice(&sign_pubkey);
+        let mut x25519_pubkey = [0; 32];
+        x25519_pubkey.copy_from_slice(&crypt_pubkey);
+        let issuer = match issuer {
+            Some(issuer) => {
+                let mut inner = [0; 32];
+                inner.copy_from_slice(&issuer);
+                Fingerprint::sha256(inner)
+            }
+            None => Fingerprint::from(&bytes),
+        };
+        Ok(Self {
+            raw: Vec::from(bytes),
+            pubkey: AlphaPublic::from_raw(ed25519_pubkey, x25519_pubkey),
+            issuer,
+            not_before,
+            not_after,
+        })
+    }
+
+    /// Same as `parse`
+    pub fn from_vec(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::parse(bytes)
+    }
 
-    pub fn from_vec(bytes: &Vec<u8>) -> Self {
-        let mut raw = bytes;
+    /// Returns the time from which the certificate is valid
+    pub fn not_before(&self) -> DateTime<Utc> {
+        self.not_before
+    }
+
+    /// Returns the time the certificate expires
+    pub fn not_after(&self) -> DateTime<Utc> {
+        self.not_after
+    }
 
-        let asn = yasna::parse_der(&raw, |reader| {
+    /// Like `Cert::is_valid`, but tells why validation failed.
+    /// A malformed signing key in `issuer_cert` is reported as
+    /// `VerifyError::MalformedEmbeddedKey`.
+    pub fn validate(&self, issuer_cert: &dyn Cert) -> Result<(), VerifyError> {
+        let asn = yasna::parse_der(&self.raw, |reader| {
             reader.read_sequence(|reader| {
                 let _version = reader.next().read_i64()?;
 
                 let cert_data = reader.next().read_der()?;
                 let cert_signature = reader.next().read_bytes()?;
 
-                yasna::parse_der(&cert_data, |reader| {
-                    reader.read_sequence(|reader| {
-                        let cert_date = reader.next().read_generalized_time()?;
-                        let sign_pubkey = reader.next().read_bytes()?;
-                        let crypt_pubkey = reader.next().read_bytes()?;
-                        // TODO: check for optional issuer
-                        unimplemented!();
-                        let issuer = reader.next().read_bytes()?;
-
-                        let mut inner: [u8; 32] = [0; 32];
-
-                        inner.copy_from_slice(&issuer[0..32]);
-
-                        Ok(Self {
-                            raw: raw.clone(),
-                            signature_pubkey: sign_pubkey,
-                            encryption_pubkey: crypt_pubkey,
-                            issuer: Fingerprint { inner },
-                        })
-                    })
-                })
+                Ok((cert_data, cert_signature))
             })
         });
-        asn.unwrap()
+        let (cert_data, cert_signature) = asn.map_err(|_| VerifyError::MalformedCert)?;
+        event!(
+            debug,
+            "Validating cert {} against issuer {}",
+            self.fingerprint(),
+            issuer_cert.fingerprint()
+        );
+        let result = if self.issuer_fingerprint() != issuer_cert.fingerprint() {
+            Err(VerifyError::WrongIssuer)
+        } else {
+            validate_signature(
+                issuer_cert.signing_public_key(),
+                &cert_data,
+                &cert_signature,
+            )
+        };
+        if let Err(ref err) = result {
+            event!(
+                warn,
+                "Cert {} failed validation against issuer {}: {}",
+                self.fingerprint(),
+                issuer_cert.fingerprint(),
+                err
+            );
+        }
+        result
     }
 
-    pub fn from_stream(stream: &mut dyn Read) -> Self {
+    /// Read a certificate from `stream` until its end and parse it
+    pub fn from_stream(stream: &mut dyn Read) -> crate::crypto::Result<Self> {
         let mut raw = Vec::new();
-        stream.read_to_end(&mut raw).unwrap();
-        Self::from_vec(&raw)
+        stream.read_to_end(&mut raw)?;
+        Ok(Self::parse(&raw)?)
     }
 
     /*
@@ -244,27 +362,257 @@ impl Cert for AlphaCert {
     }
 
     fn signing_public_key(&self) -> &[u8] {
-        &self.signature_pubkey
+        self.pubkey.signing_public_key()
+    }
+
+    fn public_key(&self) -> &dyn Public {
+        &self.pubkey
+    }
+
+    fn expires(&self) -> Option<DateTime<Utc>> {
+        Some(self.not_after)
+    }
+
+    fn valid_from(&self) -> Option<DateTime<Utc>> {
+        Some(self.not_before)
     }
 
     fn is_valid(&self, issuer_cert: &dyn Cert) -> bool {
-        let asn = yasna::parse_der(&self.raw, |reader| {
-            reader.read_sequence(|reader| {
-                let _version = reader.next().read_i64()?;
+        self.validate(issuer_cert).is_ok()
+    }
+}
 
-                let cert_data = reader.next().read_der()?;
-                let cert_signature = reader.next().read_bytes()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{EncryptError, FixedClock};
+    use chrono::TimeZone;
+
+    #[test]
+    fn validity_is_signed_and_parsed() {
+        let isec = AlphaSecret::new();
+        let not_before = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let not_after = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let cert = AlphaCert::new_with_validity(&isec, &isec, None, not_before, not_after).unwrap();
+        let parsed = AlphaCert::parse(cert.as_ref()).unwrap();
+        assert_eq!(parsed.not_before(), not_before);
+        assert_eq!(parsed.not_after(), not_after);
+        assert_eq!(parsed.expires(), Some(not_after));
+        assert_eq!(parsed.issuer_fingerprint(), cert.fingerprint());
+        assert!(parsed.is_valid(&cert));
+    }
 
-                Ok((cert_data, cert_signature))
-            })
-        });
-        let (cert_data, cert_signature) = asn.expect("Invalid ASN1");
-        self.issuer_fingerprint() == issuer_cert.fingerprint()
-            && validate_signature(
-                issuer_cert.signing_public_key(),
-                &cert_data,
-                &cert_signature,
-            )
-            .is_ok()
+    #[test]
+    fn cert_and_key_fingerprints() {
+        let isec = AlphaSecret::new();
+        let not_before = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let short = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let long = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+        let a = AlphaCert::new_with_validity(&isec, &isec, None, not_before, short).unwrap();
+        let b = AlphaCert::new_with_validity(&isec, &isec, None, not_before, long).unwrap();
+        assert_eq!(a.pubkey.fingerprint(), b.pubkey.fingerprint());
+        assert_eq!(a.pubkey.fingerprint(), isec.public_key().fingerprint());
+        assert_ne!(a.fingerprint(), b.fingerprint());
+        assert_ne!(a.fingerprint(), a.pubkey.fingerprint());
+    }
+
+    #[test]
+    fn malformed_cert_is_an_error() {
+        let isec = AlphaSecret::new();
+        let cert = AlphaCert::new(&isec, &isec, None);
+        let truncated = &cert.raw[..cert.raw.len() - 1];
+        assert!(AlphaCert::from_vec(truncated).is_err());
+        assert!(AlphaCert::from_stream(&mut &truncated[..]).is_err());
+        assert!(AlphaCert::from_stream(&mut &cert.raw[..]).is_ok());
+
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("read failed"))
+            }
+        }
+        assert!(AlphaCert::from_stream(&mut FailingReader).is_err());
+
+        let mut broken = cert.clone();
+        broken.raw = vec![0x30, 0x00];
+        match broken.validate(&cert) {
+            Err(VerifyError::MalformedCert) => {}
+            _ => panic!("malformed cert validated"),
+        }
+        assert!(!broken.is_valid(&cert));
+    }
+
+    #[test]
+    fn public_key_matches_cert() {
+        let isec = AlphaSecret::new();
+        let other = AlphaSecret::new();
+        let cert = AlphaCert::new(&isec, &isec, None);
+        let other_cert = AlphaCert::new(&other, &isec, None);
+        assert!(isec.public_key().matches_cert(&cert));
+        assert!(!isec.public_key().matches_cert(&other_cert));
+        assert!(other.public_key().matches_cert(&other_cert));
+    }
+
+    #[test]
+    fn subject_public_without_validation() {
+        let isec = AlphaSecret::new();
+        let dsec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let mut cert = AlphaCert::new(&dsec, &isec, Some(&icert));
+        // Break the signature, the embedded key is still returned
+        let last = cert.raw.len() - 1;
+        cert.raw[last] ^= 1;
+        let cert = AlphaCert::parse(&cert.raw).unwrap();
+        assert!(!cert.is_valid(&icert));
+        let subject = cert.subject_public_unchecked();
+        assert_eq!(subject.fingerprint(), dsec.public_key().fingerprint());
+        assert!(subject.matches_cert(&cert));
+    }
+
+    #[test]
+    fn ident_cert_self_signature_checked_on_parse() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let parsed = IdentCert::from_der(icert.as_ref()).unwrap();
+        assert_eq!(parsed.fingerprint(), icert.fingerprint());
+
+        let mut raw = icert.raw.clone();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        match IdentCert::from_der(&raw) {
+            Err(ParseError::BadSelfSignature) => {}
+            _ => panic!("corrupted self-signature accepted"),
+        }
+        // Not self-signed
+        let dcert = AlphaCert::new(&AlphaSecret::new(), &isec, Some(&icert));
+        assert!(IdentCert::from_der(dcert.as_ref()).is_err());
+    }
+
+    #[test]
+    fn reject_inverted_validity() {
+        let isec = AlphaSecret::new();
+        let not_before = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let not_after = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        assert!(AlphaCert::new_with_validity(&isec, &isec, None, not_before, not_after).is_err());
+        // Properly signed, but expires before it becomes valid
+        let cert = AlphaCert::encode(&isec, &isec, None, not_before, not_after);
+        match AlphaCert::parse(cert.as_ref()) {
+            Err(ParseError::InvalidValidity) => {}
+            _ => panic!("inverted validity accepted"),
+        }
+    }
+
+    #[test]
+    fn issue_and_validate_with_clock() {
+        let isec = AlphaSecret::from_parts([1; 32], [2; 32]);
+        let dsec = AlphaSecret::from_parts([3; 32], [4; 32]);
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let clock = FixedClock::new(start);
+        let icert = AlphaCert::new_with_clock(&isec, &isec, None, &clock);
+        let dcert = AlphaCert::new_with_clock(&dsec, &isec, Some(&icert), &clock);
+        assert_eq!(dcert.not_before(), start);
+        assert_eq!(
+            dcert.not_after(),
+            start + Duration::days(DEFAULT_VALIDITY_DAYS)
+        );
+        // Same keys and time give the same certificate
+        let again = AlphaCert::new_with_clock(&dsec, &isec, Some(&icert), &clock);
+        assert_eq!(again.as_ref(), dcert.as_ref());
+        assert!(dcert.is_valid(&icert));
+
+        assert!(dcert.is_valid_with_clock(&clock));
+        clock.advance(Duration::days(DEFAULT_VALIDITY_DAYS));
+        assert!(dcert.is_valid_with_clock(&clock));
+        clock.advance(Duration::seconds(1));
+        assert!(!dcert.is_valid_with_clock(&clock));
+        assert!(!dcert.is_valid_with_clock(&FixedClock::new(start - Duration::seconds(1))));
+    }
+
+    #[test]
+    fn encrypt_to_cert_validity() {
+        let isec = AlphaSecret::new();
+        let peer = AlphaSecret::new();
+        let not_before = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let not_after = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let cert = AlphaCert::new_with_validity(&peer, &isec, None, not_before, not_after).unwrap();
+
+        let valid = Utc.with_ymd_and_hms(2020, 6, 1, 0, 0, 0).unwrap();
+        let crypted = isec.encrypt_to_cert(b"hello", &cert, valid).unwrap();
+        assert_eq!(peer.decrypt(&crypted).unwrap(), b"hello");
+
+        let expired = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 1).unwrap();
+        match isec.encrypt_to_cert(b"hello", &cert, expired) {
+            Err(EncryptError::CertNotValid) => {}
+            _ => panic!("encrypted to expired cert"),
+        }
+        let not_yet_valid = Utc.with_ymd_and_hms(2019, 12, 31, 23, 59, 59).unwrap();
+        match isec.encrypt_to_cert(b"hello", &cert, not_yet_valid) {
+            Err(EncryptError::CertNotValid) => {}
+            _ => panic!("encrypted to not yet valid cert"),
+        }
+    }
+
+    /// Issuer certificate with a truncated signing key, as from a corrupt
+    /// certificate store
+    struct TruncatedKeyCert {
+        inner: AlphaCert,
+    }
+
+    impl AsRef<[u8]> for TruncatedKeyCert {
+        fn as_ref(&self) -> &[u8] {
+            self.inner.as_ref()
+        }
+    }
+
+    impl Cert for TruncatedKeyCert {
+        fn serialize(&self, stream: &mut dyn Write) {
+            self.inner.serialize(stream)
+        }
+        fn issuer_fingerprint(&self) -> Fingerprint {
+            self.inner.issuer_fingerprint()
+        }
+        fn signing_public_key(&self) -> &[u8] {
+            &self.inner.signing_public_key()[..31]
+        }
+        fn public_key(&self) -> &dyn Public {
+            self.inner.public_key()
+        }
+        fn expires(&self) -> Option<DateTime<Utc>> {
+            self.inner.expires()
+        }
+        fn valid_from(&self) -> Option<DateTime<Utc>> {
+            self.inner.valid_from()
+        }
+        fn is_valid(&self, issuer_cert: &dyn Cert) -> bool {
+            self.inner.is_valid(issuer_cert)
+        }
+    }
+
+    #[test]
+    fn malformed_issuer_key() {
+        let isec = AlphaSecret::new();
+        let dsec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let dcert = AlphaCert::new(&dsec, &isec, Some(&icert));
+        assert!(dcert.validate(&icert).is_ok());
+
+        let truncated = TruncatedKeyCert {
+            inner: icert.clone(),
+        };
+        match dcert.validate(&truncated) {
+            Err(VerifyError::MalformedEmbeddedKey(31)) => {}
+            _ => panic!("truncated key not reported"),
+        }
+        assert!(!dcert.is_valid(&truncated));
+
+        let forged = AlphaCert::new(&dsec, &dsec, Some(&icert));
+        match forged.validate(&icert) {
+            Err(VerifyError::BadSignature) => {}
+            _ => panic!("forged cert accepted"),
+        }
+        match dcert.validate(&AlphaCert::new(&dsec, &dsec, None)) {
+            Err(VerifyError::WrongIssuer) => {}
+            _ => panic!("wrong issuer accepted"),
+        }
     }
 }
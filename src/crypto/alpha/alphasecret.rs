@@ -4,43 +4,80 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-use std::ops::Deref;
+use std::convert::TryFrom;
 
-use std::fmt;
-use std::io::Read;
 use std::io::Write;
 
-use chrono::Utc;
 use ring::{self, signature::UnparsedPublicKey};
 use ring::{
-    aead, digest, pbkdf2, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
+    aead, digest, hkdf, hmac, pbkdf2, rand, signature,
+    signature::{Ed25519KeyPair, KeyPair},
 };
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
+use yasna::{self, models::ObjectIdentifier};
 
 use x25519_dalek as x25519;
 
-use rand_core::OsRng;
+use rand_core::{CryptoRng, OsRng, RngCore};
 
-use crate::crypto::{CertVariant, PublicVariant, SecretVariant};
+use zeroize::Zeroizing;
+
+use ring::constant_time::verify_slices_are_equal;
+
+use crate::crypto::{PublicVariant, SecretVariant};
 
 type Seed = [u8; SEED_LEN];
 const SEED_LEN: usize = 32;
 
+/// Length of the secrets written by `AlphaSecret::serialize_compact`
+pub const COMPACT_LEN: usize = SEED_LEN + 32;
+
+/// Length of the optional check value after the compact secrets
+pub const COMPACT_CHECK_LEN: usize = 8;
+
+/// Latest version of the serialized secret format.
+/// `serialize` always writes this version. Version 3 adds the checksum,
+/// readers older than version 3 can not load secrets written since then.
+pub const SERIALIZE_VERSION: i64 = 3;
+
+/// File format identifier at the start of serialized secrets.
+/// DER stores it big-endian as `02 06 00 fe 73 ba 20 03`.
+pub const MAGIC: i64 = 0xfe73ba2003;
+
+/// OID of Ed25519 keys in PKIX (RFC 8410)
+const ED25519_OID: &[u64] = &[1, 3, 101, 112];
+
+/// Length of the encoding from `AlphaPublic::to_bytes`
+pub const PUBLIC_BYTES_LEN: usize = 64;
+
+use crate::crypto::compress::{compress, decompress};
+use crate::crypto::convert::{ed25519_seed_to_x25519_secret, ed25519_to_x25519_public};
+use crate::crypto::parse::parse_with_options;
+use crate::crypto::shared::chacha20_key;
+use crate::crypto::sign::{check_canonical, unframe, PreparedKey, SIGNATURE_LEN};
 use crate::crypto::{
-    validate_signature, Cert, DeviceCert, Encrypted, Fingerprint, IdentCert, Public, Secret,
-    SignatureBytes, Trusted, Untrusted,
+    Cert, Compression, DecryptError, EncryptContext, EncryptError, Encrypted, Error, Fingerprint,
+    Kdf, KdfParams, KeyId, ParseError, ParseOptions, Public, Secret, SharedSecret, SignatureBytes,
+    Variant, VerifyError, DEFAULT_ENCRYPT_INFO, MAX_PLAINTEXT,
 };
 
 /// Public part of a Alpha keyring, constist of:
 ///  * ED25519 key for signing
 ///  * X25519 key for agreement and crypto
+#[derive(Clone, Copy)]
 pub struct AlphaPublic {
-    ed25519_pubkey: Vec<u8>,
+    ed25519_pubkey: [u8; 32],
     x25519_pubkey: x25519::PublicKey,
 }
 
+/// Authentication tag created by `AlphaSecret::authenticate`.
+/// A random nonce is part of the tag, so the same key is never used
+/// twice with the same nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthTag {
+    pub nonce: [u8; 12],
+    pub tag: [u8; 16],
+}
+
 /// Secret part of a Alpha keyring, constist of:
 ///  * ED25519 key for signing
 ///  * X25519 key for agreement and crypto
@@ -56,6 +93,14 @@ pub struct AlphaSecret {
     x25519_secret: x25519::StaticSecret,
     /// The public keys for this secret
     pubkey: AlphaPublic,
+    /// KDF used when encrypting, it is not part of the serialized secret
+    kdf: Kdf,
+}
+
+impl Default for AlphaSecret {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AlphaSecret {
@@ -64,10 +109,40 @@ impl AlphaSecret {
         // TODO reduce to a single random source
         let rng = rand::SystemRandom::new();
         let ed25519_seed: [u8; SEED_LEN] = rand::generate(&rng).unwrap().expose();
-        let ed25519_keypair = Ed25519KeyPair::from_seed_unchecked(&ed25519_seed).unwrap();
-        let mut rng = OsRng::default();
+        let mut rng = OsRng;
         let x25519_secret = x25519::StaticSecret::new(&mut rng);
-        let ed25519_pubkey = Vec::from(ed25519_keypair.public_key().as_ref());
+        let secret = Self::from_parts(ed25519_seed, x25519_secret.to_bytes());
+        event!(debug, "Generated key {}", secret.pubkey.key_id());
+        secret
+    }
+
+    /// Construct a new AlphaSecret with keys generated by `rng`.
+    /// Only needs `rand_core`, so any RNG works regardless of the `rand`
+    /// version it was built for.
+    pub fn from_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        Self::generate_with(rng)
+    }
+
+    fn generate_with(rng: &mut dyn RngCore) -> Self {
+        let mut ed25519_seed = [0; SEED_LEN];
+        rng.fill_bytes(&mut ed25519_seed);
+        let mut x25519_secret = Zeroizing::new([0; 32]);
+        rng.fill_bytes(&mut x25519_secret[..]);
+        Self::from_parts(ed25519_seed, *x25519_secret)
+    }
+
+    /// Returns a builder for configuring how the secret is constructed
+    pub fn builder<'a>() -> AlphaSecretBuilder<'a> {
+        AlphaSecretBuilder::default()
+    }
+
+    /// Construct an AlphaSecret from the raw ED25519 seed and X25519 secret.
+    /// The X25519 secret is clamped as described in RFC 7748, so an
+    /// unclamped scalar yields the same key as its clamped form.
+    pub fn from_parts(ed25519_seed: Seed, x25519_secret: [u8; 32]) -> Self {
+        let ed25519_keypair = Ed25519KeyPair::from_seed_unchecked(&ed25519_seed).unwrap();
+        let x25519_secret = x25519::StaticSecret::from(clamp_scalar(x25519_secret));
+        let ed25519_pubkey = public_key_array(&ed25519_keypair);
         let x25519_pubkey = x25519::PublicKey::from(&x25519_secret);
         Self {
             ed25519_seed,
@@ -77,65 +152,376 @@ impl AlphaSecret {
                 ed25519_pubkey,
                 x25519_pubkey,
             },
+            kdf: Kdf::default(),
+        }
+    }
+
+    /// Like `from_parts`, but checks that the X25519 public key derived
+    /// from `x25519_secret` is `expected_x25519_pubkey`.
+    /// This catches swapped or corrupted key material on import.
+    pub fn from_raw_checked(
+        ed25519_seed: Seed,
+        x25519_secret: [u8; 32],
+        expected_x25519_pubkey: &[u8; 32],
+    ) -> Result<Self, ParseError> {
+        let secret = Self::from_parts(ed25519_seed, x25519_secret);
+        if secret.pubkey.x25519_pubkey.as_bytes() != expected_x25519_pubkey {
+            return Err(ParseError::PublicKeyMismatch);
         }
+        Ok(secret)
+    }
+
+    /// Construct an AlphaSecret from an ED25519 seed only.
+    /// The X25519 secret is the clamped ED25519 secret scalar (the first half
+    /// of SHA512(seed)), so the X25519 public key matches the one computed
+    /// by `AlphaPublic::from_ed25519_only`.
+    pub fn from_ed25519_seed(ed25519_seed: Seed) -> Self {
+        let x25519_secret = ed25519_seed_to_x25519_secret(&ed25519_seed);
+        Self::from_parts(ed25519_seed, *x25519_secret)
     }
 
     /// Returns the public key parts for this secret
     pub fn public_key(&self) -> &AlphaPublic {
         &self.pubkey
     }
-}
 
-impl Secret for AlphaSecret {
-    fn sign(&self, bytes: &dyn AsRef<[u8]>) -> SignatureBytes {
-        SignatureBytes::from(&self.ed25519_keypair.sign(bytes.as_ref()))
+    /// Returns true if `other` holds the same secret keys.
+    /// The keys are compared in constant time.
+    pub fn matches(&self, other: &AlphaSecret) -> bool {
+        let raw = |secret: &AlphaSecret| {
+            let mut raw = Zeroizing::new(Vec::from(&secret.ed25519_seed[..]));
+            raw.extend(&secret.x25519_secret.to_bytes());
+            raw
+        };
+        verify_slices_are_equal(&raw(self), &raw(other)).is_ok()
     }
 
-    fn decrypt(&self, enc_bytes: &Encrypted, sender_pubkey: &dyn Public) -> Vec<u8> {
-        match sender_pubkey.as_variant_ref() {
-            PublicVariant::Alpha(_p) => {
-                let mut in_out = enc_bytes.data.clone();
-                let mut raw_ephemeral_pubkey = [0; 32];
-                let bytes = &enc_bytes.ephemeral_pubkey[..raw_ephemeral_pubkey.len()];
-                raw_ephemeral_pubkey.copy_from_slice(bytes);
-                let ephemeral_pub = x25519::PublicKey::from(raw_ephemeral_pubkey);
-                // DH
-                let shared_secret = self.x25519_secret.diffie_hellman(&ephemeral_pub);
+    /// Agree on a shared secret with `peer` by a static X25519 key agreement.
+    /// Both sides get the same secret without exchanging any further data.
+    pub fn agree(&self, peer: &AlphaPublic) -> SharedSecret {
+        self.agree_raw(*peer.x25519_pubkey.as_bytes())
+    }
 
-                let salt = [0];
+    /// Like `agree`, but with a raw X25519 public key, e.g. an ephemeral key
+    pub(crate) fn agree_raw(&self, peer_x25519_pubkey: [u8; 32]) -> SharedSecret {
+        let peer = x25519::PublicKey::from(peer_x25519_pubkey);
+        let shared = self.x25519_secret.diffie_hellman(&peer);
+        SharedSecret::from(*shared.as_bytes())
+    }
 
-                let mut kdf_input = Vec::new();
-                kdf_input.extend(shared_secret.as_bytes());
-                kdf_input.extend(ephemeral_pub.as_bytes());
-                kdf_input.extend(self.public_key().encryption_public_key());
-                let mut key = [0; 32];
-                pbkdf2::derive(
-                    pbkdf2::PBKDF2_HMAC_SHA256,
-                    std::num::NonZeroU32::new(1000).unwrap(),
-                    &salt,
-                    &kdf_input,
-                    &mut key,
-                );
-
-                let mut opening_key = aead::LessSafeKey::new(
-                    aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key).expect("opening key"),
-                );
+    /// Authenticate `aad` for `peer` without encrypting anything.
+    /// The tag is an AEAD tag over empty plaintext, keyed by the static
+    /// shared secret with `peer`, so only the two parties can create it.
+    pub fn authenticate(&self, aad: &[u8], peer: &AlphaPublic) -> Result<AuthTag, EncryptError> {
+        let mut nonce = [0; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let tag = self
+            .auth_key(peer)
+            .and_then(|key| {
+                key.seal_in_place_separate_tag(
+                    aead::Nonce::assume_unique_for_key(nonce),
+                    aead::Aad::from(aad),
+                    &mut [],
+                )
+            })
+            .map_err(|_| EncryptError::Crypto)?;
+        let mut raw_tag = [0; 16];
+        raw_tag.copy_from_slice(tag.as_ref());
+        Ok(AuthTag {
+            nonce,
+            tag: raw_tag,
+        })
+    }
 
-                let nonce =
-                    aead::Nonce::assume_unique_for_key([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
-                let decrypted_data = opening_key
-                    .open_in_place(nonce, aead::Aad::empty(), &mut in_out)
-                    .expect("opening failed");
-                Vec::from(decrypted_data)
+    /// Check a tag created by `peer` with `authenticate` for `aad`
+    pub fn verify_authenticate(&self, aad: &[u8], tag: &AuthTag, peer: &AlphaPublic) -> bool {
+        let mut in_out = tag.tag;
+        self.auth_key(peer)
+            .and_then(|key| {
+                key.open_in_place(
+                    aead::Nonce::assume_unique_for_key(tag.nonce),
+                    aead::Aad::from(aad),
+                    &mut in_out,
+                )
+                .map(|_| ())
+            })
+            .is_ok()
+    }
+
+    fn auth_key(&self, peer: &AlphaPublic) -> Result<aead::LessSafeKey, ring::error::Unspecified> {
+        let key = self
+            .agree(peer)
+            .derive_subkey(b"stackment authenticate", 32);
+        chacha20_key(&key)
+    }
+
+    /// Like `encrypt`, but tags the result with the key id of `peer`, so
+    /// a recipient with several secrets can pick the right one without
+    /// trying each. The key id is authenticated, but it reveals the
+    /// recipient to everyone who knows its public key.
+    pub fn encrypt_with_key_id(
+        &self,
+        plain_bytes: &[u8],
+        peer: &AlphaPublic,
+    ) -> Result<Encrypted, EncryptError> {
+        self.encrypt_with_rng(
+            plain_bytes,
+            peer,
+            Some(peer.key_id()),
+            MAX_PLAINTEXT,
+            &mut OsRng,
+        )
+    }
+
+    /// Like `encrypt`, but binds the ciphertext to the application
+    /// context `ctx`. Only `decrypt_with_context` with the same context
+    /// accepts it, so ciphertexts can not be confused between applications
+    /// using the same keys. `encrypt` uses the default context.
+    /// A custom HKDF info requires a secret with `Kdf::Hkdf`, otherwise
+    /// this fails with `EncryptError::InfoNeedsHkdf`.
+    pub fn encrypt_with_context(
+        &self,
+        plain_bytes: &[u8],
+        peer_public: &dyn Public,
+        ctx: &EncryptContext,
+    ) -> Result<Encrypted, EncryptError> {
+        if self.kdf == Kdf::Pbkdf2 && ctx.info != DEFAULT_ENCRYPT_INFO {
+            return Err(EncryptError::InfoNeedsHkdf);
+        }
+        check_plain_len(plain_bytes, MAX_PLAINTEXT)?;
+        self.encrypt_with_ephemeral_key(
+            plain_bytes,
+            peer_public,
+            None,
+            ctx,
+            self.kdf,
+            x25519::EphemeralSecret::new(&mut OsRng),
+        )
+    }
+
+    /// Decrypt data from `encrypt_with_context`, failing if it was not
+    /// encrypted with `ctx`. `Kdf::Pbkdf2` data with a custom HKDF info
+    /// fails with `DecryptError::InfoNeedsHkdf`.
+    pub fn decrypt_with_context(
+        &self,
+        enc_bytes: &Encrypted,
+        ctx: &EncryptContext,
+    ) -> Result<Vec<u8>, DecryptError> {
+        if enc_bytes.variant_id != Variant::Alpha.id() {
+            return Err(DecryptError::WrongVariant(enc_bytes.variant_id));
+        }
+        if enc_bytes.app_id != ctx.app_id {
+            return Err(DecryptError::AppMismatch(enc_bytes.app_id));
+        }
+        if enc_bytes.compression != ctx.compression {
+            return Err(DecryptError::CompressionMismatch(enc_bytes.compression));
+        }
+        let mut overhead = aead::CHACHA20_POLY1305.tag_len() as u64;
+        if ctx.key_commitment {
+            overhead += KEY_COMMITMENT_LEN as u64;
+        }
+        if enc_bytes
+            .plain_len
+            .is_some_and(|len| enc_bytes.data.len() as u64 != len.saturating_add(overhead))
+        {
+            return Err(DecryptError::Decryption);
+        }
+        // Wiped after the plaintext is copied out, see `decrypt_zeroizing`
+        let mut in_out = Zeroizing::new(enc_bytes.data.clone());
+        let commitment = if ctx.key_commitment {
+            if in_out.len() < KEY_COMMITMENT_LEN {
+                return Err(DecryptError::KeyCommitment);
+            }
+            let split = in_out.len() - KEY_COMMITMENT_LEN;
+            Some(in_out.split_off(split))
+        } else {
+            None
+        };
+        let mut raw_ephemeral_pubkey = [0; 32];
+        if enc_bytes.ephemeral_pubkey.len() != raw_ephemeral_pubkey.len() {
+            return Err(DecryptError::InvalidEphemeralKey(
+                enc_bytes.ephemeral_pubkey.len(),
+            ));
+        }
+        raw_ephemeral_pubkey.copy_from_slice(&enc_bytes.ephemeral_pubkey);
+        let ephemeral_pub = x25519::PublicKey::from(raw_ephemeral_pubkey);
+        // DH
+        let shared_secret = self.x25519_secret.diffie_hellman(&ephemeral_pub);
+
+        let salt = [0];
+
+        let mut kdf_input = Zeroizing::new(Vec::new());
+        kdf_input.extend(shared_secret.as_bytes());
+        kdf_input.extend(ephemeral_pub.as_bytes());
+        kdf_input.extend(self.public_key().encryption_public_key());
+        let key = match enc_bytes.kdf {
+            Kdf::Pbkdf2 if ctx.info != DEFAULT_ENCRYPT_INFO => {
+                return Err(DecryptError::InfoNeedsHkdf)
             }
+            Kdf::Pbkdf2 => derive_key(&salt, &kdf_input),
+            Kdf::Hkdf => derive_key_hkdf(&kdf_input, &ctx.info),
+        };
+        if let Some(commitment) = commitment {
+            hmac::verify(&commitment_key(&key[..]), KEY_COMMITMENT_LABEL, &commitment)
+                .map_err(|_| DecryptError::KeyCommitment)?;
         }
+
+        let opening_key = chacha20_key(&key[..]).map_err(|_| DecryptError::Decryption)?;
+
+        let nonce = aead::Nonce::assume_unique_for_key([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let aad = aead::Aad::from(header_aad(
+            &enc_bytes.recipient_key_id,
+            enc_bytes.app_id,
+            enc_bytes.plain_len,
+            enc_bytes.compression,
+        ));
+        let decrypted_data = opening_key
+            .open_in_place(nonce, aad, &mut in_out)
+            .map_err(|_| {
+                // Not a warning, trial decryption fails for all data
+                // addressed to other keys
+                event!(debug, "Decryption failed for key {}", self.pubkey.key_id());
+                DecryptError::Decryption
+            })?;
+        Ok(Vec::from(decrypted_data))
     }
 
-    fn encrypt(&self, plain_bytes: &dyn AsRef<[u8]>, peer_public: &dyn Public) -> Encrypted {
+    /// Like `encrypt`, but appends a commitment to the derived key, see
+    /// `EncryptContext::with_key_commitment`.
+    pub fn encrypt_committing(
+        &self,
+        plain_bytes: &[u8],
+        peer_public: &dyn Public,
+    ) -> Result<Encrypted, EncryptError> {
+        let ctx = EncryptContext::default().with_key_commitment();
+        self.encrypt_with_context(plain_bytes, peer_public, &ctx)
+    }
+
+    /// Decrypt data from `encrypt_committing`. The key commitment is
+    /// checked before the data is decrypted.
+    pub fn decrypt_committing(&self, enc_bytes: &Encrypted) -> Result<Vec<u8>, DecryptError> {
+        let ctx = EncryptContext::default().with_key_commitment();
+        self.decrypt_with_context(enc_bytes, &ctx)
+    }
+
+    /// Re-encrypt legacy data for this secret with `Kdf::Hkdf`, whatever
+    /// KDF it used, for `peer`, e.g. this secret's own public key for data
+    /// at rest. The upgrade is opt-in: new data still uses the KDF of the
+    /// secret, which stays `Kdf::Pbkdf2` by default for readers without
+    /// HKDF support, see `AlphaSecretBuilder::with_kdf`.
+    /// A recipient key id is kept, tagged for `peer`.
+    /// Only data of the default `EncryptContext` can be upgraded.
+    pub fn reencrypt_legacy(
+        &self,
+        old: &Encrypted,
+        peer: &AlphaPublic,
+    ) -> Result<Encrypted, Error> {
+        let plain = self.decrypt_zeroizing(old)?;
+        check_plain_len(&plain, MAX_PLAINTEXT)?;
+        let encrypted = self.encrypt_with_ephemeral_key(
+            &plain,
+            peer,
+            old.recipient_key_id.map(|_| peer.key_id()),
+            &EncryptContext::default(),
+            Kdf::Hkdf,
+            x25519::EphemeralSecret::new(&mut OsRng),
+        )?;
+        Ok(encrypted)
+    }
+
+    /// Trial decryption for screening data which may not be for this
+    /// secret. Returns None instead of an error if the data is tagged for
+    /// another key or does not decrypt with this secret.
+    pub fn decrypt_try(&self, enc_bytes: &Encrypted) -> Option<Vec<u8>> {
+        if !enc_bytes.is_addressed_to(&self.pubkey.key_id()) {
+            return None;
+        }
+        self.decrypt(enc_bytes).ok()
+    }
+
+    /// Sign `plain_bytes`, then encrypt the plaintext and the signature
+    /// for `peer`. The signature also covers the fingerprint of `peer`, so
+    /// the recipient can not re-encrypt the signed message to someone else
+    /// as if it was sent to them.
+    pub fn sign_and_encrypt(
+        &self,
+        plain_bytes: &[u8],
+        peer: &AlphaPublic,
+    ) -> Result<Encrypted, EncryptError> {
+        let signature = self.sign(&signed_for(plain_bytes, peer));
+        let mut in_out = Zeroizing::new(Vec::from(plain_bytes));
+        in_out.extend(signature.as_bytes());
+        self.encrypt(&*in_out, peer)
+    }
+
+    /// Returns the secret from `secrets` which `enc_bytes` is tagged for.
+    /// Without a key id, the first secret is returned.
+    pub fn select_for<'a>(
+        secrets: &'a [AlphaSecret],
+        enc_bytes: &Encrypted,
+    ) -> Option<&'a AlphaSecret> {
+        secrets
+            .iter()
+            .find(|secret| enc_bytes.is_addressed_to(&secret.public_key().key_id()))
+    }
+
+    /// Encrypt with the ephemeral key taken from `rng`.
+    /// Only to be used with a fixed RNG for reproducible test vectors.
+    pub(crate) fn encrypt_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        plain_bytes: &[u8],
+        peer_public: &dyn Public,
+        recipient_key_id: Option<KeyId>,
+        max_len: usize,
+        rng: &mut R,
+    ) -> Result<Encrypted, EncryptError> {
+        check_plain_len(plain_bytes, max_len)?;
+        self.encrypt_with_ephemeral_key(
+            plain_bytes,
+            peer_public,
+            recipient_key_id,
+            &EncryptContext::default(),
+            self.kdf,
+            x25519::EphemeralSecret::new(rng),
+        )
+    }
+
+    /// Like `encrypt`, but with the caller provided `ephemeral` key, e.g.
+    /// for deterministic test vectors or protocols which commit to the
+    /// ephemeral key beforehand.
+    /// The ephemeral key is consumed. Using the same ephemeral secret for
+    /// more than one message breaks the security of the encryption.
+    pub fn encrypt_with_ephemeral(
+        &self,
+        plain_bytes: &[u8],
+        peer_public: &dyn Public,
+        ephemeral: x25519::EphemeralSecret,
+    ) -> Result<Encrypted, EncryptError> {
+        check_plain_len(plain_bytes, MAX_PLAINTEXT)?;
+        self.encrypt_with_ephemeral_key(
+            plain_bytes,
+            peer_public,
+            None,
+            &EncryptContext::default(),
+            self.kdf,
+            ephemeral,
+        )
+    }
+
+    /// Encrypt with `kdf`, the size of `plain_bytes` has to be checked
+    /// by the caller
+    fn encrypt_with_ephemeral_key(
+        &self,
+        plain_bytes: &[u8],
+        peer_public: &dyn Public,
+        recipient_key_id: Option<KeyId>,
+        ctx: &EncryptContext,
+        kdf: Kdf,
+        ephemeral_key: x25519::EphemeralSecret,
+    ) -> Result<Encrypted, EncryptError> {
+        let len = plain_bytes.len();
         match peer_public.as_variant_ref() {
             PublicVariant::Alpha(p) => {
-                // Generate an ephemeral x25519 key
-                let ephemeral_key = x25519::EphemeralSecret::new(&mut OsRng::default());
                 let ephemeral_pub = x25519::PublicKey::from(&ephemeral_key);
                 // DH
                 let shared_secret = ephemeral_key.diffie_hellman(&p.x25519_pubkey);
@@ -147,57 +533,1002 @@ impl Secret for AlphaSecret {
                 let salt = [0];
                 // for KDF, the RFC 7748 6.1 recommends to use the shared secret + P1 + P2
                 // as input for a KDF.
-                let mut kdf_input = Vec::new();
+                let mut kdf_input = Zeroizing::new(Vec::new());
                 kdf_input.extend(shared_secret.as_bytes());
                 kdf_input.extend(ephemeral_pub.as_bytes());
                 kdf_input.extend(p.x25519_pubkey.as_bytes());
-                let mut key = [0; 32];
-                pbkdf2::derive(
-                    pbkdf2::PBKDF2_HMAC_SHA256,
-                    std::num::NonZeroU32::new(1000).unwrap(),
-                    &salt,
-                    &kdf_input,
-                    &mut key,
-                );
+                let key = match kdf {
+                    Kdf::Pbkdf2 => derive_key(&salt, &kdf_input),
+                    Kdf::Hkdf => derive_key_hkdf(&kdf_input, &ctx.info),
+                };
                 // Encrypt data
-                let mut in_out = Vec::from(plain_bytes.as_ref());
-                let mut sealing_key = aead::LessSafeKey::new(
-                    aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key).expect("sealing key"),
-                );
+                let mut in_out = Vec::from(plain_bytes);
+                let sealing_key = chacha20_key(&key[..]).map_err(|_| EncryptError::Crypto)?;
                 // Because the key is used only once and this is one single encryption step,
                 // we can work with a simple nonce.
                 let nonce =
                     aead::Nonce::assume_unique_for_key([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+                let plain_len = Some(len as u64);
+                let aad = aead::Aad::from(header_aad(
+                    &recipient_key_id,
+                    ctx.app_id,
+                    plain_len,
+                    ctx.compression,
+                ));
                 sealing_key
-                    .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut in_out)
-                    .expect("sealing failed");
-                Encrypted {
+                    .seal_in_place_append_tag(nonce, aad, &mut in_out)
+                    .map_err(|_| EncryptError::Crypto)?;
+                if ctx.key_commitment {
+                    let commitment = hmac::sign(&commitment_key(&key[..]), KEY_COMMITMENT_LABEL);
+                    in_out.extend(commitment.as_ref());
+                }
+                Ok(Encrypted {
                     ephemeral_pubkey: Vec::from(&ephemeral_pub.as_bytes()[..]),
                     data: in_out,
+                    recipient_key_id,
+                    kdf,
+                    app_id: ctx.app_id,
+                    plain_len,
+                    variant_id: Variant::Alpha.id(),
+                    compression: ctx.compression,
+                })
+            }
+        }
+    }
+
+    /// Restore a secret from data written by `serialize`.
+    /// How strict the data is parsed is controlled by `options`.
+    /// All format versions up to `SERIALIZE_VERSION` are accepted.
+    pub fn deserialize(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        Self::deserialize_up_to(bytes, options, SERIALIZE_VERSION)
+    }
+
+    /// Like `deserialize`, but only accepting versions up to `max_version`
+    pub(crate) fn deserialize_up_to(
+        bytes: &[u8],
+        options: ParseOptions,
+        max_version: i64,
+    ) -> Result<Self, ParseError> {
+        let fields = SerializedSecret::parse(bytes, options, max_version)?;
+        let mut secret = Self::from_parts(fields.ed25519_seed, fields.x25519_secret);
+        // The stored public keys must match the secrets, otherwise the
+        // data is corrupted or was tampered with
+        let public = secret.public_key();
+        if public.signing_public_key() != &fields.ed25519_pubkey[..]
+            || public.encryption_public_key() != &fields.x25519_pubkey[..]
+        {
+            return Err(ParseError::PublicKeyMismatch);
+        }
+        secret.kdf = fields.kdf;
+        Ok(secret)
+    }
+
+    /// Derive the public keys again from the secret keys.
+    /// Unlike `public_key`, this does not return the public keys kept with
+    /// the secret, so comparing both detects a corrupted secret in memory.
+    pub fn derive_public_key(&self) -> AlphaPublic {
+        *Self::from_parts(self.ed25519_seed, self.x25519_secret.to_bytes()).public_key()
+    }
+
+    /// Serialize the secret encrypted with a key derived from `passphrase`.
+    /// The KDF parameters are stored in the header and bound to the
+    /// ciphertext, so `deserialize_encrypted` does not need them.
+    /// Each call uses a new random salt, also stored in the header, so
+    /// encrypting the same secret twice with the same passphrase yields
+    /// different data.
+    pub fn serialize_encrypted(
+        &self,
+        passphrase: &[u8],
+        params: &KdfParams,
+        stream: &mut dyn Write,
+    ) -> Result<(), ParseError> {
+        let mut salt = [0; ENCRYPTED_SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = params.derive(passphrase, &salt)?;
+        let mut in_out = Zeroizing::new(Vec::new());
+        self.serialize(&mut *in_out);
+        // The key is unique because of the random salt
+        let nonce = aead::Nonce::assume_unique_for_key([0; 12]);
+        let aad = aead::Aad::from(kdf_aad(params, &salt));
+        chacha20_key(&key[..])
+            .and_then(|key| key.seal_in_place_append_tag(nonce, aad, &mut *in_out))
+            .map_err(|_| ParseError::InvalidKey(String::from("Sealing failed")))?;
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_i64(MAGIC);
+                writer.next().write_u8(2); // Encrypted private key
+                writer.next().write_i64(1); // Version
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_u8(params.algorithm as u8);
+                    writer.next().write_u32(params.memory);
+                    writer.next().write_u32(params.iterations);
+                    writer.next().write_u32(params.parallelism);
+                });
+                writer.next().write_bytes(&salt);
+                writer.next().write_bytes(&in_out);
+            });
+        });
+        stream.write_all(&raw_bytes).unwrap();
+        Ok(())
+    }
+
+    /// Restore a secret from data written by `serialize_encrypted`.
+    /// The KDF parameters are read from the data and validated first.
+    pub fn deserialize_encrypted(
+        bytes: &[u8],
+        passphrase: &[u8],
+        options: ParseOptions,
+    ) -> Result<Self, ParseError> {
+        let (magic, kind, version, kdf, salt, sealed) =
+            parse_with_options(bytes, options, |reader| {
+                reader.read_sequence(|reader| {
+                    let magic = reader.next().read_i64()?;
+                    let kind = reader.next().read_u8()?;
+                    let version = reader.next().read_i64()?;
+                    let kdf = reader.next().read_sequence(|reader| {
+                        let algorithm = reader.next().read_u8()?;
+                        let memory = reader.next().read_u32()?;
+                        let iterations = reader.next().read_u32()?;
+                        let parallelism = reader.next().read_u32()?;
+                        Ok((algorithm, memory, iterations, parallelism))
+                    })?;
+                    let salt = reader.next().read_bytes()?;
+                    let sealed = reader.next().read_bytes()?;
+                    Ok((magic, kind, version, kdf, salt, sealed))
+                })
+            })?;
+        if magic != MAGIC {
+            return Err(ParseError::BadMagic);
+        }
+        if kind != 2 || version != 1 {
+            return Err(ParseError::InvalidKey(String::from(
+                "Not an encrypted alpha secret key",
+            )));
+        }
+        if salt.len() != ENCRYPTED_SALT_LEN {
+            return Err(ParseError::InvalidKey(String::from("Invalid salt length")));
+        }
+        let params = KdfParams::from_raw(kdf.0, kdf.1, kdf.2, kdf.3)?;
+        let key = params.derive(passphrase, &salt)?;
+        let opening_key = chacha20_key(&key[..]).map_err(|_| ParseError::Decryption)?;
+        let nonce = aead::Nonce::assume_unique_for_key([0; 12]);
+        let mut in_out = Zeroizing::new(sealed);
+        let aad = aead::Aad::from(kdf_aad(&params, &salt));
+        let plain = opening_key
+            .open_in_place(nonce, aad, &mut in_out)
+            .map_err(|_| ParseError::Decryption)?;
+        Self::deserialize(plain, ParseOptions::strict())
+    }
+
+    /// Write only the ED25519 seed and the X25519 secret to `stream`,
+    /// 64 bytes in total. The public keys are derived again by
+    /// `deserialize_compact`. With `with_check`, the key id of the public
+    /// keys follows, so corrupted secrets are detected on load.
+    /// Unlike `serialize`, the KDF choice is not stored.
+    pub fn serialize_compact(&self, with_check: bool, stream: &mut dyn Write) {
+        let mut raw = Zeroizing::new(Vec::with_capacity(COMPACT_LEN + COMPACT_CHECK_LEN));
+        raw.extend(&self.ed25519_seed);
+        raw.extend(&Zeroizing::new(self.x25519_secret.to_bytes())[..]);
+        if with_check {
+            raw.extend(&self.pubkey.key_id().inner);
+        }
+        stream.write_all(&raw).unwrap();
+    }
+
+    /// Restore a secret from data written by `serialize_compact`.
+    /// If a check value is present, the derived public keys must match
+    /// it, otherwise `ParseError::PublicKeyMismatch` is returned.
+    pub fn deserialize_compact(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() != COMPACT_LEN && bytes.len() != COMPACT_LEN + COMPACT_CHECK_LEN {
+            return Err(ParseError::InvalidKey(String::from(
+                "Invalid compact secret length",
+            )));
+        }
+        let mut ed25519_seed = Zeroizing::new([0; SEED_LEN]);
+        ed25519_seed.copy_from_slice(&bytes[..SEED_LEN]);
+        let mut x25519_secret = Zeroizing::new([0; 32]);
+        x25519_secret.copy_from_slice(&bytes[SEED_LEN..COMPACT_LEN]);
+        let secret = Self::from_parts(*ed25519_seed, *x25519_secret);
+        let check = &bytes[COMPACT_LEN..];
+        if !check.is_empty() && check != secret.pubkey.key_id().inner {
+            return Err(ParseError::PublicKeyMismatch);
+        }
+        Ok(secret)
+    }
+
+    /// Serialize in the layout of `version`, which must be able to hold
+    /// the KDF of this secret. Older versions are only written by tests.
+    pub(crate) fn serialize_version(&self, version: i64) -> Vec<u8> {
+        let x25519_secret = Zeroizing::new(self.x25519_secret.to_bytes());
+        let keys = [
+            &self.ed25519_seed[..],
+            self.ed25519_keypair.public_key().as_ref(),
+            &x25519_secret[..],
+            self.pubkey.x25519_pubkey.as_bytes(),
+        ];
+        yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                write_secret_fields(writer, version, keys, self.kdf as u8);
+                if version >= 3 {
+                    let checksum = secret_checksum(version, keys, self.kdf as u8);
+                    writer.next().write_bytes(checksum.as_ref());
                 }
+            })
+        })
+    }
+
+    /// Read back data written by `serialize` and check that the embedded
+    /// public keys are the ones derived from the embedded secrets.
+    /// This guards against field-ordering regressions in the serializer.
+    pub fn check_serialized(bytes: &[u8]) -> Result<(), ParseError> {
+        let fields = SerializedSecret::parse(bytes, ParseOptions::strict(), SERIALIZE_VERSION)?;
+        let ed25519_keypair = Ed25519KeyPair::from_seed_unchecked(&fields.ed25519_seed)
+            .map_err(|e| ParseError::InvalidKey(format!("{}", e)))?;
+        let x25519_secret = x25519::StaticSecret::from(clamp_scalar(fields.x25519_secret));
+        if ed25519_keypair.public_key().as_ref() != fields.ed25519_pubkey.as_slice()
+            || x25519::PublicKey::from(&x25519_secret).as_bytes()[..] != fields.x25519_pubkey[..]
+        {
+            return Err(ParseError::PublicKeyMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Single 32 byte secret from which both keys of an AlphaSecret are derived,
+/// so only the master has to be backed up.
+/// The ED25519 seed is `HKDF(master, "ed25519")` and the X25519 secret is
+/// the clamped `HKDF(master, "x25519")`.
+/// This is a different key generation scheme than `AlphaSecret::new`, which
+/// generates both keys independently. It is opt-in, existing keys can not
+/// be converted to a master seed.
+pub struct MasterSeed {
+    inner: Zeroizing<[u8; 32]>,
+}
+
+impl MasterSeed {
+    /// Generate a new random master seed
+    pub fn generate() -> Self {
+        let mut inner = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(&mut inner[..]);
+        Self { inner }
+    }
+
+    /// Restore a master seed from its raw bytes
+    pub fn from_bytes(master: [u8; 32]) -> Self {
+        Self {
+            inner: Zeroizing::new(master),
+        }
+    }
+
+    /// Raw bytes of the master seed, for backup
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.inner
+    }
+
+    fn expand(&self, label: &[u8]) -> Zeroizing<[u8; 32]> {
+        let mut key = Zeroizing::new([0; 32]);
+        hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+            .extract(&self.inner[..])
+            .expand(&[label], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key[..]))
+            .expect("HKDF output length");
+        key
+    }
+
+    /// Regenerate the AlphaSecret derived from this master seed
+    pub fn to_secret(&self) -> AlphaSecret {
+        let ed25519_seed = self.expand(b"ed25519");
+        let x25519_secret = self.expand(b"x25519");
+        AlphaSecret::from_parts(*ed25519_seed, clamp_scalar(*x25519_secret))
+    }
+}
+
+/// A secret together with its public key, e.g. to hand the public key
+/// to others while keeping the secret.
+pub struct AlphaKeyPair {
+    pub secret: AlphaSecret,
+    pub public: AlphaPublic,
+}
+
+impl AlphaKeyPair {
+    /// Generate a new key pair like `AlphaSecret::new`
+    pub fn generate() -> Self {
+        Self::from(AlphaSecret::new())
+    }
+
+    /// Split the key pair into the secret and the public key
+    pub fn into_parts(self) -> (AlphaSecret, AlphaPublic) {
+        (self.secret, self.public)
+    }
+}
+
+impl From<AlphaSecret> for AlphaKeyPair {
+    fn from(secret: AlphaSecret) -> Self {
+        let public = secret.pubkey;
+        Self { secret, public }
+    }
+}
+
+/// Builder for an AlphaSecret, see `AlphaSecret::builder`.
+/// Without a seed or RNG, the keys are generated like `AlphaSecret::new`.
+#[derive(Default)]
+pub struct AlphaSecretBuilder<'a> {
+    rng: Option<&'a mut dyn RngCore>,
+    seed: Option<(Seed, Zeroizing<[u8; 32]>)>,
+    kdf: Kdf,
+}
+
+impl<'a> AlphaSecretBuilder<'a> {
+    /// Generate the keys with `rng`
+    pub fn with_rng<R: RngCore + CryptoRng>(mut self, rng: &'a mut R) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// Use the given raw keys instead of generating them
+    pub fn with_seed(mut self, ed25519_seed: Seed, x25519_secret: [u8; 32]) -> Self {
+        self.seed = Some((ed25519_seed, Zeroizing::new(x25519_secret)));
+        self
+    }
+
+    /// KDF used when encrypting with the secret
+    pub fn with_kdf(mut self, kdf: Kdf) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Construct the secret.
+    /// Fails if both a seed and a RNG are given.
+    pub fn build(self) -> crate::crypto::Result<AlphaSecret> {
+        let mut secret = match (self.seed, self.rng) {
+            (Some(_), Some(_)) => {
+                return Err(Error::NotValid(String::from(
+                    "Seed and RNG are mutually exclusive",
+                )))
+            }
+            (Some((ed25519_seed, x25519_secret)), None) => {
+                AlphaSecret::from_parts(ed25519_seed, *x25519_secret)
             }
+            (None, Some(rng)) => AlphaSecret::generate_with(rng),
+            (None, None) => AlphaSecret::new(),
+        };
+        secret.kdf = self.kdf;
+        Ok(secret)
+    }
+}
+
+/// Raw fields of a serialized AlphaSecret
+struct SerializedSecret {
+    ed25519_seed: Seed,
+    ed25519_pubkey: Vec<u8>,
+    x25519_secret: [u8; 32],
+    x25519_pubkey: Vec<u8>,
+    kdf: Kdf,
+}
+
+impl SerializedSecret {
+    /// Parse any version from 1 to `max_version`.
+    /// Version 1: magic, kind, version, ED25519 seed and public key,
+    /// X25519 secret and public key.
+    /// Version 2: like version 1, followed by the KDF used for encryption.
+    /// Version 3: like version 2, followed by a SHA256 checksum over the
+    /// DER encoding of the sequence without the checksum.
+    fn parse(bytes: &[u8], options: ParseOptions, max_version: i64) -> Result<Self, ParseError> {
+        let (magic, kind, version, fields) = parse_with_options(bytes, options, |reader| {
+            reader.read_sequence(|reader| {
+                let magic = reader.next().read_i64()?;
+                let kind = reader.next().read_u8()?;
+                let version = reader.next().read_i64()?;
+                if version < 1 || version > max_version {
+                    // Skip the unknown layout, the version is rejected below
+                    while reader.read_optional(|reader| reader.read_der())?.is_some() {}
+                    return Ok((magic, kind, version, None));
+                }
+                let seed = reader.next().read_bytes()?;
+                let ed25519_pubkey = reader.next().read_bytes()?;
+                let x25519_secret = reader.next().read_bytes()?;
+                let x25519_pubkey = reader.next().read_bytes()?;
+                let kdf = if version >= 2 {
+                    Some(reader.next().read_u8()?)
+                } else {
+                    None
+                };
+                let checksum = if version >= 3 {
+                    Some(reader.next().read_bytes()?)
+                } else {
+                    None
+                };
+                Ok((
+                    magic,
+                    kind,
+                    version,
+                    Some((
+                        seed,
+                        ed25519_pubkey,
+                        x25519_secret,
+                        x25519_pubkey,
+                        kdf,
+                        checksum,
+                    )),
+                ))
+            })
+        })?;
+        if magic != MAGIC {
+            return Err(ParseError::BadMagic);
+        }
+        if kind != 1 {
+            return Err(ParseError::InvalidKey(String::from(
+                "Not an alpha secret key",
+            )));
+        }
+        let (seed, ed25519_pubkey, x25519_secret, x25519_pubkey, kdf, checksum) =
+            fields.ok_or(ParseError::UnsupportedVersion(version))?;
+        if let Some(checksum) = checksum {
+            let keys = [&seed[..], &ed25519_pubkey, &x25519_secret, &x25519_pubkey];
+            let expected = secret_checksum(version, keys, kdf.unwrap_or(Kdf::Pbkdf2 as u8));
+            if expected.as_ref() != checksum.as_slice() {
+                return Err(ParseError::ChecksumMismatch);
+            }
+        }
+        if seed.len() != SEED_LEN || x25519_secret.len() != 32 {
+            return Err(ParseError::InvalidKey(String::from("Invalid key length")));
+        }
+        let kdf = match kdf {
+            None => Kdf::Pbkdf2,
+            Some(id) if id == Kdf::Pbkdf2 as u8 => Kdf::Pbkdf2,
+            Some(id) if id == Kdf::Hkdf as u8 => Kdf::Hkdf,
+            Some(id) => return Err(ParseError::InvalidKey(format!("Unknown KDF {}", id))),
+        };
+        let mut ed25519_seed: Seed = [0; SEED_LEN];
+        ed25519_seed.copy_from_slice(&seed);
+        let mut raw_x25519_secret = [0; 32];
+        raw_x25519_secret.copy_from_slice(&x25519_secret);
+        Ok(Self {
+            ed25519_seed,
+            ed25519_pubkey,
+            x25519_secret: raw_x25519_secret,
+            x25519_pubkey,
+            kdf,
+        })
+    }
+}
+
+/// Writes the fields of a serialized secret of `version`, except the
+/// checksum. `keys` are the ED25519 seed and public key, followed by the
+/// X25519 secret and public key.
+fn write_secret_fields(writer: &mut yasna::DERWriterSeq, version: i64, keys: [&[u8]; 4], kdf: u8) {
+    writer.next().write_i64(MAGIC);
+    writer.next().write_u8(1); // Private key
+    writer.next().write_i64(version); // Version
+    for key in &keys {
+        writer.next().write_bytes(key);
+    }
+    if version >= 2 {
+        writer.next().write_u8(kdf);
+    }
+}
+
+/// Checksum of the serialized secret without the checksum field
+fn secret_checksum(version: i64, keys: [&[u8]; 4], kdf: u8) -> digest::Digest {
+    let body = Zeroizing::new(yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| write_secret_fields(writer, version, keys, kdf))
+    }));
+    digest::digest(&digest::SHA256, &body)
+}
+
+impl Secret for AlphaSecret {
+    fn sign(&self, bytes: &dyn AsRef<[u8]>) -> SignatureBytes {
+        SignatureBytes::from(&self.ed25519_keypair.sign(bytes.as_ref()))
+    }
+
+    /// Returns the 64 bytes from `AlphaPublic::to_bytes`
+    fn public_bytes(&self) -> Vec<u8> {
+        Vec::from(&self.pubkey.to_bytes()[..])
+    }
+
+    fn decrypt(&self, enc_bytes: &Encrypted) -> Result<Vec<u8>, DecryptError> {
+        self.decrypt_with_context(enc_bytes, &EncryptContext::default())
+    }
+
+    /// Expects data from `AlphaSecret::sign_and_encrypt`
+    fn decrypt_and_verify(
+        &self,
+        enc_bytes: &Encrypted,
+        sender_pubkey: &dyn Public,
+    ) -> Result<Vec<u8>, DecryptError> {
+        let mut in_out = self.decrypt(enc_bytes)?;
+        if in_out.len() < SIGNATURE_LEN {
+            return Err(DecryptError::BadSignature);
         }
+        let signature = SignatureBytes::try_from(&in_out[in_out.len() - SIGNATURE_LEN..])
+            .map_err(|_| DecryptError::BadSignature)?;
+        in_out.truncate(in_out.len() - SIGNATURE_LEN);
+        if !sender_pubkey.verify(&signed_for(&in_out, self.public_key()), &signature) {
+            return Err(DecryptError::BadSignature);
+        }
+        Ok(in_out)
+    }
+
+    fn encrypt_with_limit(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        peer_public: &dyn Public,
+        max_len: usize,
+    ) -> Result<Encrypted, EncryptError> {
+        self.encrypt_with_rng(plain_bytes.as_ref(), peer_public, None, max_len, &mut OsRng)
+    }
+
+    fn encrypt_compressed(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        peer_public: &dyn Public,
+        compression: Compression,
+    ) -> Result<Encrypted, EncryptError> {
+        let ctx = EncryptContext::default().with_compression(compression);
+        self.encrypt_with_context(
+            &compress(plain_bytes.as_ref(), compression),
+            peer_public,
+            &ctx,
+        )
+    }
+
+    fn decrypt_compressed(&self, enc_bytes: &Encrypted) -> Result<Vec<u8>, DecryptError> {
+        let ctx = EncryptContext::default().with_compression(enc_bytes.compression);
+        let compressed = Zeroizing::new(self.decrypt_with_context(enc_bytes, &ctx)?);
+        decompress(&compressed, enc_bytes.compression)
     }
 
     /// Serialize the secret as ASN.1 date to `stream`.
+    /// The data ends with a checksum, so corrupted data is rejected by
+    /// `deserialize` before the keys are used.
+    /// Always writes version 3, even for the default KDF which version 1
+    /// could hold: the checksum is not optional. This breaks older readers,
+    /// which only accept version 1 or 2. Secrets of version 1 and 2 are
+    /// still read, without a checksum.
     /// TODO: Insert ASN.1 schema here
     fn serialize(&self, stream: &mut dyn Write) {
-        let raw_bytes = yasna::construct_der(|writer| {
+        let raw_bytes = self.serialize_version(SERIALIZE_VERSION);
+        #[cfg(feature = "serialize-check")]
+        Self::check_serialized(&raw_bytes).expect("Serializer wrote inconsistent keys");
+        stream.write_all(&raw_bytes).unwrap();
+    }
+
+    fn as_variant_ref(&self) -> SecretVariant<'_> {
+        SecretVariant::Alpha(self)
+    }
+}
+
+/// Reject plaintext larger than `max_len` bytes
+fn check_plain_len(plain_bytes: &[u8], max_len: usize) -> Result<(), EncryptError> {
+    if plain_bytes.len() > max_len {
+        return Err(EncryptError::TooLarge(plain_bytes.len(), max_len));
+    }
+    Ok(())
+}
+
+/// Additional data for encryption, the recipient key id if present,
+/// the application id if not 0, the plaintext length if present and
+/// the compression if any
+fn header_aad(
+    recipient_key_id: &Option<KeyId>,
+    app_id: u32,
+    plain_len: Option<u64>,
+    compression: Compression,
+) -> Vec<u8> {
+    let mut aad = recipient_key_id
+        .map(|key_id| Vec::from(&key_id.inner[..]))
+        .unwrap_or_default();
+    if app_id != 0 {
+        aad.extend(&app_id.to_be_bytes());
+    }
+    if let Some(plain_len) = plain_len {
+        // Prefixed, so it can not be confused with the 8 byte key id
+        aad.push(b'L');
+        aad.extend(&plain_len.to_be_bytes());
+    }
+    if compression != Compression::None {
+        aad.push(b'C');
+        aad.push(compression as u8);
+    }
+    aad
+}
+
+/// Length of the salt for passphrase encrypted secrets
+const ENCRYPTED_SALT_LEN: usize = 16;
+
+/// Additional data binding the KDF parameters and salt to the ciphertext
+fn kdf_aad(params: &KdfParams, salt: &[u8]) -> Vec<u8> {
+    let mut aad = vec![params.algorithm as u8];
+    aad.extend(&params.memory.to_le_bytes());
+    aad.extend(&params.iterations.to_le_bytes());
+    aad.extend(&params.parallelism.to_le_bytes());
+    aad.extend(salt);
+    aad
+}
+
+/// Derive the symmetric encryption key from the DH output.
+/// The key is wiped from memory when it is dropped.
+pub(crate) fn derive_key(salt: &[u8], kdf_input: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0; 32]);
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        std::num::NonZeroU32::new(1000).unwrap(),
+        salt,
+        kdf_input,
+        &mut key[..],
+    );
+    key
+}
+
+/// Length of the key commitment appended by `encrypt_committing`
+const KEY_COMMITMENT_LEN: usize = 32;
+
+/// Message authenticated with the derived key as key commitment
+pub(crate) const KEY_COMMITMENT_LABEL: &[u8] = b"stackment key commitment";
+
+/// HMAC-SHA256 key for the key commitment
+fn commitment_key(key: &[u8]) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, key)
+}
+
+/// Message signed by `sign_and_encrypt`
+fn signed_for(plain_bytes: &[u8], recipient: &AlphaPublic) -> Vec<u8> {
+    let mut msg = Vec::from(&b"stackment signed message"[..]);
+    msg.extend(&recipient.fingerprint().inner);
+    msg.extend(plain_bytes);
+    msg
+}
+
+/// Returns the ED25519 public key of `keypair` as a fixed-size array
+fn public_key_array(keypair: &Ed25519KeyPair) -> [u8; 32] {
+    let mut raw = [0; 32];
+    raw.copy_from_slice(keypair.public_key().as_ref());
+    raw
+}
+
+/// Clamp a X25519 scalar (RFC 7748): clear the low 3 bits and the highest
+/// bit, set the second highest bit.
+/// `x25519::StaticSecret::from` clamps as well, this makes it explicit for
+/// all raw import paths.
+fn clamp_scalar(mut scalar: [u8; 32]) -> [u8; 32] {
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    scalar
+}
+
+/// Derive the symmetric encryption key from the DH output with HKDF-SHA256,
+/// domain separated by `info`
+fn derive_key_hkdf(kdf_input: &[u8], info: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0; 32]);
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(kdf_input)
+        .expand(&[info], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key[..]))
+        .expect("HKDF output length");
+    key
+}
+
+impl AlphaPublic {
+    /// Constructs the public keys from raw key bytes
+    pub(crate) fn from_raw(ed25519_pubkey: [u8; 32], x25519_pubkey: [u8; 32]) -> Self {
+        Self {
+            ed25519_pubkey,
+            x25519_pubkey: x25519::PublicKey::from(x25519_pubkey),
+        }
+    }
+
+    /// Constructs the public keys from an ED25519 public key only.
+    /// The X25519 key is computed by `convert::ed25519_to_x25519_public`.
+    /// Encryption to this key only works if the secret derived its X25519
+    /// key the same way, see `AlphaSecret::from_ed25519_seed`.
+    pub fn from_ed25519_only(ed25519_pubkey: &[u8; 32]) -> Result<Self, ParseError> {
+        Ok(Self::from_raw(
+            *ed25519_pubkey,
+            ed25519_to_x25519_public(ed25519_pubkey)?,
+        ))
+    }
+
+    /// Returns the canonical encoding: the ED25519 public key followed by
+    /// the X25519 public key. Parse it back with `TryFrom<&[u8]>`.
+    pub fn to_bytes(&self) -> [u8; PUBLIC_BYTES_LEN] {
+        let mut raw = [0; PUBLIC_BYTES_LEN];
+        raw[..32].copy_from_slice(self.signing_public_key());
+        raw[32..].copy_from_slice(self.encryption_public_key());
+        raw
+    }
+
+    /// Returns the ED25519 key as DER encoded `SubjectPublicKeyInfo`
+    /// (RFC 8410), as used by X.509 and OpenSSL.
+    /// The X25519 key is not included.
+    pub fn to_spki_der(&self) -> Vec<u8> {
+        yasna::construct_der(|writer| {
             writer.write_sequence(|writer| {
-                writer.next().write_i64(0xfe73ba2003); // Magic
-                writer.next().write_u8(1); // Private key
-                writer.next().write_i64(1); // Version
-                writer.next().write_bytes(&self.ed25519_seed);
-                writer
-                    .next()
-                    .write_bytes(self.ed25519_keypair.public_key().as_ref());
-                writer.next().write_bytes(&self.x25519_secret.to_bytes());
+                writer.next().write_sequence(|writer| {
+                    writer
+                        .next()
+                        .write_oid(&ObjectIdentifier::from_slice(ED25519_OID));
+                });
                 writer
                     .next()
-                    .write_bytes(self.pubkey.x25519_pubkey.as_bytes());
+                    .write_bitvec_bytes(&self.ed25519_pubkey, self.ed25519_pubkey.len() * 8);
             });
-        });
-        stream.write_all(&raw_bytes).unwrap();
+        })
+    }
+
+    /// Parse a DER encoded Ed25519 `SubjectPublicKeyInfo`.
+    /// The X25519 key is derived as in `from_ed25519_only`.
+    pub fn from_spki_der(bytes: &[u8]) -> Result<Self, ParseError> {
+        let (oid, (raw, bits)) = parse_with_options(bytes, ParseOptions::strict(), |reader| {
+            reader.read_sequence(|reader| {
+                let oid = reader
+                    .next()
+                    .read_sequence(|reader| reader.next().read_oid())?;
+                let key = reader.next().read_bitvec_bytes()?;
+                Ok((oid, key))
+            })
+        })?;
+        if oid != ObjectIdentifier::from_slice(ED25519_OID) {
+            return Err(ParseError::InvalidKey(String::from("Not an Ed25519 key")));
+        }
+        if raw.len() != 32 || bits != 256 {
+            return Err(ParseError::InvalidKey(String::from("Invalid key length")));
+        }
+        let mut ed25519_pubkey = [0; 32];
+        ed25519_pubkey.copy_from_slice(&raw);
+        Self::from_ed25519_only(&ed25519_pubkey)
+    }
+
+    /// Returns the X25519 public key as a fixed-size array.
+    /// Same as `Public::encryption_public_key`, but without length checks
+    /// needed by the caller.
+    pub fn encryption_public_key_array(&self) -> &[u8; 32] {
+        self.x25519_pubkey.as_bytes()
+    }
+
+    /// Returns a copy of the X25519 public key, for use with other
+    /// `x25519_dalek` based code
+    pub fn x25519_public(&self) -> x25519::PublicKey {
+        self.x25519_pubkey
+    }
+
+    /// Returns the fingerprint of the public keys.
+    /// The fingerprint is determined by using a SHA256 digest over
+    /// the signing and encryption public key. See `Cert::fingerprint`
+    /// for the fingerprint of a certificate.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let mut raw = Vec::from(self.signing_public_key());
+        raw.extend(self.encryption_public_key());
+        Fingerprint::from(&raw)
+    }
+
+    /// Returns true if `cert` is issued for these public keys.
+    /// The keys are compared in constant time.
+    pub fn matches_cert(&self, cert: &dyn Cert) -> bool {
+        let public = cert.public_key();
+        let mut raw = Vec::from(public.signing_public_key());
+        raw.extend(public.encryption_public_key());
+        verify_slices_are_equal(&raw, &self.to_bytes()).is_ok()
+    }
+
+    /// Returns a compact id for indexing this key.
+    /// See `KeyId` why it must not be used for security decisions.
+    pub fn key_id(&self) -> KeyId {
+        self.fingerprint().key_id()
+    }
+
+    /// Verify a signature given as raw bytes, e.g. taken from the wire.
+    /// Use `verify` if the signature is already a `SignatureBytes`.
+    pub fn verify_raw(&self, msg: &[u8], signature: &[u8]) -> Result<(), VerifyError> {
+        self.check_signature(msg, signature).map_err(|err| {
+            event!(
+                warn,
+                "Signature verification failed for key {}: {}",
+                self.key_id(),
+                err
+            );
+            err
+        })
+    }
+
+    /// Like `verify_raw`, but also rejects signatures which are valid but
+    /// not canonical (`VerifyError::NonCanonical`): `s` not reduced
+    /// modulo the group order, `R` or the public key encoded
+    /// non-canonically or of small order.
+    /// The permissive verification already rejects an unreduced `s`, but
+    /// accepts small order points, e.g. the identity as public key and `R`
+    /// verifies every message with `s = 0`. Use this when signatures are
+    /// hashed or used as ids, so a third party can not derive a different
+    /// valid signature.
+    pub fn verify_strict(&self, msg: &[u8], signature: &SignatureBytes) -> Result<(), VerifyError> {
+        check_canonical(&self.ed25519_pubkey, signature)?;
+        self.verify_raw(msg, signature.as_bytes())
+    }
+
+    /// Like `verify_raw`, but rejects messages longer than `max_len` bytes
+    /// before hashing them, for messages from untrusted sources.
+    pub fn verify_bounded(
+        &self,
+        msg: &[u8],
+        signature: &SignatureBytes,
+        max_len: usize,
+    ) -> Result<(), VerifyError> {
+        if msg.len() > max_len {
+            return Err(VerifyError::TooLong(msg.len(), max_len));
+        }
+        self.verify_raw(msg, signature.as_bytes())
+    }
+
+    /// Verify a signature over a length-prefixed message from
+    /// `sign::frame` and return the payload, so callers know exactly
+    /// which bytes are authenticated.
+    /// The length prefix is checked before the signature.
+    pub fn verify_framed<'a>(
+        &self,
+        framed: &'a [u8],
+        signature: &SignatureBytes,
+    ) -> Result<&'a [u8], VerifyError> {
+        let payload = unframe(framed)?;
+        self.verify_raw(framed, signature.as_bytes())?;
+        Ok(payload)
+    }
+
+    /// Returns only the ED25519 key, for parties which never encrypt to
+    /// this key
+    pub fn signing_only(&self) -> SigningPublicKey {
+        SigningPublicKey::new(self.ed25519_pubkey)
+    }
+
+    /// Returns a verifier holding the checked and decompressed signing
+    /// key, for verifying many signatures without parsing the key for
+    /// each one. It accepts the same signatures as `verify_raw`.
+    /// Fails if the signing key is not a curve point.
+    pub fn verifier(&self) -> Result<Verifier, VerifyError> {
+        self.verifying_key()?;
+        let key = PreparedKey::new(self.ed25519_pubkey).ok_or(VerifyError::InvalidKey)?;
+        Ok(Verifier { key })
+    }
+
+    /// Verify with ring, which parses the key on every call
+    fn check_signature(&self, msg: &[u8], signature: &[u8]) -> Result<(), VerifyError> {
+        if signature.len() != SIGNATURE_LEN {
+            return Err(VerifyError::InvalidLength(signature.len()));
+        }
+        self.verifying_key()?
+            .verify(msg, signature)
+            .map_err(|_| VerifyError::BadSignature)
+    }
+
+    /// Like `verify`, but a failure is only logged at debug level, for
+    /// trying several keys. The caller logs the outcome once.
+    fn verify_candidate(&self, msg: &[u8], signature: &SignatureBytes) -> bool {
+        let valid = self.check_signature(msg, signature.as_bytes()).is_ok();
+        if !valid {
+            event!(debug, "Signature does not match key {}", self.key_id());
+        }
+        valid
+    }
+
+    /// Returns the ED25519 key for verification.
+    /// Only rejects a signing key equal to the encryption key
+    /// (`VerifyError::KeyUsage`), e.g. the X25519 key copied into both
+    /// components. Swapped components are different keys and are not
+    /// detected here, signatures then fail to verify like for any other
+    /// wrong key.
+    fn verifying_key(&self) -> Result<UnparsedPublicKey<&[u8]>, VerifyError> {
+        if self.ed25519_pubkey == *self.x25519_pubkey.as_bytes() {
+            return Err(VerifyError::KeyUsage);
+        }
+        Ok(UnparsedPublicKey::new(
+            &signature::ED25519,
+            &self.ed25519_pubkey,
+        ))
+    }
+
+    /// Verify `msg` and `signature` against each of `keys` and return
+    /// the index of the first matching key.
+    /// Stops at the first match, so the time taken leaks which key matched.
+    pub fn verify_any(
+        keys: &[&AlphaPublic],
+        msg: &[u8],
+        signature: &SignatureBytes,
+    ) -> Option<usize> {
+        let found = keys
+            .iter()
+            .position(|key| key.verify_candidate(msg, signature));
+        if found.is_none() {
+            event!(
+                warn,
+                "Signature verification failed for all {} keys",
+                keys.len()
+            );
+        }
+        found
+    }
+
+    /// Like `verify_any`, but always checks all keys so the time taken
+    /// does not depend on which key matched.
+    pub fn verify_any_constant_time(
+        keys: &[&AlphaPublic],
+        msg: &[u8],
+        signature: &SignatureBytes,
+    ) -> Option<usize> {
+        let mut found = None;
+        for (i, key) in keys.iter().enumerate() {
+            let valid = key.verify_candidate(msg, signature);
+            found = found.or(if valid { Some(i) } else { None });
+        }
+        if found.is_none() {
+            event!(
+                warn,
+                "Signature verification failed for all {} keys",
+                keys.len()
+            );
+        }
+        found
+    }
+}
+
+impl TryFrom<&[u8]> for AlphaPublic {
+    type Error = ParseError;
+
+    /// Parse the encoding from `AlphaPublic::to_bytes`
+    fn try_from(raw: &[u8]) -> Result<Self, Self::Error> {
+        if raw.len() != PUBLIC_BYTES_LEN {
+            return Err(ParseError::InvalidKey(String::from(
+                "Invalid public key length",
+            )));
+        }
+        let mut ed25519_pubkey = [0; 32];
+        ed25519_pubkey.copy_from_slice(&raw[..32]);
+        let mut x25519_pubkey = [0; 32];
+        x25519_pubkey.copy_from_slice(&raw[32..]);
+        Ok(Self::from_raw(ed25519_pubkey, x25519_pubkey))
+    }
+}
+
+/// ED25519 public key of an AlphaPublic without the X25519 key, for
+/// services which only verify signatures, see `AlphaPublic::signing_only`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SigningPublicKey {
+    ed25519_pubkey: [u8; 32],
+}
+
+impl SigningPublicKey {
+    /// Constructs the key from the raw ED25519 public key
+    pub fn new(ed25519_pubkey: [u8; 32]) -> Self {
+        Self { ed25519_pubkey }
+    }
+
+    /// Returns the raw ED25519 public key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.ed25519_pubkey
+    }
+
+    /// Verify `signature` over `msg`
+    pub fn verify(&self, msg: &[u8], signature: &SignatureBytes) -> Result<(), VerifyError> {
+        UnparsedPublicKey::new(&signature::ED25519, &self.ed25519_pubkey[..])
+            .verify(msg, signature.as_bytes())
+            .map_err(|_| VerifyError::BadSignature)
+    }
+}
+
+/// Decompressed signing key of an AlphaPublic, see `AlphaPublic::verifier`
+#[derive(Clone, Copy)]
+pub struct Verifier {
+    key: PreparedKey,
+}
+
+impl Verifier {
+    /// Verify a signature given as raw bytes
+    pub fn verify(&self, msg: &[u8], signature: &[u8]) -> Result<(), VerifyError> {
+        let signature = <&[u8; SIGNATURE_LEN]>::try_from(signature)
+            .map_err(|_| VerifyError::InvalidLength(signature.len()))?;
+        if self.key.verify(msg, signature) {
+            Ok(())
+        } else {
+            Err(VerifyError::BadSignature)
+        }
     }
 }
 
@@ -211,10 +1542,17 @@ impl Public for AlphaPublic {
     }
 
     fn verify(&self, bytes: &dyn AsRef<[u8]>, signature: &SignatureBytes) -> bool {
-        let public_key = UnparsedPublicKey::new(&signature::ED25519, self.signing_public_key());
-        public_key
-            .verify(bytes.as_ref(), signature.as_ref())
-            .is_ok()
+        let valid = self
+            .check_signature(bytes.as_ref(), signature.as_bytes())
+            .is_ok();
+        if !valid {
+            event!(
+                warn,
+                "Signature verification failed for key {}",
+                self.key_id()
+            );
+        }
+        valid
     }
     fn as_variant_ref(&self) -> PublicVariant<'_> {
         PublicVariant::Alpha(self)
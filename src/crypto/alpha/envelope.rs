@@ -0,0 +1,570 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::HashSet;
+use std::io::Write;
+
+use ring::aead;
+
+use rand_core::{OsRng, RngCore};
+
+use x25519_dalek as x25519;
+
+use zeroize::Zeroizing;
+
+use crate::crypto::alpha::{AlphaPublic, AlphaSecret};
+use crate::crypto::parse::parse_with_options;
+use crate::crypto::shared::chacha20_key;
+use crate::crypto::{
+    DecryptError, EncryptError, Fingerprint, ParseError, ParseOptions, Public, SharedSecret,
+    MAX_PLAINTEXT,
+};
+
+/// Per-message key wrapped for the recipient of a detached encryption,
+/// see `AlphaSecret::encrypt_detached`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEnvelope {
+    pub ephemeral_pubkey: [u8; 32],
+    /// Message key and authentication tag
+    pub wrapped_key: Vec<u8>,
+}
+
+impl KeyEnvelope {
+    /// Serialize the envelope as ASN.1 to `stream`
+    pub fn serialize(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_bytes(&self.ephemeral_pubkey);
+                writer.next().write_bytes(&self.wrapped_key);
+            });
+        });
+        stream.write_all(&raw_bytes)
+    }
+
+    /// Restore an envelope written by `serialize`
+    pub fn deserialize(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        let (raw_pubkey, wrapped_key) = parse_with_options(bytes, options, |reader| {
+            reader.read_sequence(|reader| {
+                let ephemeral_pubkey = reader.next().read_bytes()?;
+                let wrapped_key = reader.next().read_bytes()?;
+                Ok((ephemeral_pubkey, wrapped_key))
+            })
+        })?;
+        if raw_pubkey.len() != 32 {
+            return Err(ParseError::InvalidKey(String::from(
+                "Invalid ephemeral key length",
+            )));
+        }
+        let mut ephemeral_pubkey = [0; 32];
+        ephemeral_pubkey.copy_from_slice(&raw_pubkey);
+        Ok(Self {
+            ephemeral_pubkey,
+            wrapped_key,
+        })
+    }
+}
+
+/// Message key wrapped for one recipient of a `MultiEncrypted`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientSlot {
+    /// Fingerprint of the recipient public keys, see
+    /// `AlphaPublic::fingerprint`
+    pub fingerprint: Fingerprint,
+    /// Message key and authentication tag
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Message encrypted once for several recipients,
+/// see `AlphaSecret::encrypt_to_many`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiEncrypted {
+    pub ephemeral_pubkey: [u8; 32],
+    slots: Vec<RecipientSlot>,
+    /// Encrypted data including the authentication tag
+    pub data: Vec<u8>,
+}
+
+impl MultiEncrypted {
+    /// Returns the slots of all recipients, in the order the recipients
+    /// were given to `encrypt_to_many`.
+    /// The slots can be inspected before decrypting, e.g. to count the
+    /// recipients or to find the own slot by fingerprint.
+    pub fn recipient_slots(&self) -> impl Iterator<Item = &RecipientSlot> {
+        self.slots.iter()
+    }
+
+    /// Serialize the message as ASN.1 to `stream`:
+    /// SEQUENCE { magic INTEGER, version INTEGER, ephemeral key OCTET STRING,
+    /// slots SEQUENCE OF SEQUENCE { fingerprint OCTET STRING,
+    /// wrapped key OCTET STRING }, data OCTET STRING }
+    /// The fingerprints are stored with their algorithm tag, see
+    /// `Fingerprint::to_bytes`.
+    pub fn serialize(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_i64(MULTI_MAGIC);
+                writer.next().write_i64(MULTI_VERSION);
+                writer.next().write_bytes(&self.ephemeral_pubkey);
+                writer.next().write_sequence(|writer| {
+                    for slot in &self.slots {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_bytes(&slot.fingerprint.to_bytes());
+                            writer.next().write_bytes(&slot.wrapped_key);
+                        });
+                    }
+                });
+                writer.next().write_bytes(&self.data);
+            });
+        });
+        stream.write_all(&raw_bytes)
+    }
+
+    /// Restore a message written by `serialize`
+    pub fn deserialize(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        let (magic, version, raw_pubkey, raw_slots, data) =
+            parse_with_options(bytes, options, |reader| {
+                reader.read_sequence(|reader| {
+                    let magic = reader.next().read_i64()?;
+                    let version = reader.next().read_i64()?;
+                    let ephemeral_pubkey = reader.next().read_bytes()?;
+                    let slots = reader.next().collect_sequence_of(|reader| {
+                        reader.read_sequence(|reader| {
+                            let fingerprint = reader.next().read_bytes()?;
+                            let wrapped_key = reader.next().read_bytes()?;
+                            Ok((fingerprint, wrapped_key))
+                        })
+                    })?;
+                    let data = reader.next().read_bytes()?;
+                    Ok((magic, version, ephemeral_pubkey, slots, data))
+                })
+            })?;
+        if magic != MULTI_MAGIC {
+            return Err(ParseError::BadMagic);
+        }
+        if version != MULTI_VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+        if raw_pubkey.len() != 32 {
+            return Err(ParseError::InvalidKey(String::from(
+                "Invalid ephemeral key length",
+            )));
+        }
+        let mut ephemeral_pubkey = [0; 32];
+        ephemeral_pubkey.copy_from_slice(&raw_pubkey);
+        let slots = raw_slots
+            .into_iter()
+            .map(|(fingerprint, wrapped_key)| {
+                if wrapped_key.len() != WRAPPED_KEY_LEN {
+                    return Err(ParseError::InvalidKey(String::from(
+                        "Invalid wrapped key length",
+                    )));
+                }
+                Ok(RecipientSlot {
+                    fingerprint: Fingerprint::from_bytes(&fingerprint)?,
+                    wrapped_key,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            ephemeral_pubkey,
+            slots,
+            data,
+        })
+    }
+}
+
+/// Format identifier at the start of a serialized `MultiEncrypted`
+pub const MULTI_MAGIC: i64 = 0xfe73ba2004;
+
+/// Version of the serialized `MultiEncrypted` format
+pub const MULTI_VERSION: i64 = 1;
+
+/// Length of a message key wrapped for one recipient (key and tag)
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+
+/// HKDF label prefix of the key wrapping the message key
+const ENVELOPE_WRAP_DOMAIN: &[u8] = b"stackment envelope";
+
+/// Each key is used for one message only
+fn nonce() -> aead::Nonce {
+    aead::Nonce::assume_unique_for_key([0; 12])
+}
+
+/// Wrap `message_key` for one recipient of a `MultiEncrypted` or a
+/// `KeyEnvelope`
+fn wrap_slot(
+    message_key: &[u8; 32],
+    ephemeral_secret: &x25519::StaticSecret,
+    ephemeral_pubkey: &[u8; 32],
+    peer: &AlphaPublic,
+) -> Result<RecipientSlot, EncryptError> {
+    let recipient = *peer.encryption_public_key_array();
+    let shared = ephemeral_secret.diffie_hellman(&x25519::PublicKey::from(recipient));
+    let mut wrapped_key = Vec::from(&message_key[..]);
+    SharedSecret::from(*shared.as_bytes())
+        .wrapping_key(ENVELOPE_WRAP_DOMAIN, ephemeral_pubkey, &recipient)
+        .and_then(|key| key.seal_in_place_append_tag(nonce(), aead::Aad::empty(), &mut wrapped_key))
+        .map_err(|_| EncryptError::Crypto)?;
+    Ok(RecipientSlot {
+        fingerprint: peer.fingerprint(),
+        wrapped_key,
+    })
+}
+
+/// Wrap `message_key` for all `peers`, the slots are in the order of
+/// `peers`
+#[cfg(not(feature = "rayon"))]
+fn wrap_slots(
+    message_key: &[u8; 32],
+    ephemeral_secret: &x25519::StaticSecret,
+    ephemeral_pubkey: &[u8; 32],
+    peers: &[&AlphaPublic],
+) -> Result<Vec<RecipientSlot>, EncryptError> {
+    peers
+        .iter()
+        .map(|peer| wrap_slot(message_key, ephemeral_secret, ephemeral_pubkey, peer))
+        .collect()
+}
+
+/// Wrap `message_key` for all `peers` in parallel. Collecting keeps the
+/// order of `peers`, independent of the thread scheduling.
+#[cfg(feature = "rayon")]
+fn wrap_slots(
+    message_key: &[u8; 32],
+    ephemeral_secret: &x25519::StaticSecret,
+    ephemeral_pubkey: &[u8; 32],
+    peers: &[&AlphaPublic],
+) -> Result<Vec<RecipientSlot>, EncryptError> {
+    use rayon::prelude::*;
+
+    peers
+        .par_iter()
+        .map(|peer| wrap_slot(message_key, ephemeral_secret, ephemeral_pubkey, peer))
+        .collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn find_slot<'a>(
+    slots: &'a [RecipientSlot],
+    fingerprint: &Fingerprint,
+) -> Option<&'a RecipientSlot> {
+    slots.iter().find(|slot| slot.fingerprint == *fingerprint)
+}
+
+/// Returns the first matching slot, like the sequential search
+#[cfg(feature = "rayon")]
+fn find_slot<'a>(
+    slots: &'a [RecipientSlot],
+    fingerprint: &Fingerprint,
+) -> Option<&'a RecipientSlot> {
+    use rayon::prelude::*;
+
+    slots
+        .par_iter()
+        .find_first(|slot| slot.fingerprint == *fingerprint)
+}
+
+impl AlphaSecret {
+    /// Encrypt `plain_bytes` for `peer` with a random message key, which is
+    /// returned wrapped for `peer` separately from the ciphertext.
+    /// The envelope and the ciphertext can be stored and transported
+    /// independently, e.g. the ciphertext in object storage and the
+    /// envelope in a database.
+    pub fn encrypt_detached(
+        &self,
+        plain_bytes: &[u8],
+        peer: &AlphaPublic,
+    ) -> Result<(KeyEnvelope, Vec<u8>), EncryptError> {
+        let len = plain_bytes.len();
+        if len > MAX_PLAINTEXT {
+            return Err(EncryptError::TooLarge(len, MAX_PLAINTEXT));
+        }
+        let mut message_key = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(&mut message_key[..]);
+        let mut ciphertext = Vec::from(plain_bytes);
+        chacha20_key(&message_key[..])
+            .and_then(|key| {
+                key.seal_in_place_append_tag(nonce(), aead::Aad::empty(), &mut ciphertext)
+            })
+            .map_err(|_| EncryptError::Crypto)?;
+
+        // Only used for this envelope, dropped at the end of the call
+        let ephemeral_secret = x25519::StaticSecret::new(&mut OsRng);
+        let ephemeral_pubkey = *x25519::PublicKey::from(&ephemeral_secret).as_bytes();
+        let slot = wrap_slot(&message_key, &ephemeral_secret, &ephemeral_pubkey, peer)?;
+        Ok((
+            KeyEnvelope {
+                ephemeral_pubkey,
+                wrapped_key: slot.wrapped_key,
+            },
+            ciphertext,
+        ))
+    }
+
+    /// Decrypt `ciphertext` from `encrypt_detached` with the message key
+    /// unwrapped from `envelope`
+    pub fn decrypt_detached(
+        &self,
+        envelope: &KeyEnvelope,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, DecryptError> {
+        let mut wrapped_key = Zeroizing::new(envelope.wrapped_key.clone());
+        let message_key = self
+            .agree_raw(envelope.ephemeral_pubkey)
+            .wrapping_key(
+                ENVELOPE_WRAP_DOMAIN,
+                &envelope.ephemeral_pubkey,
+                self.public_key().encryption_public_key(),
+            )
+            .and_then(|key| key.open_in_place(nonce(), aead::Aad::empty(), &mut wrapped_key[..]))
+            .map_err(|_| DecryptError::Decryption)?;
+        let mut in_out = Vec::from(ciphertext);
+        let plain = chacha20_key(message_key)
+            .and_then(|key| key.open_in_place(nonce(), aead::Aad::empty(), &mut in_out))
+            .map_err(|_| DecryptError::Decryption)?;
+        Ok(Vec::from(plain))
+    }
+
+    /// Encrypt `plain_bytes` once with a random message key, which is
+    /// wrapped for each of `peers` in its own recipient slot.
+    /// Peers given more than once get a single slot, at the position of
+    /// their first occurrence. Use `encrypt_to_many_strict` to reject them.
+    /// With the `rayon` feature, the keys are wrapped in parallel.
+    pub fn encrypt_to_many(
+        &self,
+        plain_bytes: &[u8],
+        peers: &[&AlphaPublic],
+    ) -> Result<MultiEncrypted, EncryptError> {
+        self.encrypt_to_many_checked(plain_bytes, peers, false)
+    }
+
+    /// Like `encrypt_to_many`, but fails with
+    /// `EncryptError::DuplicateRecipient` if a peer is given more than once
+    pub fn encrypt_to_many_strict(
+        &self,
+        plain_bytes: &[u8],
+        peers: &[&AlphaPublic],
+    ) -> Result<MultiEncrypted, EncryptError> {
+        self.encrypt_to_many_checked(plain_bytes, peers, true)
+    }
+
+    fn encrypt_to_many_checked(
+        &self,
+        plain_bytes: &[u8],
+        peers: &[&AlphaPublic],
+        strict: bool,
+    ) -> Result<MultiEncrypted, EncryptError> {
+        let mut seen = HashSet::new();
+        let mut unique = Vec::with_capacity(peers.len());
+        for peer in peers {
+            let fingerprint = peer.fingerprint();
+            if seen.insert(fingerprint) {
+                unique.push(*peer);
+            } else if strict {
+                return Err(EncryptError::DuplicateRecipient(fingerprint));
+            }
+        }
+        let len = plain_bytes.len();
+        if len > MAX_PLAINTEXT {
+            return Err(EncryptError::TooLarge(len, MAX_PLAINTEXT));
+        }
+        let mut message_key = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(&mut message_key[..]);
+        let mut data = Vec::from(plain_bytes);
+        chacha20_key(&message_key[..])
+            .and_then(|key| key.seal_in_place_append_tag(nonce(), aead::Aad::empty(), &mut data))
+            .map_err(|_| EncryptError::Crypto)?;
+
+        // The wrapping keys differ per recipient, so one ephemeral key
+        // is enough for all slots
+        let ephemeral_secret = x25519::StaticSecret::new(&mut OsRng);
+        let ephemeral_pubkey = *x25519::PublicKey::from(&ephemeral_secret).as_bytes();
+        let slots = wrap_slots(&message_key, &ephemeral_secret, &ephemeral_pubkey, &unique)?;
+        Ok(MultiEncrypted {
+            ephemeral_pubkey,
+            slots,
+            data,
+        })
+    }
+
+    /// Decrypt a message from `encrypt_to_many` with the message key
+    /// from the slot matching our fingerprint
+    pub fn decrypt_from_many(&self, enc: &MultiEncrypted) -> Result<Vec<u8>, DecryptError> {
+        let public = self.public_key();
+        let fingerprint = public.fingerprint();
+        let slot = find_slot(&enc.slots, &fingerprint).ok_or(DecryptError::Decryption)?;
+        let mut wrapped_key = Zeroizing::new(slot.wrapped_key.clone());
+        let message_key = self
+            .agree_raw(enc.ephemeral_pubkey)
+            .wrapping_key(
+                ENVELOPE_WRAP_DOMAIN,
+                &enc.ephemeral_pubkey,
+                public.encryption_public_key(),
+            )
+            .and_then(|key| key.open_in_place(nonce(), aead::Aad::empty(), &mut wrapped_key[..]))
+            .map_err(|_| DecryptError::Decryption)?;
+        let mut in_out = enc.data.clone();
+        let plain = chacha20_key(message_key)
+            .and_then(|key| key.open_in_place(nonce(), aead::Aad::empty(), &mut in_out))
+            .map_err(|_| DecryptError::Decryption)?;
+        Ok(Vec::from(plain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_parts_from_different_sources() {
+        let sender = AlphaSecret::new();
+        let recipient = AlphaSecret::new();
+        let plain = vec![0x42; 100_000];
+        let (envelope, ciphertext) = sender
+            .encrypt_detached(&plain, recipient.public_key())
+            .unwrap();
+        // The envelope goes through a database, the ciphertext through
+        // object storage
+        let mut database = Vec::new();
+        envelope.serialize(&mut database).unwrap();
+        let object_storage = ciphertext.clone();
+
+        let envelope = KeyEnvelope::deserialize(&database, ParseOptions::strict()).unwrap();
+        let decrypted = recipient
+            .decrypt_detached(&envelope, &object_storage)
+            .unwrap();
+        assert_eq!(decrypted, plain);
+
+        // Wrong recipient or mismatching parts
+        assert!(sender.decrypt_detached(&envelope, &object_storage).is_err());
+        let (other_envelope, _) = sender
+            .encrypt_detached(&plain, recipient.public_key())
+            .unwrap();
+        assert!(recipient
+            .decrypt_detached(&other_envelope, &object_storage)
+            .is_err());
+    }
+
+    #[test]
+    fn inspect_recipient_slots() {
+        let sender = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let carol = AlphaSecret::new();
+        let dave = AlphaSecret::new();
+        let enc = sender
+            .encrypt_to_many(b"hello", &[bob.public_key(), carol.public_key()])
+            .unwrap();
+        assert_eq!(enc.recipient_slots().count(), 2);
+
+        let fingerprint = carol.public_key().fingerprint();
+        let position = enc
+            .recipient_slots()
+            .position(|slot| slot.fingerprint == fingerprint);
+        assert_eq!(position, Some(1));
+        let dave_fingerprint = dave.public_key().fingerprint();
+        assert!(enc
+            .recipient_slots()
+            .all(|slot| slot.fingerprint != dave_fingerprint));
+
+        assert_eq!(bob.decrypt_from_many(&enc).unwrap(), b"hello");
+        assert_eq!(carol.decrypt_from_many(&enc).unwrap(), b"hello");
+        assert!(dave.decrypt_from_many(&enc).is_err());
+    }
+
+    #[test]
+    fn serialize_multi_encrypted() {
+        let sender = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let carol = AlphaSecret::new();
+        let enc = sender
+            .encrypt_to_many(b"stored", &[bob.public_key(), carol.public_key()])
+            .unwrap();
+        let mut raw = Vec::new();
+        enc.serialize(&mut raw).unwrap();
+        let restored = MultiEncrypted::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(restored, enc);
+        assert_eq!(bob.decrypt_from_many(&restored).unwrap(), b"stored");
+        assert_eq!(carol.decrypt_from_many(&restored).unwrap(), b"stored");
+
+        assert!(
+            MultiEncrypted::deserialize(&raw[..raw.len() - 1], ParseOptions::strict()).is_err()
+        );
+        // Magic `02 06 00 fe 73 ba 20 04`, followed by the version `02 01 01`
+        let pos = raw
+            .windows(11)
+            .position(|w| {
+                w == [
+                    0x02, 0x06, 0x00, 0xfe, 0x73, 0xba, 0x20, 0x04, 0x02, 0x01, 0x01,
+                ]
+            })
+            .unwrap();
+        let mut other_magic = raw.clone();
+        other_magic[pos + 7] = 0x03;
+        match MultiEncrypted::deserialize(&other_magic, ParseOptions::strict()) {
+            Err(ParseError::BadMagic) => {}
+            _ => panic!("wrong magic accepted"),
+        }
+        raw[pos + 10] = 2;
+        match MultiEncrypted::deserialize(&raw, ParseOptions::strict()) {
+            Err(ParseError::UnsupportedVersion(2)) => {}
+            _ => panic!("unknown version accepted"),
+        }
+    }
+
+    #[test]
+    fn duplicate_recipients() {
+        let sender = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let carol = AlphaSecret::new();
+        let peers = [bob.public_key(), carol.public_key(), bob.public_key()];
+        let enc = sender.encrypt_to_many(b"hello", &peers).unwrap();
+        let fingerprints: Vec<Fingerprint> =
+            enc.recipient_slots().map(|slot| slot.fingerprint).collect();
+        assert_eq!(
+            fingerprints,
+            vec![
+                bob.public_key().fingerprint(),
+                carol.public_key().fingerprint()
+            ]
+        );
+        assert_eq!(bob.decrypt_from_many(&enc).unwrap(), b"hello");
+
+        match sender.encrypt_to_many_strict(b"hello", &peers) {
+            Err(EncryptError::DuplicateRecipient(fingerprint)) => {
+                assert_eq!(fingerprint, bob.public_key().fingerprint())
+            }
+            _ => panic!("duplicate recipient accepted"),
+        }
+        let enc = sender
+            .encrypt_to_many_strict(b"hello", &peers[..2])
+            .unwrap();
+        assert_eq!(carol.decrypt_from_many(&enc).unwrap(), b"hello");
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_wrapping_matches_sequential() {
+        let secrets: Vec<AlphaSecret> = (0..20).map(|_| AlphaSecret::new()).collect();
+        let peers: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        let message_key = [0x42; 32];
+        let ephemeral_secret = x25519::StaticSecret::from([0x24; 32]);
+        let ephemeral_pubkey = *x25519::PublicKey::from(&ephemeral_secret).as_bytes();
+
+        let parallel =
+            wrap_slots(&message_key, &ephemeral_secret, &ephemeral_pubkey, &peers).unwrap();
+        let sequential: Vec<RecipientSlot> = peers
+            .iter()
+            .map(|peer| wrap_slot(&message_key, &ephemeral_secret, &ephemeral_pubkey, peer))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        // The order of the recipients is kept, not only the set of slots
+        assert_eq!(parallel, sequential);
+        for secret in &secrets {
+            let slot = find_slot(&parallel, &secret.public_key().fingerprint()).unwrap();
+            assert_eq!(slot.fingerprint, secret.public_key().fingerprint());
+        }
+    }
+}
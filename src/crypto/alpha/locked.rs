@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::ops::Deref;
+
+use crate::crypto::alpha::AlphaSecret;
+
+/// AlphaSecret kept in memory pages locked with `mlock`, so the secret
+/// keys are not swapped to disk. The pages are unlocked on drop.
+/// Locking is best effort: it is limited by RLIMIT_MEMLOCK and only
+/// supported on unix. If it fails, the secret is still usable and
+/// `is_locked` returns false.
+/// The secret is moved to the heap on construction, copies made before
+/// (e.g. on the stack) are not protected.
+pub struct LockedAlphaSecret {
+    inner: Box<AlphaSecret>,
+    locked: bool,
+}
+
+impl LockedAlphaSecret {
+    /// Move `secret` into locked memory
+    pub fn new(secret: AlphaSecret) -> Self {
+        let inner = Box::new(secret);
+        let locked = lock(&inner);
+        Self { inner, locked }
+    }
+
+    /// Returns true if the memory of the secret is locked
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+#[cfg(unix)]
+fn lock(secret: &AlphaSecret) -> bool {
+    let ptr = secret as *const AlphaSecret as *const libc::c_void;
+    unsafe { libc::mlock(ptr, std::mem::size_of::<AlphaSecret>()) == 0 }
+}
+
+#[cfg(not(unix))]
+fn lock(_secret: &AlphaSecret) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn unlock(secret: &AlphaSecret) {
+    let ptr = secret as *const AlphaSecret as *const libc::c_void;
+    unsafe {
+        libc::munlock(ptr, std::mem::size_of::<AlphaSecret>());
+    }
+}
+
+#[cfg(not(unix))]
+fn unlock(_secret: &AlphaSecret) {}
+
+impl Deref for LockedAlphaSecret {
+    type Target = AlphaSecret;
+
+    fn deref(&self) -> &AlphaSecret {
+        &self.inner
+    }
+}
+
+impl Drop for LockedAlphaSecret {
+    fn drop(&mut self) {
+        if self.locked {
+            unlock(&self.inner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Public, Secret};
+
+    #[test]
+    fn locked_secret_is_usable() {
+        let secret = LockedAlphaSecret::new(AlphaSecret::new());
+        // Locking may fail in restricted environments, the secret must
+        // work either way
+        let msg = b"locked";
+        assert!(secret.public_key().verify(&msg, &secret.sign(&msg)));
+        #[cfg(not(unix))]
+        assert!(!secret.is_locked());
+    }
+}
@@ -6,38 +6,1421 @@
 
 mod alphacert;
 mod alphasecret;
+mod envelope;
+#[cfg(feature = "mlock")]
+mod locked;
 
 pub use alphacert::AlphaCert;
 pub use alphasecret::AlphaSecret;
+pub use alphasecret::AlphaSecretBuilder;
+pub use alphasecret::AlphaKeyPair;
 pub use alphasecret::AlphaPublic;
-
+pub use alphasecret::AuthTag;
+pub use alphasecret::MasterSeed;
+pub use alphasecret::SigningPublicKey;
+pub use alphasecret::Verifier;
+pub use envelope::KeyEnvelope;
+pub use envelope::MultiEncrypted;
+pub use envelope::RecipientSlot;
+#[cfg(feature = "mlock")]
+pub use locked::LockedAlphaSecret;
+pub(crate) use alphasecret::derive_key;
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::crypto::*;
+    use std::ops::Deref;
+
+    #[test]
+    fn split_key_pair() {
+        let (secret, public) = AlphaKeyPair::generate().into_parts();
+        assert_eq!(public.fingerprint(), secret.public_key().fingerprint());
+        // The public half is used on its own by another party
+        let sender = AlphaSecret::new();
+        let crypted = sender.encrypt(b"for the owner", &public).unwrap();
+        assert_eq!(secret.decrypt(&crypted).unwrap(), b"for the owner");
+        let sig = secret.sign(b"from the owner");
+        assert!(public.verify(b"from the owner", &sig));
+
+        let pair = AlphaKeyPair::from(AlphaSecret::from_parts([1; 32], [2; 32]));
+        assert_eq!(
+            pair.public.fingerprint(),
+            AlphaSecret::from_parts([1; 32], [2; 32])
+                .public_key()
+                .fingerprint()
+        );
+    }
+
+    #[test]
+    fn verify_strict_rejects_malleated_signature() {
+        use std::convert::TryFrom;
+        let isec = AlphaSecret::new();
+        let public = isec.public_key();
+        let sig = isec.sign(b"message");
+        public.verify_strict(b"message", &sig).unwrap();
+        assert!(public.verify_strict(b"other", &sig).is_err());
+
+        // s + L is another encoding of s modulo the group order
+        let order = curve25519_dalek::constants::BASEPOINT_ORDER.to_bytes();
+        let mut raw = [0; 64];
+        raw.copy_from_slice(sig.as_bytes());
+        let mut carry = 0;
+        for (s, l) in raw[32..].iter_mut().zip(order.iter()) {
+            let sum = u16::from(*s) + u16::from(*l) + carry;
+            *s = sum as u8;
+            carry = sum >> 8;
+        }
+        let malleated = SignatureBytes::try_from(&raw[..]).unwrap();
+        match public.verify_strict(b"message", &malleated) {
+            Err(VerifyError::NonCanonical) => {}
+            _ => panic!("malleated signature accepted"),
+        }
+
+        // Identity as public key and R, valid for any message with s = 0,
+        // only rejected by the strict verification
+        let mut identity = [0; 64];
+        identity[0] = 1;
+        let weak = AlphaPublic::try_from(&identity[..]).unwrap();
+        let forged = SignatureBytes::try_from(&identity[..]).unwrap();
+        assert!(weak.verify_raw(b"message", forged.as_bytes()).is_ok());
+        match weak.verify_strict(b"message", &forged) {
+            Err(VerifyError::NonCanonical) => {}
+            _ => panic!("small order signature accepted"),
+        }
+    }
 
     #[test]
     fn sign_and_verify() {
         let isec = AlphaSecret::new();
         let data = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
         let sig = isec.sign(&data);
-        assert_eq!(isec.public_key().verify(&data, &sig), true);
+        assert!(isec.public_key().verify(&data, &sig));
         let data = vec![0x01, 0x02, 0x03];
-        assert_eq!(isec.public_key().verify(&data, &sig), false);
+        assert!(!isec.public_key().verify(&data, &sig));
+    }
+
+    fn takes_bytes(x: impl AsRef<[u8]>) -> usize {
+        x.as_ref().len()
+    }
+
+    #[test]
+    fn bytes_views() {
+        let isec = AlphaSecret::new();
+        let data = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
+        let sig = isec.sign(&data);
+        assert_eq!(takes_bytes(&sig), 64);
+        assert_eq!(sig.as_bytes(), sig.as_ref());
+        let crypted = isec.encrypt(&data, isec.public_key()).unwrap();
+        assert_eq!(takes_bytes(&crypted), data.len() + 16);
+        assert_eq!(takes_bytes(&crypted.ephemeral_pubkey), 32);
+    }
+
+    #[test]
+    fn verify_raw_signature() {
+        let isec = AlphaSecret::new();
+        let msg = b"from the wire";
+        let signature = isec.sign(&msg);
+        let raw: &[u8] = signature.as_bytes();
+        assert_eq!(raw.len(), 64);
+        assert!(isec.public_key().verify_raw(msg, raw).is_ok());
+        match isec.public_key().verify_raw(b"other message", raw) {
+            Err(VerifyError::BadSignature) => {}
+            _ => panic!("signature verified for wrong message"),
+        }
+        match isec.public_key().verify_raw(msg, &raw[..63]) {
+            Err(VerifyError::InvalidLength(63)) => {}
+            _ => panic!("short signature accepted"),
+        }
+    }
+
+    #[test]
+    fn encryption_public_key_array() {
+        let isec = AlphaSecret::new();
+        let array: &[u8; 32] = isec.public_key().encryption_public_key_array();
+        assert_eq!(array.len(), 32);
+        assert_eq!(&array[..], isec.public_key().encryption_public_key());
+    }
+
+    #[test]
+    fn verify_rejects_encryption_key() {
+        let isec = AlphaSecret::new();
+        let msg = b"signed";
+        let signature = isec.sign(&msg);
+        let public = isec.public_key();
+        // Swap the signing and encryption key
+        let swapped = AlphaPublic::from_raw(
+            *public.encryption_public_key_array(),
+            *array_ref(public.signing_public_key()),
+        );
+        assert!(!swapped.verify(&msg, &signature));
+        // The X25519 key in both places
+        let reused = AlphaPublic::from_raw(
+            *public.encryption_public_key_array(),
+            *public.encryption_public_key_array(),
+        );
+        assert!(!reused.verify(&msg, &signature));
+        match reused.verify_raw(msg, signature.as_bytes()) {
+            Err(VerifyError::KeyUsage) => {}
+            _ => panic!("encryption key used for verification"),
+        }
+        assert!(public.verify(&msg, &signature));
+    }
+
+    #[test]
+    fn verify_bounded_length() {
+        let isec = AlphaSecret::new();
+        let msg = [7; 64];
+        let signature = isec.sign(&msg);
+        let public = isec.public_key();
+        assert!(public.verify_bounded(&msg, &signature, 64).is_ok());
+        match public.verify_bounded(&msg, &signature, 63) {
+            Err(VerifyError::TooLong(64, 63)) => {}
+            _ => panic!("long message accepted"),
+        }
+        match public.verify_bounded(&msg[..63], &signature, 64) {
+            Err(VerifyError::BadSignature) => {}
+            _ => panic!("wrong message accepted"),
+        }
+    }
+
+    #[test]
+    fn verify_signing_only() {
+        let isec = AlphaSecret::new();
+        let public = isec.public_key();
+        let signing = public.signing_only();
+        assert_eq!(&signing.as_bytes()[..], public.signing_public_key());
+        assert_eq!(SigningPublicKey::new(*signing.as_bytes()), signing);
+        let signature = isec.sign(b"message");
+        assert!(public.verify(b"message", &signature));
+        assert!(signing.verify(b"message", &signature).is_ok());
+        assert!(!public.verify(b"other", &signature));
+        match signing.verify(b"other", &signature) {
+            Err(VerifyError::BadSignature) => {}
+            _ => panic!("wrong message accepted"),
+        }
+        let other = AlphaSecret::new().sign(b"message");
+        assert!(!public.verify(b"message", &other));
+        assert!(signing.verify(b"message", &other).is_err());
+    }
+
+    #[test]
+    fn verify_framed_payload() {
+        let isec = AlphaSecret::new();
+        let public = isec.public_key();
+        let framed = sign::frame(b"payload");
+        assert_eq!(&framed[..sign::FRAME_PREFIX_LEN], &[0, 0, 0, 7]);
+        let signature = isec.sign(&framed);
+        assert_eq!(
+            public.verify_framed(&framed, &signature).unwrap(),
+            b"payload"
+        );
+
+        // Length prefix mismatch, even with a valid signature
+        let mut extended = framed.clone();
+        extended.push(0);
+        let signature = isec.sign(&extended);
+        match public.verify_framed(&extended, &signature) {
+            Err(VerifyError::InvalidFrame(12)) => {}
+            _ => panic!("wrong length prefix accepted"),
+        }
+        match public.verify_framed(&framed[..3], &signature) {
+            Err(VerifyError::InvalidFrame(3)) => {}
+            _ => panic!("truncated prefix accepted"),
+        }
+
+        let signature = AlphaSecret::new().sign(&framed);
+        match public.verify_framed(&framed, &signature) {
+            Err(VerifyError::BadSignature) => {}
+            _ => panic!("bad signature accepted"),
+        }
+    }
+
+    #[test]
+    fn reuse_verifier() {
+        let isec = AlphaSecret::new();
+        let verifier = isec.public_key().verifier().unwrap();
+        for i in 0..4u8 {
+            let msg = [i; 16];
+            let signature = isec.sign(&msg);
+            assert!(verifier.verify(&msg, signature.as_bytes()).is_ok());
+            assert!(verifier.verify(&[i + 1; 16], signature.as_bytes()).is_err());
+        }
+        let public = isec.public_key();
+        let reused = AlphaPublic::from_raw(
+            *public.encryption_public_key_array(),
+            *public.encryption_public_key_array(),
+        );
+        assert!(reused.verifier().is_err());
+
+        // Swapped components pass the guard, but verify nothing
+        let msg = b"swapped";
+        let signature = isec.sign(&msg);
+        let swapped = AlphaPublic::from_raw(
+            *public.encryption_public_key_array(),
+            *array_ref(public.signing_public_key()),
+        );
+        match swapped.verify_raw(msg, signature.as_bytes()) {
+            Err(VerifyError::BadSignature) => {}
+            _ => panic!("swapped key verified"),
+        }
+        assert!(!swapped.verify(&msg, &signature));
+        assert!(swapped
+            .verifier()
+            .and_then(|verifier| verifier.verify(msg, signature.as_bytes()))
+            .is_err());
+    }
+
+    #[test]
+    fn verifier_agrees_with_verify_raw() {
+        use std::convert::TryFrom;
+        let isec = AlphaSecret::new();
+        let public = isec.public_key();
+        let verifier = public.verifier().unwrap();
+        let sig = isec.sign(b"message");
+        let mut tampered = [0; 64];
+        tampered.copy_from_slice(sig.as_bytes());
+        tampered[10] ^= 0x01;
+        // s + L, rejected by both
+        let mut unreduced = [0; 64];
+        unreduced.copy_from_slice(sig.as_bytes());
+        let order = curve25519_dalek::constants::BASEPOINT_ORDER.to_bytes();
+        let mut carry = 0;
+        for (s, l) in unreduced[32..].iter_mut().zip(order.iter()) {
+            let sum = u16::from(*s) + u16::from(*l) + carry;
+            *s = sum as u8;
+            carry = sum >> 8;
+        }
+        for (msg, raw) in &[
+            (&b"message"[..], sig.as_bytes()),
+            (&b"other"[..], sig.as_bytes()),
+            (&b"message"[..], &tampered),
+            (&b"message"[..], &unreduced),
+        ] {
+            assert_eq!(
+                verifier.verify(msg, &raw[..]).is_ok(),
+                public.verify_raw(msg, &raw[..]).is_ok()
+            );
+        }
+        assert!(verifier.verify(b"message", sig.as_bytes()).is_ok());
+        match verifier.verify(b"message", &sig.as_bytes()[..63]) {
+            Err(VerifyError::InvalidLength(63)) => {}
+            _ => panic!("short signature accepted"),
+        }
+
+        // Small order key, accepted by both, see `verify_strict`
+        let mut identity = [0; 64];
+        identity[0] = 1;
+        let weak = AlphaPublic::try_from(&identity[..]).unwrap();
+        assert!(weak.verify_raw(b"message", &identity).is_ok());
+        assert!(weak
+            .verifier()
+            .unwrap()
+            .verify(b"message", &identity)
+            .is_ok());
+
+        // Not a curve point, the key is rejected once
+        let y = (2..=u8::MAX)
+            .find(|y| {
+                let mut raw = [0; 32];
+                raw[0] = *y;
+                curve25519_dalek::edwards::CompressedEdwardsY(raw)
+                    .decompress()
+                    .is_none()
+            })
+            .unwrap();
+        let mut raw = [0; 64];
+        raw[0] = y;
+        raw[32] = 9;
+        let invalid = AlphaPublic::try_from(&raw[..]).unwrap();
+        match invalid.verifier() {
+            Err(VerifyError::InvalidKey) => {}
+            _ => panic!("invalid key accepted"),
+        }
+        assert!(invalid.verify_raw(b"message", sig.as_bytes()).is_err());
+    }
+
+    fn array_ref(bytes: &[u8]) -> &[u8; 32] {
+        use std::convert::TryInto;
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn key_ids() {
+        let isec = AlphaSecret::new();
+        assert_eq!(isec.public_key().key_id(), isec.public_key().key_id());
+        assert_eq!(
+            isec.public_key().key_id(),
+            isec.public_key().fingerprint().key_id()
+        );
+        let mut ids: Vec<KeyId> = (0..256)
+            .map(|_| AlphaSecret::new().public_key().key_id())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 256);
+    }
+
+    #[test]
+    fn verify_any_of_several_keys() {
+        let secrets: Vec<AlphaSecret> = (0..4).map(|_| AlphaSecret::new()).collect();
+        let keys: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        let data = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
+        for (i, secret) in secrets.iter().enumerate() {
+            let sig = secret.sign(&data);
+            assert_eq!(AlphaPublic::verify_any(&keys, &data, &sig), Some(i));
+            assert_eq!(
+                AlphaPublic::verify_any_constant_time(&keys, &data, &sig),
+                Some(i)
+            );
+        }
+        let sig = AlphaSecret::new().sign(&data);
+        assert_eq!(AlphaPublic::verify_any(&keys, &data, &sig), None);
+        assert_eq!(
+            AlphaPublic::verify_any_constant_time(&keys, &data, &sig),
+            None
+        );
+        assert_eq!(AlphaPublic::verify_any(&[], &data, &sig), None);
+    }
+
+    #[test]
+    fn agree_on_shared_secret() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let a = alice.agree(bob.public_key()).derive_subkey(b"test", 32);
+        let b = bob.agree(alice.public_key()).derive_subkey(b"test", 32);
+        assert_eq!(a, b);
+        let eve = AlphaSecret::new();
+        assert_ne!(a, eve.agree(bob.public_key()).derive_subkey(b"test", 32));
+    }
+
+    #[test]
+    fn x25519_public_agrees_with_ephemeral() {
+        let bob = AlphaSecret::new();
+        let ephemeral = x25519_dalek::EphemeralSecret::new(&mut rand_core::OsRng);
+        let ephemeral_pubkey = x25519_dalek::PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&bob.public_key().x25519_public());
+        assert_eq!(
+            SharedSecret::from(*shared.as_bytes()).derive_subkey(b"test", 32),
+            bob.agree_raw(*ephemeral_pubkey.as_bytes())
+                .derive_subkey(b"test", 32)
+        );
+        assert_eq!(
+            bob.public_key().x25519_public().as_bytes(),
+            bob.public_key().encryption_public_key_array()
+        );
+    }
+
+    #[test]
+    fn derived_key_is_wiped() {
+        use zeroize::Zeroize;
+        let mut key = derive_key(&[0], b"kdf input");
+        assert_ne!(*key, [0; 32]);
+        // Same as on drop, but observable
+        key.zeroize();
+        assert_eq!(*key, [0; 32]);
+    }
+
+    #[test]
+    fn authenticate_associated_data() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let header = b"public header";
+        let tag = alice.authenticate(header, bob.public_key()).unwrap();
+        assert!(bob.verify_authenticate(header, &tag, alice.public_key()));
+        assert!(!bob.verify_authenticate(b"public heaser", &tag, alice.public_key()));
+        let eve = AlphaSecret::new();
+        assert!(!eve.verify_authenticate(header, &tag, alice.public_key()));
     }
 
     #[test]
     fn encrypt_and_decrypt() {
         let isec = AlphaSecret::new();
         let plain = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
-        let crypted = isec.encrypt(&plain, isec.public_key());
-        assert_eq!(isec.decrypt(&crypted, isec.public_key()), plain);
+        let crypted = isec.encrypt(&plain, isec.public_key()).unwrap();
+        assert_eq!(isec.decrypt(&crypted).unwrap(), plain);
+    }
+
+    #[test]
+    fn dedupe_identical_ciphertext() {
+        use std::collections::HashSet;
+        let isec = AlphaSecret::new();
+        let plain = b"stored twice";
+        let crypted = isec.encrypt(&plain, isec.public_key()).unwrap();
+        let again = isec.encrypt(&plain, isec.public_key()).unwrap();
+        assert!(crypted == crypted.clone());
+        assert!(crypted != again);
+        let blobs: HashSet<Encrypted> = vec![crypted.clone(), crypted, again].into_iter().collect();
+        assert_eq!(blobs.len(), 2);
+    }
+
+    #[test]
+    fn route_by_recipient_key_id() {
+        let sender = AlphaSecret::new();
+        let secrets = vec![AlphaSecret::new(), AlphaSecret::new()];
+        let plain = b"for key a";
+        let crypted = sender
+            .encrypt_with_key_id(plain, secrets[0].public_key())
+            .unwrap();
+        let mut raw = Vec::new();
+        crypted.serialize(&mut raw);
+        let parsed = Encrypted::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(parsed, crypted);
+
+        let selected = AlphaSecret::select_for(&secrets, &parsed).unwrap();
+        assert_eq!(
+            selected.public_key().fingerprint(),
+            secrets[0].public_key().fingerprint()
+        );
+        assert!(!parsed.is_addressed_to(&secrets[1].public_key().key_id()));
+        assert_eq!(selected.decrypt(&parsed).unwrap(), &plain[..]);
+        // Untagged data is for anyone
+        let anonymous = sender.encrypt(&plain, secrets[1].public_key()).unwrap();
+        assert!(anonymous.is_addressed_to(&secrets[0].public_key().key_id()));
+    }
+
+    #[test]
+    fn trial_decrypt_anonymous() {
+        let sender = AlphaSecret::new();
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let batch = [
+            sender.encrypt(b"for alice", alice.public_key()).unwrap(),
+            sender.encrypt(b"for bob", bob.public_key()).unwrap(),
+            sender.encrypt(b"also alice", alice.public_key()).unwrap(),
+            sender
+                .encrypt_with_key_id(b"tagged bob", bob.public_key())
+                .unwrap(),
+        ];
+        let screen = |secret: &AlphaSecret| -> Vec<Vec<u8>> {
+            batch
+                .iter()
+                .filter_map(|enc| secret.decrypt_try(enc))
+                .collect()
+        };
+        assert_eq!(
+            screen(&alice),
+            vec![b"for alice".to_vec(), b"also alice".to_vec()]
+        );
+        assert_eq!(
+            screen(&bob),
+            vec![b"for bob".to_vec(), b"tagged bob".to_vec()]
+        );
+        // Nothing in the wire format depends on the recipient
+        let mut wire = Vec::new();
+        batch[0].serialize(&mut wire);
+        let fingerprint = alice.public_key().fingerprint();
+        let key = alice.public_key().to_bytes();
+        for needle in &[&fingerprint.inner[..8], &key[..8], &key[32..40]] {
+            assert!(!wire.windows(8).any(|window| window == *needle));
+        }
+    }
+
+    #[test]
+    fn build_secrets() {
+        let peer = AlphaSecret::new();
+        let plain = b"built";
+
+        let from_seed = AlphaSecret::builder()
+            .with_seed([0x12; 32], [0x21; 32])
+            .build()
+            .unwrap();
+        assert_eq!(
+            from_seed.public_key().fingerprint(),
+            AlphaSecret::from_parts([0x12; 32], [0x21; 32])
+                .public_key()
+                .fingerprint()
+        );
+
+        let mut rng = rand_core::OsRng;
+        let hkdf = AlphaSecret::builder()
+            .with_rng(&mut rng)
+            .with_kdf(Kdf::Hkdf)
+            .build()
+            .unwrap();
+        let crypted = hkdf.encrypt(&plain, peer.public_key()).unwrap();
+        assert_eq!(crypted.kdf, Kdf::Hkdf);
+        let mut raw = Vec::new();
+        crypted.serialize(&mut raw);
+        let parsed = Encrypted::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(peer.decrypt(&parsed).unwrap(), &plain[..]);
+
+        let default = AlphaSecret::builder().build().unwrap();
+        let crypted = default.encrypt(&plain, peer.public_key()).unwrap();
+        assert_eq!(crypted.kdf, Kdf::Pbkdf2);
+
+        let mut rng = rand_core::OsRng;
+        assert!(AlphaSecret::builder()
+            .with_rng(&mut rng)
+            .with_seed([0x12; 32], [0x21; 32])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn generate_from_rand_core_rng() {
+        let mut bytes = vec![0x12; 32];
+        bytes.extend(&[0x21; 32]);
+        let mut rng = testvectors::FixedRng { bytes };
+        let secret = AlphaSecret::from_rng(&mut rng);
+        assert!(rng.bytes.is_empty());
+        assert_eq!(
+            secret.public_key().fingerprint(),
+            AlphaSecret::from_parts([0x12; 32], [0x21; 32])
+                .public_key()
+                .fingerprint()
+        );
+        let other = AlphaSecret::from_rng(&mut rand_core::OsRng);
+        assert_ne!(
+            other.public_key().fingerprint(),
+            secret.public_key().fingerprint()
+        );
+        let crypted = other.encrypt(b"rand_core", secret.public_key()).unwrap();
+        assert_eq!(secret.decrypt(&crypted).unwrap(), b"rand_core");
+    }
+
+    #[test]
+    fn encrypt_size_limit() {
+        let isec = AlphaSecret::new();
+        let plain = vec![0x42; 17];
+        assert!(isec
+            .encrypt_with_limit(&&plain[..16], isec.public_key(), 16)
+            .is_ok());
+        match isec.encrypt_with_limit(&plain, isec.public_key(), 16) {
+            Err(EncryptError::TooLarge(17, 16)) => (),
+            _ => panic!("Expected TooLarge"),
+        }
+        let plain = vec![0; MAX_PLAINTEXT + 1];
+        assert!(isec.encrypt(&plain, isec.public_key()).is_err());
+    }
+
+    #[test]
+    fn encrypt_to_converted_ed25519_key() {
+        let isec = AlphaSecret::from_ed25519_seed([0x17; 32]);
+        let mut ed25519_pubkey = [0; 32];
+        ed25519_pubkey.copy_from_slice(isec.public_key().signing_public_key());
+        let converted = AlphaPublic::from_ed25519_only(&ed25519_pubkey).unwrap();
+        assert_eq!(
+            converted.encryption_public_key(),
+            isec.public_key().encryption_public_key()
+        );
+        let plain = b"only the signing key is published";
+        let crypted = isec.encrypt(&plain, &converted).unwrap();
+        assert_eq!(isec.decrypt(&crypted).unwrap(), &plain[..]);
+        // Not every 32 byte value is a valid point, y = 2 is not
+        let mut invalid = [0; 32];
+        invalid[0] = 2;
+        assert!(AlphaPublic::from_ed25519_only(&invalid).is_err());
+    }
+
+    #[test]
+    fn encrypt_with_fixed_ephemeral() {
+        let sender = AlphaSecret::from_parts([1; 32], [2; 32]);
+        let recipient = AlphaSecret::from_parts([3; 32], [4; 32]);
+        let ephemeral = || {
+            let mut rng = testvectors::FixedRng { bytes: vec![5; 32] };
+            x25519_dalek::EphemeralSecret::new(&mut rng)
+        };
+        let plain = b"fixed ephemeral";
+        let crypted = sender
+            .encrypt_with_ephemeral(plain, recipient.public_key(), ephemeral())
+            .unwrap();
+        let again = sender
+            .encrypt_with_ephemeral(plain, recipient.public_key(), ephemeral())
+            .unwrap();
+        assert_eq!(crypted, again);
+        let hex = |bytes: &[u8]| data_encoding::HEXLOWER.encode(bytes);
+        assert_eq!(
+            hex(&crypted.ephemeral_pubkey),
+            "50a61409b1ddd0325e9b16b700e719e9772c07000b1bd7786e907c653d20495d"
+        );
+        assert_eq!(
+            hex(&crypted.data),
+            "8be9521e66bdac2d040a63de15591427310b87d2c7581f2fc801951dcadc13"
+        );
+        assert_eq!(recipient.decrypt(&crypted).unwrap(), &plain[..]);
+    }
+
+    #[test]
+    fn plaintext_length_bound() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let crypted = alice.encrypt(b"length bound", bob.public_key()).unwrap();
+        assert_eq!(crypted.plain_len, Some(12));
+        let mut truncated = crypted.clone();
+        truncated.data.truncate(crypted.data.len() - 1);
+        assert!(bob.decrypt(&truncated).is_err());
+        // Stripping or changing the length breaks authentication
+        let mut stripped = crypted.clone();
+        stripped.plain_len = None;
+        assert!(bob.decrypt(&stripped).is_err());
+        let mut changed = crypted.clone();
+        changed.plain_len = Some(11);
+        changed.data.truncate(crypted.data.len() - 1);
+        assert!(bob.decrypt(&changed).is_err());
+    }
+
+    #[test]
+    fn ciphertext_bytes_are_sealed() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let plain = b"not in the ciphertext";
+        let crypted = alice.encrypt(plain, bob.public_key()).unwrap();
+        let sealed = crypted.ciphertext_bytes();
+        assert_eq!(sealed, &crypted.data[..]);
+        assert_eq!(sealed, crypted.as_ref());
+        assert_eq!(sealed.len(), plain.len() + 16);
+        assert_ne!(&sealed[..plain.len()], &plain[..]);
+        assert_eq!(bob.decrypt(&crypted).unwrap(), &plain[..]);
+    }
+
+    #[test]
+    fn reject_other_variant() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let mut crypted = alice.encrypt(b"for alpha", bob.public_key()).unwrap();
+        let mut raw = Vec::new();
+        crypted.serialize(&mut raw);
+        // The alpha tag is omitted on the wire
+        crypted.variant_id = 2;
+        let mut tagged = Vec::new();
+        crypted.serialize(&mut tagged);
+        assert_eq!(tagged.len(), raw.len() + 5);
+        let restored = Encrypted::deserialize(&tagged, ParseOptions::strict()).unwrap();
+        assert_eq!(restored.variant_id, 2);
+        match bob.decrypt(&restored) {
+            Err(DecryptError::WrongVariant(2)) => {}
+            _ => panic!("data of another variant decrypted"),
+        }
+        crypted.variant_id = Variant::Alpha.id();
+        assert_eq!(bob.decrypt(&crypted).unwrap(), b"for alpha");
+    }
+
+    #[test]
+    fn decrypt_without_plaintext_length() {
+        // Encrypted before the plaintext length was bound, by the
+        // sender AlphaSecret::from_parts([1; 32], [2; 32])
+        let recipient = AlphaSecret::from_parts([3; 32], [4; 32]);
+        let unhex = |s: &str| data_encoding::HEXLOWER.decode(s.as_bytes()).unwrap();
+        let legacy = Encrypted {
+            ephemeral_pubkey: unhex(
+                "50a61409b1ddd0325e9b16b700e719e9772c07000b1bd7786e907c653d20495d",
+            ),
+            data: unhex("8be9521e66bdac2d040a63de155914c1926df945338ce09e14365b88871d29"),
+            recipient_key_id: None,
+            kdf: Kdf::Pbkdf2,
+            app_id: 0,
+            plain_len: None,
+            variant_id: Variant::Alpha.id(),
+            compression: Compression::None,
+        };
+        assert_eq!(recipient.decrypt(&legacy).unwrap(), b"fixed ephemeral");
+    }
+
+    #[test]
+    fn sign_and_encrypt_envelope() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let plain = b"signed and sealed";
+        let crypted = alice.sign_and_encrypt(plain, bob.public_key()).unwrap();
+        assert_eq!(crypted.data.len(), plain.len() + 64 + 16);
+        let decrypted = bob
+            .decrypt_and_verify(&crypted, alice.public_key())
+            .unwrap();
+        assert_eq!(decrypted, &plain[..]);
+        // Claimed to be from someone else
+        let eve = AlphaSecret::new();
+        match bob.decrypt_and_verify(&crypted, eve.public_key()) {
+            Err(DecryptError::BadSignature) => {}
+            _ => panic!("wrong sender accepted"),
+        }
+    }
+
+    #[test]
+    fn sign_and_encrypt_tampered_signature() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let plain = b"signed and sealed";
+        let crypted = alice.sign_and_encrypt(plain, bob.public_key()).unwrap();
+        // Re-encrypt the content with a flipped signature bit, so the
+        // AEAD layer is intact and only the signature is invalid
+        let mut inner = bob.decrypt(&crypted).unwrap();
+        let last = inner.len() - 1;
+        inner[last] ^= 1;
+        let tampered = alice.encrypt(&inner, bob.public_key()).unwrap();
+        match bob.decrypt_and_verify(&tampered, alice.public_key()) {
+            Err(DecryptError::BadSignature) => {}
+            _ => panic!("tampered signature accepted"),
+        }
+        // Signed for bob, forwarded by bob to carol
+        let carol = AlphaSecret::new();
+        let forwarded = bob
+            .encrypt(&bob.decrypt(&crypted).unwrap(), carol.public_key())
+            .unwrap();
+        assert!(carol
+            .decrypt_and_verify(&forwarded, alice.public_key())
+            .is_err());
+    }
+
+    #[test]
+    fn decrypt_zeroizing() {
+        use zeroize::Zeroize;
+
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let crypted = alice.encrypt(&[0x42; 32], bob.public_key()).unwrap();
+        let mut plain = bob.decrypt_zeroizing(&crypted).unwrap();
+        assert_eq!(&plain[..], &[0x42; 32]);
+        // Best effort: run what `Drop` runs before the memory is freed,
+        // then look at the still allocated buffer
+        let ptr = plain.as_ptr();
+        let capacity = plain.capacity();
+        plain.zeroize();
+        let wiped = unsafe { std::slice::from_raw_parts(ptr, capacity) };
+        assert!(wiped.iter().all(|b| *b == 0));
+        assert!(plain.is_empty());
+    }
+
+    #[test]
+    fn sender_key_only_for_verification() {
+        let alice = AlphaSecret::new();
+        let eve = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        // `decrypt` does not know the sender, data from anyone decrypts
+        let anonymous = eve.encrypt(b"from anyone", bob.public_key()).unwrap();
+        assert_eq!(bob.decrypt(&anonymous).unwrap(), b"from anyone");
+        // `decrypt_and_verify` checks the signature of the given sender
+        let signed = alice
+            .sign_and_encrypt(b"from alice", bob.public_key())
+            .unwrap();
+        let secret: &dyn Secret = &bob;
+        assert_eq!(
+            secret
+                .decrypt_and_verify(&signed, alice.public_key())
+                .unwrap(),
+            b"from alice"
+        );
+        assert!(secret
+            .decrypt_and_verify(&signed, eve.public_key())
+            .is_err());
+        match secret.decrypt_and_verify(&anonymous, eve.public_key()) {
+            Err(DecryptError::BadSignature) => {}
+            _ => panic!("unsigned data accepted"),
+        }
+    }
+
+    #[test]
+    fn decrypt_errors_instead_of_panic() {
+        let isec = AlphaSecret::new();
+        let crypted = isec.encrypt(b"no panic", isec.public_key()).unwrap();
+        let mut tampered = crypted.clone();
+        tampered.data[0] ^= 1;
+        match isec.decrypt(&tampered) {
+            Err(DecryptError::Decryption) => {}
+            _ => panic!("tampered data decrypted"),
+        }
+        let mut short_key = crypted.clone();
+        short_key.ephemeral_pubkey.truncate(16);
+        match isec.decrypt(&short_key) {
+            Err(DecryptError::InvalidEphemeralKey(16)) => {}
+            _ => panic!("short ephemeral key accepted"),
+        }
+        let other = AlphaSecret::new();
+        assert!(other.decrypt(&crypted).is_err());
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_empty() {
+        let isec = AlphaSecret::new();
+        let crypted = isec.encrypt(&[], isec.public_key()).unwrap();
+        // Only the authentication tag
+        assert_eq!(crypted.data.len(), 16);
+        let mut raw = Vec::new();
+        crypted.serialize(&mut raw);
+        let restored = Encrypted::deserialize(&raw, ParseOptions::default()).unwrap();
+        assert_eq!(restored.ephemeral_pubkey, crypted.ephemeral_pubkey);
+        assert_eq!(restored.data, crypted.data);
+        assert!(isec.decrypt(&restored).unwrap().is_empty());
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_compressed() {
+        let isec = AlphaSecret::new();
+        let plain = b"text heavy payload, text heavy payload, text heavy payload".repeat(8);
+        let crypted = isec
+            .encrypt_compressed(&plain, isec.public_key(), Compression::Deflate)
+            .unwrap();
+        assert!(crypted.data.len() < plain.len());
+        let decrypted = isec.decrypt_compressed(&crypted).unwrap();
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_padded() {
+        let isec = AlphaSecret::new();
+        let sizes: Vec<usize> = [0, 1, 31, 32, 33, 64]
+            .iter()
+            .map(|len| {
+                let plain = vec![0x42; *len];
+                let crypted = isec.encrypt_padded(&plain, isec.public_key(), 32).unwrap();
+                assert_eq!(isec.decrypt_padded(&crypted, 32).unwrap(), plain);
+                crypted.data.len()
+            })
+            .collect();
+        // Lengths within one block are indistinguishable
+        assert_eq!(sizes[0], sizes[1]);
+        assert_eq!(sizes[1], sizes[2]);
+        assert_eq!(sizes[3], sizes[4]);
+        assert!(sizes[3] > sizes[2]);
+        assert!(sizes[5] > sizes[4]);
+        assert!(isec
+            .encrypt_padded(b"data", isec.public_key(), MAX_PAD_BLOCK + 1)
+            .is_err());
+        assert!(isec.encrypt_padded(b"data", isec.public_key(), 0).is_err());
+
+        // Authenticated, but not padded correctly
+        let crypted = isec.encrypt(&[0x42; 32], isec.public_key()).unwrap();
+        match isec.decrypt_padded(&crypted, 32) {
+            Err(DecryptError::InvalidPadding) => {}
+            _ => panic!("malformed padding accepted"),
+        }
+        let crypted = isec.encrypt(b"data", isec.public_key()).unwrap();
+        assert!(isec.decrypt_padded(&crypted, 32).is_err());
+    }
+
+    #[test]
+    fn compression_header_selects_decompression() {
+        let isec = AlphaSecret::new();
+        let plain = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
+        for compression in &[Compression::None, Compression::Deflate] {
+            let crypted = isec
+                .encrypt_compressed(&plain, isec.public_key(), *compression)
+                .unwrap();
+            assert_eq!(crypted.compression, *compression);
+            let mut raw = Vec::new();
+            crypted.serialize(&mut raw);
+            let restored = Encrypted::deserialize(&raw, ParseOptions::strict()).unwrap();
+            assert_eq!(restored, crypted);
+            let decrypted = isec.decrypt_compressed(&restored).unwrap();
+            assert_eq!(decrypted, plain);
+        }
+        // Compressed data is not returned by plain decryption
+        let crypted = isec
+            .encrypt_compressed(&plain, isec.public_key(), Compression::Deflate)
+            .unwrap();
+        match isec.decrypt(&crypted) {
+            Err(DecryptError::CompressionMismatch(Compression::Deflate)) => {}
+            _ => panic!("compressed data decrypted as is"),
+        }
+        // The algorithm is authenticated
+        let mut tampered = crypted.clone();
+        tampered.compression = Compression::None;
+        match isec.decrypt_compressed(&tampered) {
+            Err(DecryptError::Decryption) => {}
+            _ => panic!("changed compression accepted"),
+        }
+        let stored = isec.encrypt(&plain, isec.public_key()).unwrap();
+        let mut tampered = stored.clone();
+        tampered.compression = Compression::Deflate;
+        assert!(isec.decrypt_compressed(&tampered).is_err());
+        assert_eq!(isec.decrypt_compressed(&stored).unwrap(), plain);
     }
 
     #[test]
     fn save_and_restore_secret() {
         let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        let restored = AlphaSecret::deserialize(&raw, ParseOptions::default()).unwrap();
+        let data = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
+        assert!(isec.public_key().verify(&data, &restored.sign(&data)));
+        let crypted = isec.encrypt(&data, restored.public_key()).unwrap();
+        assert_eq!(restored.decrypt(&crypted).unwrap(), data);
+    }
+
+    #[test]
+    fn save_and_restore_encrypted_secret() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize_encrypted(b"passphrase", &KdfParams::low(), &mut raw)
+            .unwrap();
+        assert_ne!(KdfParams::low(), KdfParams::default());
+        // The parameters are taken from the header, not the defaults
+        let restored =
+            AlphaSecret::deserialize_encrypted(&raw, b"passphrase", ParseOptions::strict())
+                .unwrap();
+        assert_eq!(
+            restored.public_key().fingerprint(),
+            isec.public_key().fingerprint()
+        );
+        assert!(
+            AlphaSecret::deserialize_encrypted(&raw, b"wrong", ParseOptions::strict()).is_err()
+        );
+    }
+
+    #[test]
+    fn encrypted_secret_uses_random_salt() {
+        let isec = AlphaSecret::new();
+        let encrypt = || {
+            let mut raw = Vec::new();
+            isec.serialize_encrypted(b"passphrase", &KdfParams::low(), &mut raw)
+                .unwrap();
+            raw
+        };
+        let first = encrypt();
+        let second = encrypt();
+        assert_ne!(first, second);
+        for raw in &[first, second] {
+            let restored =
+                AlphaSecret::deserialize_encrypted(raw, b"passphrase", ParseOptions::strict())
+                    .unwrap();
+            assert_eq!(
+                restored.public_key().fingerprint(),
+                isec.public_key().fingerprint()
+            );
+        }
+    }
+
+    #[test]
+    fn import_raw_keys_checked() {
+        let x25519_secret = [0x21; 32];
+        let expected = *AlphaSecret::from_parts([0x12; 32], x25519_secret)
+            .public_key()
+            .encryption_public_key_array();
+        assert!(AlphaSecret::from_raw_checked([0x12; 32], x25519_secret, &expected).is_ok());
+        // Swapped key material
+        match AlphaSecret::from_raw_checked(x25519_secret, [0x12; 32], &expected) {
+            Err(ParseError::PublicKeyMismatch) => {}
+            _ => panic!("mismatching public key accepted"),
+        }
+    }
+
+    #[test]
+    fn import_unclamped_x25519_secret() {
+        let mut clamped = [0xff; 32];
+        clamped[0] = 0xf8;
+        clamped[31] = 0x7f;
+        let unclamped = AlphaSecret::from_parts([0x42; 32], [0xff; 32]);
+        let expected = AlphaSecret::from_parts([0x42; 32], clamped);
+        assert_eq!(
+            unclamped.public_key().encryption_public_key(),
+            expected.public_key().encryption_public_key()
+        );
+        // The clamped scalar is stored, so a restored key is the same
+        let mut raw = Vec::new();
+        unclamped.serialize(&mut raw);
+        let restored = AlphaSecret::deserialize(&raw, ParseOptions::strict()).unwrap();
+        let mut expected_raw = Vec::new();
+        expected.serialize(&mut expected_raw);
+        assert_eq!(raw, expected_raw);
+        assert_eq!(
+            restored.public_key().encryption_public_key(),
+            expected.public_key().encryption_public_key()
+        );
+    }
+
+    #[test]
+    fn derive_from_master_seed() {
+        let master = MasterSeed::generate();
+        let a = master.to_secret();
+        let b = MasterSeed::from_bytes(*master.as_bytes()).to_secret();
+        assert_eq!(a.public_key().fingerprint(), b.public_key().fingerprint());
+        let msg = b"same key";
+        assert!(a.public_key().verify(&msg, &b.sign(&msg)));
+        assert_ne!(
+            a.public_key().signing_public_key(),
+            a.public_key().encryption_public_key()
+        );
+        let other = MasterSeed::from_bytes([0x42; 32]).to_secret();
+        assert_ne!(
+            a.public_key().fingerprint(),
+            other.public_key().fingerprint()
+        );
+    }
+
+    #[test]
+    fn restore_secret_versions() {
+        let v1 = AlphaSecret::new();
+        let raw = v1.serialize_version(1);
+        assert!(AlphaSecret::deserialize_up_to(&raw, ParseOptions::strict(), 1).is_ok());
+        assert!(AlphaSecret::deserialize(&raw, ParseOptions::strict()).is_ok());
+
+        // The KDF choice is only stored since version 2
+        let v2 = AlphaSecret::builder().with_kdf(Kdf::Hkdf).build().unwrap();
+        let raw = v2.serialize_version(2);
+        match AlphaSecret::deserialize_up_to(&raw, ParseOptions::strict(), 1) {
+            Err(ParseError::UnsupportedVersion(2)) => {}
+            _ => panic!("version 1 parser accepted version 2"),
+        }
+        let restored = AlphaSecret::deserialize(&raw, ParseOptions::strict()).unwrap();
+        let peer = AlphaSecret::new();
+        let crypted = restored.encrypt(b"kdf kept", peer.public_key()).unwrap();
+        assert_eq!(crypted.kdf, Kdf::Hkdf);
+
+        // The checksum is only stored since version 3
+        let mut raw = Vec::new();
+        v2.serialize(&mut raw);
+        match AlphaSecret::deserialize_up_to(&raw, ParseOptions::strict(), 2) {
+            Err(ParseError::UnsupportedVersion(3)) => {}
+            _ => panic!("version 2 parser accepted version 3"),
+        }
+    }
+
+    #[test]
+    fn encrypt_for_app() {
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let app = |app_id| EncryptContext::default().with_app_id(app_id);
+        let crypted = alice
+            .encrypt_with_context(b"app data", bob.public_key(), &app(7))
+            .unwrap();
+        let mut raw = Vec::new();
+        crypted.serialize(&mut raw);
+        let restored = Encrypted::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(restored.app_id, 7);
+        assert_eq!(
+            bob.decrypt_with_context(&restored, &app(7)).unwrap(),
+            b"app data"
+        );
+        match bob.decrypt_with_context(&restored, &app(8)) {
+            Err(DecryptError::AppMismatch(7)) => {}
+            _ => panic!("wrong app accepted"),
+        }
+        assert!(bob.decrypt(&restored).is_err());
+        // Rewriting the app id in the header breaks authentication
+        let mut rewritten = restored.clone();
+        rewritten.app_id = 8;
+        match bob.decrypt_with_context(&rewritten, &app(8)) {
+            Err(DecryptError::Decryption) => {}
+            _ => panic!("rewritten app id accepted"),
+        }
+        let plain = alice.encrypt(b"no app", bob.public_key()).unwrap();
+        assert!(bob.decrypt_with_context(&plain, &app(7)).is_err());
+    }
+
+    #[test]
+    fn reencrypt_legacy_to_hkdf() {
+        let secret = AlphaSecret::new();
+        let plain = b"stored before the switch to HKDF";
+        let legacy = secret.encrypt(plain, secret.public_key()).unwrap();
+        assert_eq!(legacy.kdf, Kdf::Pbkdf2);
+
+        let migrated = secret
+            .reencrypt_legacy(&legacy, secret.public_key())
+            .unwrap();
+        assert_eq!(migrated.kdf, Kdf::Hkdf);
+        let mut raw = Vec::new();
+        migrated.serialize(&mut raw);
+        let parsed = Encrypted::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(secret.decrypt(&parsed).unwrap(), &plain[..]);
+
+        // The key id is kept, for the new recipient
+        let peer = AlphaSecret::new();
+        let tagged = secret
+            .encrypt_with_key_id(plain, secret.public_key())
+            .unwrap();
+        let migrated = secret.reencrypt_legacy(&tagged, peer.public_key()).unwrap();
+        assert!(migrated.is_addressed_to(&peer.public_key().key_id()));
+        assert_eq!(peer.decrypt(&migrated).unwrap(), &plain[..]);
+        assert!(peer.reencrypt_legacy(&legacy, peer.public_key()).is_err());
+
+        // Data already using HKDF is still re-encrypted for the peer
+        let again = peer
+            .reencrypt_legacy(&migrated, secret.public_key())
+            .unwrap();
+        assert_eq!(again.kdf, Kdf::Hkdf);
+        assert_eq!(secret.decrypt(&again).unwrap(), &plain[..]);
+        assert!(peer.decrypt(&again).is_err());
+    }
+
+    #[test]
+    fn encrypt_with_hkdf_info() {
+        let alice = AlphaSecret::builder().with_kdf(Kdf::Hkdf).build().unwrap();
+        let bob = AlphaSecret::new();
+        let ctx_a = EncryptContext::new(b"app a");
+        let ctx_b = EncryptContext::new(b"app b");
+        let crypted = alice
+            .encrypt_with_context(b"for app a", bob.public_key(), &ctx_a)
+            .unwrap();
+        assert_eq!(crypted.kdf, Kdf::Hkdf);
+        assert_eq!(
+            bob.decrypt_with_context(&crypted, &ctx_a).unwrap(),
+            b"for app a"
+        );
+        assert!(bob.decrypt_with_context(&crypted, &ctx_b).is_err());
+        assert!(bob.decrypt(&crypted).is_err());
+        // The default context matches plain encryption
+        let plain = alice.encrypt(b"default", bob.public_key()).unwrap();
+        assert_eq!(
+            bob.decrypt_with_context(&plain, &EncryptContext::default())
+                .unwrap(),
+            b"default"
+        );
+        assert!(bob.decrypt_with_context(&plain, &ctx_a).is_err());
+        // A custom info is not silently applied to another KDF
+        let legacy = bob.encrypt(b"legacy", alice.public_key()).unwrap();
+        assert_eq!(legacy.kdf, Kdf::Pbkdf2);
+        match alice.decrypt_with_context(&legacy, &ctx_a) {
+            Err(DecryptError::InfoNeedsHkdf) => {}
+            _ => panic!("custom info accepted for Pbkdf2 data"),
+        }
+        match bob.encrypt_with_context(b"for app a", alice.public_key(), &ctx_a) {
+            Err(EncryptError::InfoNeedsHkdf) => {}
+            _ => panic!("custom info accepted for Pbkdf2"),
+        }
+    }
+
+    #[test]
+    fn fixed_size_signing_key() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        let isec = AlphaSecret::from_parts([1; 32], [2; 32]);
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        assert_eq!(
+            isec.public_key().signing_public_key(),
+            keypair.public_key().as_ref()
+        );
+        // The serialized form still embeds the 32 raw bytes
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        let mut expected = vec![0x04, 0x20];
+        expected.extend(keypair.public_key().as_ref());
+        assert!(raw.windows(34).any(|window| window == &expected[..]));
+        let restored = AlphaSecret::deserialize(&raw, ParseOptions::strict()).unwrap();
+        let mut reserialized = Vec::new();
+        restored.serialize(&mut reserialized);
+        assert_eq!(raw, reserialized);
+    }
+
+    #[test]
+    fn spki_der() {
+        // Key from RFC 8410, SPKI generated with `openssl pkey -pubout -outform DER`
+        let seed = data_encoding::HEXLOWER
+            .decode(b"d4ee72dbf913584ad5b6d8f1f769f8ad3afe7c28cbf1d4fbe097a88f44755842")
+            .unwrap();
+        let openssl_spki = data_encoding::HEXLOWER
+            .decode(
+                b"302a300506032b657003210019bf44096984cdfe8541bac167dc3b96c85086aa30b6b6cb0c5c38ad703166e1",
+            )
+            .unwrap();
+        let isec = AlphaSecret::from_parts(*array_ref(&seed), [2; 32]);
+        let spki = isec.public_key().to_spki_der();
+        assert_eq!(spki, openssl_spki);
+        let public = AlphaPublic::from_spki_der(&spki).unwrap();
+        assert_eq!(
+            public.signing_public_key(),
+            isec.public_key().signing_public_key()
+        );
+        // X25519 OID 1.3.101.110
+        let mut x25519_spki = spki.clone();
+        x25519_spki[8] = 110;
+        assert!(AlphaPublic::from_spki_der(&x25519_spki).is_err());
+        assert!(AlphaPublic::from_spki_der(&spki[..spki.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn publish_public_bytes() {
+        use std::convert::TryFrom;
+        let isec = AlphaSecret::new();
+        let raw = isec.public_bytes();
+        assert_eq!(raw.len(), 64);
+        let public = AlphaPublic::try_from(&raw[..]).unwrap();
+        assert_eq!(public.fingerprint(), isec.public_key().fingerprint());
+        assert!(AlphaPublic::try_from(&raw[..63]).is_err());
+    }
+
+    #[test]
+    fn serialized_magic_bytes() {
+        let magic = yasna::construct_der(|writer| writer.write_i64(alphasecret::MAGIC));
+        assert_eq!(magic, [0x02, 0x06, 0x00, 0xfe, 0x73, 0xba, 0x20, 0x03]);
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        // Long form sequence header, then the magic
+        assert_eq!(&raw[..2], &[0x30, 0x81]);
+        assert_eq!(&raw[3..11], &magic[..]);
+    }
+
+    #[test]
+    fn restore_secret_with_bad_magic() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        raw[10] ^= 0x01;
+        match AlphaSecret::deserialize(&raw, ParseOptions::strict()) {
+            Err(ParseError::BadMagic) => {}
+            _ => panic!("wrong magic accepted"),
+        }
+        let mut raw = Vec::new();
+        isec.serialize_encrypted(b"passphrase", &KdfParams::low(), &mut raw)
+            .unwrap();
+        raw[10] ^= 0x01;
+        match AlphaSecret::deserialize_encrypted(&raw, b"passphrase", ParseOptions::strict()) {
+            Err(ParseError::BadMagic) => {}
+            _ => panic!("wrong magic accepted"),
+        }
+    }
+
+    #[test]
+    fn compare_secrets() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        let restored = AlphaSecret::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert!(isec.matches(&restored));
+        assert!(restored.matches(&isec));
+        assert!(!isec.matches(&AlphaSecret::new()));
+        // Same signing key, different encryption key
+        let mixed = AlphaSecret::from_parts([1; 32], [2; 32]);
+        assert!(!mixed.matches(&AlphaSecret::from_parts([1; 32], [3; 32])));
+    }
+
+    #[test]
+    fn restore_secret_with_trailing_data() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        raw.extend(&[0xde, 0xad, 0xbe, 0xef]);
+        assert!(AlphaSecret::deserialize(&raw, ParseOptions::strict()).is_err());
+        let restored = AlphaSecret::deserialize(&raw, ParseOptions::lenient()).unwrap();
+        assert_eq!(
+            restored.public_key().signing_public_key(),
+            isec.public_key().signing_public_key()
+        );
+    }
+
+    #[test]
+    fn compact_secret_size() {
+        let isec = AlphaSecret::new();
+        let mut full = Vec::new();
+        isec.serialize(&mut full);
+        let mut compact = Vec::new();
+        isec.serialize_compact(false, &mut compact);
+        assert_eq!(compact.len(), alphasecret::COMPACT_LEN);
+        assert!(compact.len() * 2 <= full.len());
+        let mut checked = Vec::new();
+        isec.serialize_compact(true, &mut checked);
+        assert_eq!(
+            checked.len(),
+            alphasecret::COMPACT_LEN + alphasecret::COMPACT_CHECK_LEN
+        );
+    }
+
+    #[test]
+    fn restore_compact_secret() {
+        let isec = AlphaSecret::new();
+        for with_check in &[false, true] {
+            let mut raw = Vec::new();
+            isec.serialize_compact(*with_check, &mut raw);
+            let restored = AlphaSecret::deserialize_compact(&raw).unwrap();
+            assert!(restored.matches(&isec));
+            let crypted = isec.encrypt(b"compact", restored.public_key()).unwrap();
+            assert_eq!(restored.decrypt(&crypted).unwrap(), b"compact");
+        }
+        // A corrupted secret derives other public keys than the check value
+        let mut raw = Vec::new();
+        isec.serialize_compact(true, &mut raw);
+        raw[0] ^= 0x01;
+        match AlphaSecret::deserialize_compact(&raw) {
+            Err(ParseError::PublicKeyMismatch) => {}
+            _ => panic!("corrupted compact secret accepted"),
+        }
+        assert!(AlphaSecret::deserialize_compact(&raw[..63]).is_err());
+    }
+
+    #[test]
+    fn restore_corrupted_secret() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        // Flip a bit in the ED25519 seed following the sequence header,
+        // magic, kind and version, the DER structure stays intact
+        let seed = 3 + 8 + 3 + 3;
+        assert_eq!(&raw[seed..seed + 2], &[0x04, 0x20]);
+        raw[seed + 10] ^= 0x01;
+        match AlphaSecret::deserialize(&raw, ParseOptions::strict()) {
+            Err(ParseError::ChecksumMismatch) => {}
+            _ => panic!("corrupted secret accepted"),
+        }
+        // Corrupted checksum
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        let last = raw.len() - 1;
+        raw[last] ^= 0x01;
+        match AlphaSecret::deserialize(&raw, ParseOptions::strict()) {
+            Err(ParseError::ChecksumMismatch) => {}
+            _ => panic!("corrupted checksum accepted"),
+        }
+    }
+
+    #[test]
+    fn restore_secret_with_corrupted_public_key() {
+        let isec = AlphaSecret::new();
+        let public = isec.derive_public_key();
+        assert_eq!(public.to_bytes(), isec.public_key().to_bytes());
+        // Without the checksum of version 3, only the comparison with the
+        // derived public keys catches the corruption
+        let stored_keys = [public.signing_public_key(), public.encryption_public_key()];
+        for version in 1..=2 {
+            for stored in &stored_keys {
+                let mut raw = isec.serialize_version(version);
+                let pos = raw
+                    .windows(stored.len())
+                    .position(|window| window == *stored)
+                    .unwrap();
+                raw[pos + 5] ^= 0x01;
+                match AlphaSecret::deserialize(&raw, ParseOptions::strict()) {
+                    Err(ParseError::PublicKeyMismatch) => {}
+                    _ => panic!("corrupted public key accepted"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn restore_secret_with_non_canonical_length() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        // Re-encode the length of the key kind field in long form
+        let pos = raw
+            .windows(6)
+            .position(|w| w == [0x02, 0x01, 0x01, 0x02, 0x01, 0x03])
+            .unwrap();
+        raw.splice(pos + 1..pos + 2, vec![0x81, 0x01]);
+        assert_eq!(raw[1], 0x81);
+        raw[2] += 1;
+        assert!(AlphaSecret::deserialize(&raw, ParseOptions::strict()).is_err());
+        let options = ParseOptions {
+            allow_trailing: false,
+            strict_der: false,
+        };
+        assert!(AlphaSecret::deserialize(&raw, options).is_ok());
+    }
+
+    #[test]
+    fn serialized_public_keys_match_secrets() {
+        for _ in 0..32 {
+            let isec = AlphaSecret::new();
+            let mut raw = Vec::new();
+            isec.serialize(&mut raw);
+            assert!(AlphaSecret::check_serialized(&raw).is_ok());
+        }
+    }
+
+    #[test]
+    fn serialized_public_keys_swapped() {
+        let isec = AlphaSecret::new();
+        let mut raw = Vec::new();
+        isec.serialize(&mut raw);
+        // Swap the ed25519 and x25519 public keys
+        let ed = isec.public_key().signing_public_key();
+        let x = isec.public_key().encryption_public_key();
+        let ed_pos = raw.windows(32).position(|w| w == ed).unwrap();
+        let x_pos = raw.windows(32).position(|w| w == x).unwrap();
+        let (ed, x) = (Vec::from(ed), Vec::from(x));
+        raw[ed_pos..ed_pos + 32].copy_from_slice(&x);
+        raw[x_pos..x_pos + 32].copy_from_slice(&ed);
+        assert!(AlphaSecret::check_serialized(&raw).is_err());
     }
 
     #[test]
@@ -45,12 +1428,32 @@ mod tests {
         let isec = AlphaSecret::new();
         let icert = AlphaCert::new(&isec, &isec, None);
 
-        assert_eq!(icert.is_valid(&icert), true);
+        assert!(icert.is_valid(&icert));
 
         let dsec = AlphaSecret::new();
         let dcert = AlphaCert::new(&dsec, &isec, Some(&icert));
 
-        assert_eq!(dcert.is_valid(&icert), true);
+        assert!(dcert.is_valid(&icert));
+    }
+
+    fn encrypt_to_cert(secret: &dyn Secret, cert: &impl Cert, plain: &[u8]) -> Encrypted {
+        secret.encrypt(&plain, cert.public_key()).unwrap()
+    }
+
+    #[test]
+    fn encrypt_to_cert_public_key() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let dsec = AlphaSecret::new();
+        let plain = vec![0x34, 0x84, 0x23, 0x98, 0xA2];
+        let crypted = encrypt_to_cert(&dsec, &icert, &plain);
+        assert_eq!(isec.decrypt(&crypted).unwrap(), plain);
+
+        let trusted = icert.clone().into_ident_cert().into_trusted();
+        assert_eq!(
+            trusted.public_key().signing_public_key(),
+            isec.public_key().signing_public_key()
+        );
     }
 
     #[test]
@@ -66,5 +1469,262 @@ mod tests {
 
         let dcert = dcert.into_device_cert();
         let dcert_trusted = dcert.into_trusted(trusted.deref());
+        assert!(dcert_trusted.is_valid(trusted.deref()));
+    }
+
+    /// Crafts a ChaCha20-Poly1305 ciphertext which opens under two keys,
+    /// to show why the key commitment is needed.
+    mod key_commitment {
+        use super::*;
+        use std::convert::TryInto;
+
+        const MASK: u64 = (1 << 26) - 1;
+
+        /// Element of GF(2^130 - 5) in 26 bit limbs
+        #[derive(Clone, Copy)]
+        struct Fe([u64; 5]);
+
+        impl Fe {
+            fn from_u128(low: u128, high: u64) -> Self {
+                Fe([
+                    low as u64 & MASK,
+                    (low >> 26) as u64 & MASK,
+                    (low >> 52) as u64 & MASK,
+                    (low >> 78) as u64 & MASK,
+                    (low >> 104) as u64 | (high << 24),
+                ])
+            }
+
+            /// A full 16 byte Poly1305 block
+            fn block(bytes: &[u8]) -> Self {
+                Fe::from_u128(u128::from_le_bytes(bytes.try_into().unwrap()), 1)
+            }
+
+            fn carry(mut d: [u64; 5]) -> Self {
+                for _ in 0..2 {
+                    for i in 0..4 {
+                        d[i + 1] += d[i] >> 26;
+                        d[i] &= MASK;
+                    }
+                    d[0] += (d[4] >> 26) * 5;
+                    d[4] &= MASK;
+                }
+                // Final reduction
+                let mut g = d;
+                g[0] += 5;
+                for i in 0..4 {
+                    g[i + 1] += g[i] >> 26;
+                    g[i] &= MASK;
+                }
+                if g[4] >> 26 != 0 {
+                    g[4] &= MASK;
+                    Fe(g)
+                } else {
+                    Fe(d)
+                }
+            }
+
+            fn add(self, other: Fe) -> Fe {
+                let mut d = self.0;
+                for (d, o) in d.iter_mut().zip(other.0.iter()) {
+                    *d += o;
+                }
+                Fe::carry(d)
+            }
+
+            fn mul(self, other: Fe) -> Fe {
+                let (a, b) = (self.0, other.0);
+                let mut d = [0; 5];
+                for i in 0..5 {
+                    for j in 0..5 {
+                        let factor = if i + j >= 5 { 5 } else { 1 };
+                        d[(i + j) % 5] += a[i] * b[j] * factor;
+                    }
+                }
+                Fe::carry(d)
+            }
+
+            fn neg(self) -> Fe {
+                // p - 1
+                self.mul(Fe([MASK - 5, MASK, MASK, MASK, MASK]))
+            }
+
+            fn inv(self) -> Fe {
+                // a^(p - 2), p - 2 = 2^130 - 7
+                let mut r = Fe([1, 0, 0, 0, 0]);
+                for bit in (0..130).rev() {
+                    r = r.mul(r);
+                    if bit >= 3 || bit == 0 {
+                        r = r.mul(self);
+                    }
+                }
+                r
+            }
+
+            /// Returns the low 128 bits and the bits above
+            fn to_u128(self) -> (u128, u64) {
+                let l = self.0;
+                let low = l[0] as u128
+                    | (l[1] as u128) << 26
+                    | (l[2] as u128) << 52
+                    | (l[3] as u128) << 78
+                    | ((l[4] & 0xff_ffff) as u128) << 104;
+                (low, l[4] >> 24)
+            }
+        }
+
+        fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+            x[a] = x[a].wrapping_add(x[b]);
+            x[d] = (x[d] ^ x[a]).rotate_left(16);
+            x[c] = x[c].wrapping_add(x[d]);
+            x[b] = (x[b] ^ x[c]).rotate_left(12);
+            x[a] = x[a].wrapping_add(x[b]);
+            x[d] = (x[d] ^ x[a]).rotate_left(8);
+            x[c] = x[c].wrapping_add(x[d]);
+            x[b] = (x[b] ^ x[c]).rotate_left(7);
+        }
+
+        /// ChaCha20 block 0, the Poly1305 key (RFC 8439)
+        fn poly_key(key: &[u8], nonce: &[u8; 12]) -> (Fe, u128) {
+            let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+            let mut state = [0; 16];
+            state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+            for i in 0..8 {
+                state[4 + i] = word(&key[4 * i..4 * i + 4]);
+            }
+            for i in 0..3 {
+                state[13 + i] = word(&nonce[4 * i..4 * i + 4]);
+            }
+            let mut x = state;
+            for _ in 0..10 {
+                quarter_round(&mut x, 0, 4, 8, 12);
+                quarter_round(&mut x, 1, 5, 9, 13);
+                quarter_round(&mut x, 2, 6, 10, 14);
+                quarter_round(&mut x, 3, 7, 11, 15);
+                quarter_round(&mut x, 0, 5, 10, 15);
+                quarter_round(&mut x, 1, 6, 11, 12);
+                quarter_round(&mut x, 2, 7, 8, 13);
+                quarter_round(&mut x, 3, 4, 9, 14);
+            }
+            let mut block = Vec::new();
+            for i in 0..8 {
+                block.extend(&x[i].wrapping_add(state[i]).to_le_bytes());
+            }
+            let r = u128::from_le_bytes(block[..16].try_into().unwrap())
+                & 0x0fff_fffc_0fff_fffc_0fff_fffc_0fff_ffff;
+            let s = u128::from_le_bytes(block[16..].try_into().unwrap());
+            (Fe::from_u128(r, 0), s)
+        }
+
+        /// Ciphertext of two blocks plus tag, valid under both keys
+        fn craft(key_a: &[u8], key_b: &[u8], aad: &[u8; 16], lengths: &[u8; 16]) -> Vec<u8> {
+            let nonce = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+            let (r_a, s_a) = poly_key(key_a, &nonce);
+            let (r_b, s_b) = poly_key(key_b, &nonce);
+            let d_low = s_b.wrapping_sub(s_a);
+            for first in 0u128.. {
+                let c0 = first.to_le_bytes();
+                // Everything but the free second block, which is multiplied by r^2
+                let fixed = |r: Fe| {
+                    let r2 = r.mul(r);
+                    let r3 = r2.mul(r);
+                    Fe::block(aad)
+                        .mul(r3.mul(r))
+                        .add(Fe::block(&c0).mul(r3))
+                        .add(Fe::from_u128(0, 1).mul(r2))
+                        .add(Fe::block(lengths).mul(r))
+                };
+                let (h_a, h_b) = (fixed(r_a), fixed(r_b));
+                let denominator = r_a.mul(r_a).add(r_b.mul(r_b).neg()).inv();
+                // h_a - h_b must be s_b - s_a, either without or with a wrap
+                for d in &[
+                    Fe::from_u128(d_low, 0),
+                    Fe::from_u128(d_low, 0).add(Fe::from_u128(0, 4).neg()),
+                ] {
+                    let x = d.add(h_a.neg()).add(h_b).mul(denominator);
+                    let (x_low, x_high) = x.to_u128();
+                    if x_high != 0 {
+                        continue;
+                    }
+                    let tag = |h: Fe, r: Fe, s: u128| {
+                        let x_block = Fe::from_u128(x_low, 0).mul(r.mul(r));
+                        h.add(x_block).to_u128().0.wrapping_add(s)
+                    };
+                    let tag_a = tag(h_a, r_a, s_a);
+                    if tag_a != tag(h_b, r_b, s_b) {
+                        continue;
+                    }
+                    let mut data = Vec::from(&c0[..]);
+                    data.extend(&x_low.to_le_bytes());
+                    data.extend(&tag_a.to_le_bytes());
+                    return data;
+                }
+            }
+            unreachable!()
+        }
+
+        #[test]
+        fn reject_ciphertext_for_two_keys() {
+            let bob = AlphaSecret::new();
+            let carol = AlphaSecret::new();
+            let ephemeral = x25519_dalek::StaticSecret::from([9; 32]);
+            let ephemeral_pub = x25519_dalek::PublicKey::from(&ephemeral);
+            let key = |recipient: &AlphaSecret| {
+                let recipient_pub = recipient.public_key().encryption_public_key_array();
+                let shared =
+                    ephemeral.diffie_hellman(&x25519_dalek::PublicKey::from(*recipient_pub));
+                let mut kdf_input = Vec::from(&shared.as_bytes()[..]);
+                kdf_input.extend(ephemeral_pub.as_bytes());
+                kdf_input.extend(recipient_pub);
+                derive_key(&[0], &kdf_input)
+            };
+            let (key_bob, key_carol) = (key(&bob), key(&carol));
+            // Padded additional data with the plaintext length, then the
+            // lengths of the additional data and of the ciphertext
+            let mut aad = [0; 16];
+            aad[0] = b'L';
+            aad[1..9].copy_from_slice(&32u64.to_be_bytes());
+            let mut lengths = [0; 16];
+            lengths[..8].copy_from_slice(&9u64.to_le_bytes());
+            lengths[8..].copy_from_slice(&32u64.to_le_bytes());
+            let data = craft(&key_bob[..], &key_carol[..], &aad, &lengths);
+
+            let mut crafted = Encrypted {
+                ephemeral_pubkey: Vec::from(&ephemeral_pub.as_bytes()[..]),
+                data,
+                recipient_key_id: None,
+                kdf: Kdf::Pbkdf2,
+                app_id: 0,
+                plain_len: Some(32),
+                variant_id: Variant::Alpha.id(),
+                compression: Compression::None,
+            };
+            let for_bob = bob.decrypt(&crafted).unwrap();
+            let for_carol = carol.decrypt(&crafted).unwrap();
+            assert_ne!(for_bob, for_carol);
+
+            let commitment = ring::hmac::sign(
+                &ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key_bob[..]),
+                alphasecret::KEY_COMMITMENT_LABEL,
+            );
+            crafted.data.extend(commitment.as_ref());
+            assert_eq!(bob.decrypt_committing(&crafted).unwrap(), for_bob);
+            match carol.decrypt_committing(&crafted) {
+                Err(DecryptError::KeyCommitment) => {}
+                _ => panic!("ciphertext accepted under a second key"),
+            }
+        }
+
+        #[test]
+        fn committing_round_trip() {
+            let alice = AlphaSecret::new();
+            let bob = AlphaSecret::new();
+            let crypted = alice
+                .encrypt_committing(b"committed", bob.public_key())
+                .unwrap();
+            assert_eq!(crypted.data.len(), 9 + 16 + 32);
+            assert_eq!(bob.decrypt_committing(&crypted).unwrap(), b"committed");
+            assert!(bob.decrypt(&crypted).is_err());
+        }
     }
 }
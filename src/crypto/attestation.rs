@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::io::Write;
+
+use failure::Fail;
+
+use crate::crypto::parse::parse_with_options;
+use crate::crypto::{ParseError, ParseOptions, Public, SignatureBytes};
+
+/// Domain separation for attestation signatures
+const ATTESTATION_LABEL: &[u8] = b"stackment attestation";
+
+/// Error type for attestation verification
+#[derive(Debug, Fail)]
+pub enum AttestationError {
+    #[fail(display = "Signature does not match")]
+    BadSignature,
+    #[fail(display = "Invalid claims: {}", _0)]
+    InvalidClaims(ParseError),
+    #[fail(display = "Claim {} is given more than once", _0)]
+    DuplicateClaim(String),
+}
+
+/// Signed set of key-value claims, see `Secret::attest`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    /// Canonical DER encoding of the claims
+    pub claims: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Attestation {
+    /// Serialize the attestation as ASN.1 to `stream`
+    pub fn serialize(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_der(&self.claims);
+                writer.next().write_bytes(&self.signature);
+            });
+        });
+        stream.write_all(&raw_bytes)
+    }
+
+    /// Restore an attestation written by `serialize`.
+    /// The claims are only checked by `verify_attestation`.
+    pub fn deserialize(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        let (claims, signature) = parse_with_options(bytes, options, |reader| {
+            reader.read_sequence(|reader| {
+                let claims = reader.next().read_der()?;
+                let signature = reader.next().read_bytes()?;
+                Ok((claims, signature))
+            })
+        })?;
+        Ok(Self { claims, signature })
+    }
+}
+
+/// Returns the first key which is used by more than one claim
+fn find_duplicate<'a>(mut keys: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut seen = BTreeSet::new();
+    keys.find(|key| !seen.insert(*key))
+}
+
+/// Encode `claims` sorted by key, so the same set of claims always gives
+/// the same bytes. Each key may only be used once.
+pub(crate) fn encode_claims(claims: &[(&str, &[u8])]) -> Result<Vec<u8>, AttestationError> {
+    if let Some(key) = find_duplicate(claims.iter().map(|(key, _)| *key)) {
+        return Err(AttestationError::DuplicateClaim(String::from(key)));
+    }
+    let mut sorted = Vec::from(claims);
+    sorted.sort();
+    Ok(yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            for (key, value) in &sorted {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_utf8_string(key);
+                    writer.next().write_bytes(value);
+                });
+            }
+        });
+    }))
+}
+
+/// Bytes covered by the signature of an attestation
+pub(crate) fn signed_claims(claims: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::from(ATTESTATION_LABEL);
+    signed.extend(claims);
+    signed
+}
+
+fn decode_claims(claims: &[u8]) -> Result<Vec<(String, Vec<u8>)>, ParseError> {
+    parse_with_options(claims, ParseOptions::strict(), |reader| {
+        reader.collect_sequence_of(|reader| {
+            reader.read_sequence(|reader| {
+                let key = reader.next().read_utf8string()?;
+                let value = reader.next().read_bytes()?;
+                Ok((key, value))
+            })
+        })
+    })
+}
+
+/// Verify `attestation` against `public` and return its claims in
+/// canonical order. Claims using a key more than once are rejected, even
+/// if they are signed.
+pub fn verify_attestation(
+    public: &dyn Public,
+    attestation: &Attestation,
+) -> Result<Vec<(String, Vec<u8>)>, AttestationError> {
+    let signature = SignatureBytes::try_from(&attestation.signature[..])
+        .map_err(|_| AttestationError::BadSignature)?;
+    if !public.verify(&signed_claims(&attestation.claims), &signature) {
+        return Err(AttestationError::BadSignature);
+    }
+    let claims = decode_claims(&attestation.claims).map_err(AttestationError::InvalidClaims)?;
+    if let Some(key) = find_duplicate(claims.iter().map(|(key, _)| key.as_str())) {
+        return Err(AttestationError::DuplicateClaim(key.to_string()));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+    use crate::crypto::Secret;
+
+    #[test]
+    fn verify_claims() {
+        let device = AlphaSecret::new();
+        let attestation = device
+            .attest(&[("model", b"x1"), ("firmware", b"1.2.3")])
+            .unwrap();
+        let claims = verify_attestation(device.public_key(), &attestation).unwrap();
+        assert_eq!(
+            claims,
+            vec![
+                (String::from("firmware"), b"1.2.3".to_vec()),
+                (String::from("model"), b"x1".to_vec()),
+            ]
+        );
+
+        let mut raw = Vec::new();
+        attestation.serialize(&mut raw).unwrap();
+        let restored = Attestation::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(restored, attestation);
+
+        let other = AlphaSecret::new();
+        assert!(verify_attestation(other.public_key(), &attestation).is_err());
+    }
+
+    #[test]
+    fn tampered_claims() {
+        let device = AlphaSecret::new();
+        let attestation = device.attest(&[("model", b"x1")]).unwrap();
+        let mut tampered = attestation.clone();
+        let last = tampered.claims.len() - 1;
+        tampered.claims[last] ^= 0x01;
+        match verify_attestation(device.public_key(), &tampered) {
+            Err(AttestationError::BadSignature) => {}
+            _ => panic!("tampered claims accepted"),
+        }
+        // Claims signed without the attestation label
+        let plain_signature = device.sign(&attestation.claims);
+        let forged = Attestation {
+            claims: attestation.claims.clone(),
+            signature: plain_signature.as_bytes().to_vec(),
+        };
+        assert!(verify_attestation(device.public_key(), &forged).is_err());
+    }
+
+    #[test]
+    fn canonical_order() {
+        let device = AlphaSecret::new();
+        let a = device
+            .attest(&[("a", b"1"), ("b", b"2"), ("c", b"3")])
+            .unwrap();
+        let b = device
+            .attest(&[("c", b"3"), ("a", b"1"), ("b", b"2")])
+            .unwrap();
+        assert_eq!(a, b);
+        let c = device
+            .attest(&[("a", b"1"), ("b", b"3"), ("c", b"2")])
+            .unwrap();
+        assert_ne!(a.claims, c.claims);
+    }
+
+    #[test]
+    fn reject_duplicate_claims() {
+        let device = AlphaSecret::new();
+        match device.attest(&[("role", b"user"), ("model", b"x1"), ("role", b"admin")]) {
+            Err(AttestationError::DuplicateClaim(key)) => assert_eq!(key, "role"),
+            _ => panic!("duplicate claim attested"),
+        }
+
+        // Signed directly, bypassing the check in `attest`
+        let claims = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                for value in &[&b"admin"[..], b"user"] {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_utf8_string("role");
+                        writer.next().write_bytes(value);
+                    });
+                }
+            });
+        });
+        let signature = device.sign(&signed_claims(&claims));
+        let forged = Attestation {
+            claims,
+            signature: signature.as_bytes().to_vec(),
+        };
+        match verify_attestation(device.public_key(), &forged) {
+            Err(AttestationError::DuplicateClaim(key)) => assert_eq!(key, "role"),
+            _ => panic!("duplicate claim verified"),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Batch signing with a Merkle tree.
+//! All messages of a batch are hashed into a tree and only the root is
+//! signed. Each message gets an inclusion proof with the sibling hashes
+//! from its leaf up to the root, so verifying one message needs one
+//! signature check and about log2(n) hashes.
+//!
+//! Leaves are `SHA256(0x00 || msg)`, inner nodes `SHA256(0x01 || l || r)`.
+//! A node without a sibling is moved up to the next level unchanged.
+
+use ring::digest;
+
+use crate::crypto::{Error, Public, Result, Secret, SignatureBytes};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+const ROOT_LABEL: &[u8] = b"stackment merkle root";
+
+type Hash = [u8; 32];
+
+/// Signed root of a batch
+pub struct MerkleRoot {
+    pub hash: [u8; 32],
+    pub signature: SignatureBytes,
+}
+
+/// Proof that one message is part of a signed batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    /// Position of the message in the batch
+    pub index: u64,
+    /// Number of messages in the batch
+    pub leaves: u64,
+    /// Sibling hashes from the leaf up to the root
+    pub path: Vec<[u8; 32]>,
+}
+
+fn hash(prefix: u8, parts: &[&[u8]]) -> Hash {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[prefix]);
+    for part in parts {
+        ctx.update(part);
+    }
+    let mut out = [0; 32];
+    out.copy_from_slice(ctx.finish().as_ref());
+    out
+}
+
+fn root_message(root: &Hash) -> Vec<u8> {
+    let mut msg = Vec::from(ROOT_LABEL);
+    msg.extend(root);
+    msg
+}
+
+/// Sign all `msgs` with a single signature over their Merkle root.
+/// Returns the signed root and one inclusion proof per message, in the
+/// order of `msgs`. Fails for an empty batch.
+pub fn sign_batch(
+    secret: &dyn Secret,
+    msgs: &[&[u8]],
+) -> Result<(MerkleRoot, Vec<InclusionProof>)> {
+    if msgs.is_empty() {
+        return Err(Error::NotValid(String::from("Empty batch")));
+    }
+    let leaves = msgs.len() as u64;
+    let mut proofs: Vec<InclusionProof> = (0..leaves)
+        .map(|index| InclusionProof {
+            index,
+            leaves,
+            path: Vec::new(),
+        })
+        .collect();
+    let mut level: Vec<Hash> = msgs.iter().map(|msg| hash(LEAF_PREFIX, &[msg])).collect();
+    // Position of each message's ancestor in the current level
+    let mut positions: Vec<usize> = (0..msgs.len()).collect();
+    while level.len() > 1 {
+        for (proof, pos) in proofs.iter_mut().zip(positions.iter_mut()) {
+            if let Some(sibling) = level.get(*pos ^ 1) {
+                proof.path.push(*sibling);
+            }
+            *pos /= 2;
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash(NODE_PREFIX, &[left, right]),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    let root = level[0];
+    let signature = secret.sign(&root_message(&root));
+    Ok((
+        MerkleRoot {
+            hash: root,
+            signature,
+        },
+        proofs,
+    ))
+}
+
+/// Verify that `msg` is part of the batch signed by `public`.
+/// The signature over the root is checked first, then the path of `proof`.
+pub fn verify_batch_member(
+    public: &dyn Public,
+    root: &MerkleRoot,
+    proof: &InclusionProof,
+    msg: &[u8],
+) -> bool {
+    if !public.verify(&root_message(&root.hash), &root.signature) {
+        return false;
+    }
+    if proof.index >= proof.leaves {
+        return false;
+    }
+    let mut node = hash(LEAF_PREFIX, &[msg]);
+    let mut index = proof.index;
+    let mut width = proof.leaves;
+    let mut path = proof.path.iter();
+    while width > 1 {
+        let sibling = index ^ 1;
+        if sibling < width {
+            let sibling_hash = match path.next() {
+                Some(hash) => hash,
+                None => return false,
+            };
+            node = if index & 1 == 0 {
+                hash(NODE_PREFIX, &[&node, sibling_hash])
+            } else {
+                hash(NODE_PREFIX, &[sibling_hash, &node])
+            };
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    path.next().is_none() && node == root.hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+
+    fn messages(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| format!("log entry {}", i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn verify_members() {
+        let secret = AlphaSecret::new();
+        for count in &[1, 2, 5, 8] {
+            let msgs = messages(*count);
+            let refs: Vec<&[u8]> = msgs.iter().map(|m| m.as_slice()).collect();
+            let (root, proofs) = sign_batch(&secret, &refs).unwrap();
+            assert_eq!(proofs.len(), *count);
+            for (proof, msg) in proofs.iter().zip(&refs) {
+                assert!(verify_batch_member(secret.public_key(), &root, proof, msg));
+            }
+        }
+        assert!(sign_batch(&secret, &[]).is_err());
+    }
+
+    #[test]
+    fn reject_forged_proof() {
+        let secret = AlphaSecret::new();
+        let msgs = messages(5);
+        let refs: Vec<&[u8]> = msgs.iter().map(|m| m.as_slice()).collect();
+        let (root, proofs) = sign_batch(&secret, &refs).unwrap();
+        // Message not in the batch
+        assert!(!verify_batch_member(
+            secret.public_key(),
+            &root,
+            &proofs[0],
+            b"forged entry"
+        ));
+        // Proof of another message
+        assert!(!verify_batch_member(
+            secret.public_key(),
+            &root,
+            &proofs[1],
+            refs[0]
+        ));
+        // Tampered path
+        let mut forged = proofs[2].clone();
+        forged.path[0][0] ^= 1;
+        assert!(!verify_batch_member(
+            secret.public_key(),
+            &root,
+            &forged,
+            refs[2]
+        ));
+        // Root signed by another key
+        let other = AlphaSecret::new();
+        assert!(!verify_batch_member(
+            other.public_key(),
+            &root,
+            &proofs[2],
+            refs[2]
+        ));
+    }
+}
@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::crypto::{Cert, DeviceCert, Fingerprint, Trusted, Untrusted};
+
+struct CacheEntry {
+    cert: DeviceCert<Trusted>,
+    issuer: Fingerprint,
+    last_used: u64,
+}
+
+/// Cache for validated device certificates, keyed by the certificate
+/// fingerprint. Repeated validations of the same certificate are answered
+/// from the cache without checking the signature again.
+/// If the cache is full, the least recently used entry is evicted.
+pub struct ValidationCache {
+    capacity: usize,
+    entries: HashMap<Fingerprint, CacheEntry>,
+    tick: u64,
+}
+
+impl ValidationCache {
+    /// Constructs an empty cache holding up to `capacity` certificates
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Number of cached certificates
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no certificate is cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Validate `cert` against `issuer_cert` at time `now`.
+    /// Returns the trusted certificate, or None if it is not valid.
+    /// The validity window is checked like `Cert::is_valid_at`, cached
+    /// entries are dropped once `now` is outside of it.
+    pub fn validate(
+        &mut self,
+        cert: DeviceCert<Untrusted>,
+        issuer_cert: &dyn Cert,
+        now: DateTime<Utc>,
+    ) -> Option<&DeviceCert<Trusted>> {
+        self.tick += 1;
+        let fingerprint = cert.fingerprint();
+        let hit = match self.entries.get(&fingerprint) {
+            Some(entry) if !entry.cert.is_valid_at(now) => {
+                self.entries.remove(&fingerprint);
+                false
+            }
+            Some(entry) => entry.issuer == issuer_cert.fingerprint(),
+            None => false,
+        };
+        if !hit {
+            if !cert.is_valid_at(now) {
+                return None;
+            }
+            let trusted = cert.try_into_trusted(issuer_cert).ok()?;
+            if self.entries.len() >= self.capacity && !self.entries.contains_key(&fingerprint) {
+                self.evict();
+            }
+            self.entries.insert(
+                fingerprint,
+                CacheEntry {
+                    issuer: issuer_cert.fingerprint(),
+                    cert: trusted,
+                    last_used: 0,
+                },
+            );
+        }
+        let entry = self.entries.get_mut(&fingerprint)?;
+        entry.last_used = self.tick;
+        Some(&entry.cert)
+    }
+
+    /// Remove the least recently used entry
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(fingerprint, _)| *fingerprint);
+        if let Some(fingerprint) = oldest {
+            self.entries.remove(&fingerprint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::{AlphaCert, AlphaSecret};
+    use crate::crypto::Public;
+    use chrono::Duration;
+    use std::cell::Cell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    /// Wraps a cert, counts validations and allows setting the validity
+    struct CountingCert {
+        inner: AlphaCert,
+        valid_from: Option<DateTime<Utc>>,
+        expires: Option<DateTime<Utc>>,
+        validations: Rc<Cell<usize>>,
+    }
+
+    impl AsRef<[u8]> for CountingCert {
+        fn as_ref(&self) -> &[u8] {
+            self.inner.as_ref()
+        }
+    }
+
+    impl Cert for CountingCert {
+        fn serialize(&self, stream: &mut dyn Write) {
+            self.inner.serialize(stream)
+        }
+        fn issuer_fingerprint(&self) -> Fingerprint {
+            self.inner.issuer_fingerprint()
+        }
+        fn signing_public_key(&self) -> &[u8] {
+            self.inner.signing_public_key()
+        }
+        fn public_key(&self) -> &dyn Public {
+            self.inner.public_key()
+        }
+        fn expires(&self) -> Option<DateTime<Utc>> {
+            self.expires
+        }
+        fn valid_from(&self) -> Option<DateTime<Utc>> {
+            self.valid_from
+        }
+        fn is_valid(&self, issuer_cert: &dyn Cert) -> bool {
+            self.validations.set(self.validations.get() + 1);
+            self.inner.is_valid(issuer_cert)
+        }
+    }
+
+    fn device_cert(
+        dcert: &AlphaCert,
+        expires: Option<DateTime<Utc>>,
+        validations: &Rc<Cell<usize>>,
+    ) -> DeviceCert<Untrusted> {
+        device_cert_from(dcert, None, expires, validations)
+    }
+
+    fn device_cert_from(
+        dcert: &AlphaCert,
+        valid_from: Option<DateTime<Utc>>,
+        expires: Option<DateTime<Utc>>,
+        validations: &Rc<Cell<usize>>,
+    ) -> DeviceCert<Untrusted> {
+        DeviceCert::new(Box::new(CountingCert {
+            inner: dcert.clone(),
+            valid_from,
+            expires,
+            validations: validations.clone(),
+        }))
+    }
+
+    #[test]
+    fn cache_hit_skips_validation() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let dsec = AlphaSecret::new();
+        let dcert = AlphaCert::new(&dsec, &isec, Some(&icert));
+        let validations = Rc::new(Cell::new(0));
+        let now = Utc::now();
+
+        let mut cache = ValidationCache::new(4);
+        assert!(cache
+            .validate(device_cert(&dcert, None, &validations), &icert, now)
+            .is_some());
+        assert!(cache
+            .validate(device_cert(&dcert, None, &validations), &icert, now)
+            .is_some());
+        assert_eq!(validations.get(), 1);
+        assert_eq!(cache.len(), 1);
+
+        // A different issuer is not answered from the cache
+        let other = AlphaCert::new(&dsec, &dsec, None);
+        assert!(cache
+            .validate(device_cert(&dcert, None, &validations), &other, now)
+            .is_none());
+    }
+
+    #[test]
+    fn expired_entry_is_recomputed() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let dsec = AlphaSecret::new();
+        let dcert = AlphaCert::new(&dsec, &isec, Some(&icert));
+        let validations = Rc::new(Cell::new(0));
+        let now = Utc::now();
+        let expires = Some(now + Duration::hours(1));
+
+        let mut cache = ValidationCache::new(4);
+        assert!(cache
+            .validate(device_cert(&dcert, expires, &validations), &icert, now)
+            .is_some());
+        let later = now + Duration::hours(2);
+        assert!(cache
+            .validate(device_cert(&dcert, None, &validations), &icert, later)
+            .is_some());
+        assert_eq!(validations.get(), 2);
+        // Expired certs are not valid at all
+        let mut cache = ValidationCache::new(4);
+        assert!(cache
+            .validate(device_cert(&dcert, expires, &validations), &icert, later)
+            .is_none());
+        // Valid up to and including the expiry time
+        let mut cache = ValidationCache::new(4);
+        let expiry = now + Duration::hours(1);
+        assert!(cache
+            .validate(device_cert(&dcert, expires, &validations), &icert, expiry)
+            .is_some());
+        assert!(cache
+            .validate(device_cert(&dcert, expires, &validations), &icert, expiry)
+            .is_some());
+    }
+
+    #[test]
+    fn not_yet_valid_is_rejected() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let dsec = AlphaSecret::new();
+        let dcert = AlphaCert::new(&dsec, &isec, Some(&icert));
+        let validations = Rc::new(Cell::new(0));
+        let now = Utc::now();
+        let from = Some(now + Duration::hours(1));
+
+        let mut cache = ValidationCache::new(4);
+        assert!(cache
+            .validate(
+                device_cert_from(&dcert, from, None, &validations),
+                &icert,
+                now
+            )
+            .is_none());
+        assert!(cache.is_empty());
+        assert_eq!(validations.get(), 0);
+
+        // Cached once valid, but not answered for an earlier time
+        let later = now + Duration::hours(2);
+        assert!(cache
+            .validate(
+                device_cert_from(&dcert, from, None, &validations),
+                &icert,
+                later
+            )
+            .is_some());
+        assert_eq!(cache.len(), 1);
+        assert!(cache
+            .validate(
+                device_cert_from(&dcert, from, None, &validations),
+                &icert,
+                now
+            )
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let dcerts: Vec<AlphaCert> = (0..3)
+            .map(|_| AlphaCert::new(&AlphaSecret::new(), &isec, Some(&icert)))
+            .collect();
+        let validations = Rc::new(Cell::new(0));
+        let now = Utc::now();
+
+        let mut cache = ValidationCache::new(2);
+        cache.validate(device_cert(&dcerts[0], None, &validations), &icert, now);
+        cache.validate(device_cert(&dcerts[1], None, &validations), &icert, now);
+        cache.validate(device_cert(&dcerts[0], None, &validations), &icert, now);
+        cache.validate(device_cert(&dcerts[2], None, &validations), &icert, now);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(validations.get(), 3);
+        // dcerts[1] was evicted, dcerts[0] is still cached
+        cache.validate(device_cert(&dcerts[0], None, &validations), &icert, now);
+        assert_eq!(validations.get(), 3);
+        cache.validate(device_cert(&dcerts[1], None, &validations), &icert, now);
+        assert_eq!(validations.get(), 4);
+    }
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{Public, Secret, SignatureBytes};
+
+/// Key variants which can be negotiated, strongest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Alpha,
+}
+
+impl Variant {
+    /// All variants in order of preference
+    pub const ALL: &'static [Variant] = &[Variant::Alpha];
+
+    /// Returns the name used in `Capabilities::variants`
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Alpha => "alpha",
+        }
+    }
+
+    /// Returns the id tagging data of this variant, see
+    /// `Encrypted::variant_id`
+    pub fn id(self) -> u8 {
+        match self {
+            Variant::Alpha => 1,
+        }
+    }
+}
+
+/// Digest over both capability lists and the chosen variant.
+/// After negotiating, both peers sign their transcript and verify the
+/// signature of the other. If an attacker modified a capability list in
+/// transit, e.g. to force a weaker variant, the transcripts differ and
+/// the verification fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+    inner: [u8; 32],
+}
+
+impl AsRef<[u8]> for Transcript {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+impl Transcript {
+    fn new(local: &Capabilities, remote: &Capabilities, variant: Variant) -> Self {
+        // Sorted, so both peers hash their lists in the same order
+        let mut lists = [local.encode(), remote.encode()];
+        lists.sort();
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(b"stackment negotiation");
+        for list in &lists {
+            ctx.update(&(list.len() as u64).to_be_bytes());
+            ctx.update(list);
+        }
+        ctx.update(variant.name().as_bytes());
+        let mut inner = [0; 32];
+        inner.copy_from_slice(ctx.finish().as_ref());
+        Self { inner }
+    }
+
+    /// Sign the transcript for the peer
+    pub fn sign(&self, secret: &dyn Secret) -> SignatureBytes {
+        secret.sign(self)
+    }
+
+    /// Returns true if `signature` is the signature of `peer` over the
+    /// same transcript
+    pub fn verify(&self, peer: &dyn Public, signature: &SignatureBytes) -> bool {
+        peer.verify(self, signature)
+    }
+}
+
+/// Choose the strongest variant supported by both `local` and `remote`.
+/// The choice does not depend on which peer is local, so both peers agree.
+/// Returns None without a common variant.
+pub fn negotiate_variant(
+    local: &Capabilities,
+    remote: &Capabilities,
+) -> Option<(Variant, Transcript)> {
+    let supports = |caps: &Capabilities, variant: Variant| {
+        caps.variants.iter().any(|name| name == variant.name())
+    };
+    let variant = *Variant::ALL
+        .iter()
+        .find(|variant| supports(local, **variant) && supports(remote, **variant))?;
+    Some((variant, Transcript::new(local, remote, variant)))
+}
+
+/// Algorithms and features compiled into this build.
+/// Peers can exchange their capabilities and pick a scheme both support
+/// with `common`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Key variants, e.g. "alpha"
+    pub variants: Vec<String>,
+    /// AEAD algorithms for encryption
+    pub aeads: Vec<String>,
+    /// Key derivation functions, for key agreement and passphrases
+    pub kdfs: Vec<String>,
+    /// Serialized secrets are read back and checked (`serialize-check`)
+    pub serialize_check: bool,
+    /// Secret keys can be locked in memory (`mlock`)
+    #[serde(default)]
+    pub mlock: bool,
+    /// Message keys are wrapped in parallel (`rayon`)
+    #[serde(default)]
+    pub rayon: bool,
+    /// Events are logged with the `log` crate (`logging`)
+    #[serde(default)]
+    pub logging: bool,
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| String::from(*name)).collect()
+}
+
+fn intersect(a: &[String], b: &[String]) -> Vec<String> {
+    a.iter().filter(|name| b.contains(name)).cloned().collect()
+}
+
+/// Returns the capabilities of this build
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        variants: names(&["alpha"]),
+        aeads: names(&["chacha20-poly1305"]),
+        kdfs: names(&["pbkdf2-hmac-sha256", "hkdf-sha256", "argon2id"]),
+        serialize_check: cfg!(feature = "serialize-check"),
+        mlock: cfg!(feature = "mlock"),
+        rayon: cfg!(feature = "rayon"),
+        logging: cfg!(feature = "logging"),
+    }
+}
+
+impl Capabilities {
+    /// Algorithms supported by both `self` and `other`, in the order of
+    /// preference of `self`.
+    /// Build features are local and copied from `self`.
+    pub fn common(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            variants: intersect(&self.variants, &other.variants),
+            aeads: intersect(&self.aeads, &other.aeads),
+            kdfs: intersect(&self.kdfs, &other.kdfs),
+            serialize_check: self.serialize_check,
+            mlock: self.mlock,
+            rayon: self.rayon,
+            logging: self.logging,
+        }
+    }
+
+    /// Canonical DER encoding for the negotiation transcript
+    fn encode(&self) -> Vec<u8> {
+        yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                for list in &[&self.variants, &self.aeads, &self.kdfs] {
+                    writer.next().write_sequence(|writer| {
+                        for name in list.iter() {
+                            writer.next().write_utf8string(name);
+                        }
+                    });
+                }
+                for flag in &[self.serialize_check, self.mlock, self.rayon, self.logging] {
+                    writer.next().write_bool(*flag);
+                }
+            });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_build() {
+        let caps = capabilities();
+        assert!(caps.variants.contains(&String::from("alpha")));
+        assert!(caps.aeads.contains(&String::from("chacha20-poly1305")));
+    }
+
+    #[test]
+    fn build_features() {
+        let caps = capabilities();
+        #[cfg(feature = "serialize-check")]
+        assert!(caps.serialize_check);
+        #[cfg(not(feature = "serialize-check"))]
+        assert!(!caps.serialize_check);
+        #[cfg(feature = "mlock")]
+        assert!(caps.mlock);
+        #[cfg(not(feature = "mlock"))]
+        assert!(!caps.mlock);
+        #[cfg(feature = "rayon")]
+        assert!(caps.rayon);
+        #[cfg(not(feature = "rayon"))]
+        assert!(!caps.rayon);
+        #[cfg(feature = "logging")]
+        assert!(caps.logging);
+        #[cfg(not(feature = "logging"))]
+        assert!(!caps.logging);
+    }
+
+    #[test]
+    fn negotiate_common() {
+        let ours = capabilities();
+        let peer: Capabilities = serde_json::from_str(
+            r#"{"variants":["beta","alpha"],"aeads":["aes-256-gcm"],
+                "kdfs":["argon2id"],"serialize_check":false}"#,
+        )
+        .unwrap();
+        // Build features missing in older lists default to false
+        assert!(!peer.mlock && !peer.rayon && !peer.logging);
+        let common = ours.common(&peer);
+        assert_eq!(common.variants, vec![String::from("alpha")]);
+        assert!(common.aeads.is_empty());
+        assert_eq!(common.kdfs, vec![String::from("argon2id")]);
+    }
+
+    #[test]
+    fn detect_downgrade() {
+        use crate::crypto::alpha::AlphaSecret;
+        let alice = AlphaSecret::new();
+        let bob = AlphaSecret::new();
+        let alice_caps = capabilities();
+        let mut bob_caps = capabilities();
+        bob_caps.variants.insert(0, String::from("beta"));
+
+        let (variant, alice_transcript) = negotiate_variant(&alice_caps, &bob_caps).unwrap();
+        let (bob_variant, bob_transcript) = negotiate_variant(&bob_caps, &alice_caps).unwrap();
+        assert_eq!(variant, Variant::Alpha);
+        assert_eq!(variant, bob_variant);
+        assert_eq!(alice_transcript, bob_transcript);
+        let signature = bob_transcript.sign(&bob);
+        assert!(alice_transcript.verify(bob.public_key(), &signature));
+
+        // The list of bob was stripped down on its way to alice
+        let mut tampered = bob_caps.clone();
+        tampered.kdfs.retain(|kdf| kdf == "pbkdf2-hmac-sha256");
+        let (_, alice_transcript) = negotiate_variant(&alice_caps, &tampered).unwrap();
+        assert!(!alice_transcript.verify(bob.public_key(), &signature));
+        let signature = alice_transcript.sign(&alice);
+        assert!(!bob_transcript.verify(alice.public_key(), &signature));
+
+        tampered.variants = vec![String::from("beta")];
+        assert!(negotiate_variant(&alice_caps, &tampered).is_none());
+    }
+}
@@ -4,26 +4,13 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-use std::ops::Deref;
-
-use std::fmt;
-use std::io::Read;
 use std::io::Write;
 
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
-};
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
-
-use crate::crypto::{
-    validate_signature, DeviceCert, Fingerprint, IdentCert, Public, Secret, SignatureBytes,
-    Trusted, Untrusted,
-};
+use chrono::{DateTime, Utc};
+use ring::digest;
 
+use crate::crypto::alpha::AlphaPublic;
+use crate::crypto::{Clock, Fingerprint, Public, PublicVariant};
 
 /// Cert trait which all Certificate variants must implement.
 pub trait Cert: AsRef<[u8]> {
@@ -40,6 +27,39 @@ pub trait Cert: AsRef<[u8]> {
     /// Returns the raw bytes of the public signing key
     fn signing_public_key(&self) -> &[u8];
 
+    /// Returns the public keys of the certificate subject
+    fn public_key(&self) -> &dyn Public;
+
+    /// Returns the key the certificate claims to be issued for, e.g. to
+    /// display it before a trust decision.
+    /// The signature is not checked, the key must not be trusted until
+    /// the certificate is validated with `is_valid` or `into_trusted`.
+    fn subject_public_unchecked(&self) -> AlphaPublic {
+        match self.public_key().as_variant_ref() {
+            PublicVariant::Alpha(public) => *public,
+        }
+    }
+
+    /// Returns the time the certificate expires,
+    /// or None if it does not expire.
+    fn expires(&self) -> Option<DateTime<Utc>>;
+
+    /// Returns the time the certificate becomes valid,
+    /// or None if it is valid from the start.
+    fn valid_from(&self) -> Option<DateTime<Utc>>;
+
+    /// Returns true if `now` is within the validity of the certificate.
+    /// The signature is not checked, see `is_valid`.
+    fn is_valid_at(&self, now: DateTime<Utc>) -> bool {
+        self.valid_from().is_none_or(|from| from <= now)
+            && self.expires().is_none_or(|expires| now <= expires)
+    }
+
+    /// Like `is_valid_at`, with the current time of `clock`
+    fn is_valid_with_clock(&self, clock: &dyn Clock) -> bool {
+        self.is_valid_at(clock.now())
+    }
+
     /// Validate the certificate againts a specific issuer certificate.
     /// The issuer can be found by using `issuer_fingerprint`, then by
     /// a lookup into a managed trusted keystore.
@@ -47,12 +67,16 @@ pub trait Cert: AsRef<[u8]> {
 
     /// Returns the fingerprint from the certificate.
     /// The fingerprint is determined by using a SHA256 digest over
-    /// the raw certificate bytes.
+    /// the raw certificate bytes, including validity, subject and signature.
+    /// This identifies the exact certificate, e.g. for revocation or
+    /// caching. It differs from the key fingerprint of the subject
+    /// (`AlphaPublic::fingerprint`), which stays the same for all
+    /// certificates issued for one key.
     fn fingerprint(&self) -> Fingerprint {
         let raw = self.as_ref();
         let d = digest::digest(&digest::SHA256, raw);
         let mut inner: [u8; 32] = [0; 32];
         inner.copy_from_slice(&d.as_ref()[0..32]);
-        Fingerprint { inner }
+        Fingerprint::sha256(inner)
     }
 }
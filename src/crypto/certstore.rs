@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use crate::crypto::alpha::AlphaCert;
+use crate::crypto::{Cert, Fingerprint, PublicVariant, Result};
+
+/// Storage for issued device certificates, e.g. on a device management
+/// server. Certificates are addressed by the key fingerprint of the
+/// device (`AlphaPublic::fingerprint`) and grouped by the fingerprint of
+/// the issuing identity certificate.
+/// Revoked certificates are not returned by `get_by_fingerprint` or the
+/// lists.
+pub trait CertStore {
+    /// Store `cert`, replacing any certificate for the same device
+    fn put(&mut self, cert: AlphaCert) -> Result<()>;
+
+    /// Returns the certificate of the device with key `fingerprint`
+    fn get_by_fingerprint(&self, fingerprint: &Fingerprint) -> Result<Option<AlphaCert>>;
+
+    /// Returns all certificates issued by the identity `identity`
+    fn list_for_identity(&self, identity: &Fingerprint) -> Result<Vec<AlphaCert>>;
+
+    /// Revoke the certificate of the device with key `fingerprint`
+    fn revoke(&mut self, fingerprint: &Fingerprint) -> Result<()>;
+
+    /// Like `list_for_identity`, but only certificates valid at `now`
+    fn list_valid_for_identity(
+        &self,
+        identity: &Fingerprint,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<AlphaCert>> {
+        Ok(self
+            .list_for_identity(identity)?
+            .into_iter()
+            .filter(|cert| cert.is_valid_at(now))
+            .collect())
+    }
+}
+
+/// Returns the key fingerprint of the device a certificate is issued for
+fn device_fingerprint(cert: &AlphaCert) -> Fingerprint {
+    match cert.public_key().as_variant_ref() {
+        PublicVariant::Alpha(public) => public.fingerprint(),
+    }
+}
+
+/// Certificate store keeping everything in memory
+#[derive(Default)]
+pub struct MemoryCertStore {
+    certs: HashMap<Fingerprint, AlphaCert>,
+    revoked: HashSet<Fingerprint>,
+}
+
+impl MemoryCertStore {
+    /// Constructs an empty certificate store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CertStore for MemoryCertStore {
+    fn put(&mut self, cert: AlphaCert) -> Result<()> {
+        self.certs.insert(device_fingerprint(&cert), cert);
+        Ok(())
+    }
+
+    fn get_by_fingerprint(&self, fingerprint: &Fingerprint) -> Result<Option<AlphaCert>> {
+        if self.revoked.contains(fingerprint) {
+            return Ok(None);
+        }
+        Ok(self.certs.get(fingerprint).cloned())
+    }
+
+    fn list_for_identity(&self, identity: &Fingerprint) -> Result<Vec<AlphaCert>> {
+        Ok(self
+            .certs
+            .iter()
+            .filter(|(fingerprint, cert)| {
+                !self.revoked.contains(fingerprint) && cert.issuer_fingerprint() == *identity
+            })
+            .map(|(_, cert)| cert.clone())
+            .collect())
+    }
+
+    fn revoke(&mut self, fingerprint: &Fingerprint) -> Result<()> {
+        self.revoked.insert(*fingerprint);
+        Ok(())
+    }
+}
+
+/// Certificate store keeping each certificate as DER in a file named by
+/// the device fingerprint. A revoked certificate is kept, next to an
+/// empty marker file.
+pub struct FileCertStore {
+    dir: PathBuf,
+}
+
+impl FileCertStore {
+    /// Constructs a certificate store using the existing directory `dir`
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, fingerprint: &Fingerprint, extension: &str) -> PathBuf {
+        self.dir
+            .join(data_encoding::HEXLOWER.encode(&fingerprint.inner))
+            .with_extension(extension)
+    }
+
+    fn is_revoked(&self, fingerprint: &Fingerprint) -> bool {
+        self.path(fingerprint, "revoked").exists()
+    }
+}
+
+impl CertStore for FileCertStore {
+    fn put(&mut self, cert: AlphaCert) -> Result<()> {
+        let mut raw = Vec::new();
+        cert.serialize(&mut raw);
+        fs::write(self.path(&device_fingerprint(&cert), "cert"), &raw)?;
+        Ok(())
+    }
+
+    fn get_by_fingerprint(&self, fingerprint: &Fingerprint) -> Result<Option<AlphaCert>> {
+        let path = self.path(fingerprint, "cert");
+        if self.is_revoked(fingerprint) || !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(AlphaCert::parse(&fs::read(path)?)?))
+    }
+
+    fn list_for_identity(&self, identity: &Fingerprint) -> Result<Vec<AlphaCert>> {
+        let mut certs = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "cert")
+                || path.with_extension("revoked").exists()
+            {
+                continue;
+            }
+            let cert = AlphaCert::parse(&fs::read(path)?)?;
+            if cert.issuer_fingerprint() == *identity {
+                certs.push(cert);
+            }
+        }
+        Ok(certs)
+    }
+
+    fn revoke(&mut self, fingerprint: &Fingerprint) -> Result<()> {
+        fs::write(self.path(fingerprint, "revoked"), b"")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+    use chrono::{Duration, TimeZone};
+
+    fn store_and_revoke(store: &mut dyn CertStore) {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let devices: Vec<AlphaSecret> = (0..3).map(|_| AlphaSecret::new()).collect();
+        for device in &devices {
+            store
+                .put(AlphaCert::new(device, &isec, Some(&icert)))
+                .unwrap();
+        }
+        let other = AlphaSecret::new();
+        store.put(AlphaCert::new(&other, &other, None)).unwrap();
+
+        let fingerprint = devices[0].public_key().fingerprint();
+        let cert = store.get_by_fingerprint(&fingerprint).unwrap().unwrap();
+        assert!(devices[0].public_key().matches_cert(&cert));
+        assert!(cert.is_valid(&icert));
+        assert_eq!(
+            store.list_for_identity(&icert.fingerprint()).unwrap().len(),
+            3
+        );
+
+        store.revoke(&fingerprint).unwrap();
+        assert!(store.get_by_fingerprint(&fingerprint).unwrap().is_none());
+        assert_eq!(
+            store.list_for_identity(&icert.fingerprint()).unwrap().len(),
+            2
+        );
+        let unknown = AlphaSecret::new().public_key().fingerprint();
+        assert!(store.get_by_fingerprint(&unknown).unwrap().is_none());
+    }
+
+    fn filter_expired(store: &mut dyn CertStore) {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        for days in &[10, 100] {
+            let cert = AlphaCert::new_with_validity(
+                &AlphaSecret::new(),
+                &isec,
+                Some(&icert),
+                start,
+                start + Duration::days(*days),
+            )
+            .unwrap();
+            store.put(cert).unwrap();
+        }
+        let identity = icert.fingerprint();
+        let valid_at = |days| {
+            store
+                .list_valid_for_identity(&identity, start + Duration::days(days))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(valid_at(5), 2);
+        assert_eq!(valid_at(50), 1);
+        assert_eq!(valid_at(500), 0);
+        assert_eq!(store.list_for_identity(&identity).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn memory_store() {
+        store_and_revoke(&mut MemoryCertStore::new());
+        filter_expired(&mut MemoryCertStore::new());
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        store_and_revoke(&mut FileCertStore::new(dir.path().to_path_buf()));
+        let dir = tempfile::tempdir().unwrap();
+        filter_expired(&mut FileCertStore::new(dir.path().to_path_buf()));
+    }
+}
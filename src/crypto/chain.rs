@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use chrono::{DateTime, Utc};
+
+use crate::crypto::{Cert, Fingerprint};
+
+/// Result of validating one certificate of a chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkResult {
+    /// Fingerprint of the certificate
+    pub fingerprint: Fingerprint,
+    /// True if the signature validates against the issuer, the next
+    /// certificate of the chain
+    pub signature_valid: bool,
+    /// True if the time was within the validity of the certificate
+    pub time_valid: bool,
+}
+
+impl LinkResult {
+    /// Returns true if the certificate passed all checks
+    pub fn is_valid(&self) -> bool {
+        self.signature_valid && self.time_valid
+    }
+}
+
+/// Validate each certificate of `chain` at `now` and report the result of
+/// every link, e.g. to find an expired intermediate certificate.
+/// The chain starts with the leaf, each certificate is issued by the next
+/// one and the last certificate must be self-signed. All certificates are
+/// checked, also after a failed one.
+/// The chain is only trusted if all links are valid and the last
+/// certificate is a trusted identity certificate.
+/// Logs a debug event before and after the validation, the second one is
+/// a warning if a link failed.
+pub fn validate_chain_verbose(chain: &[&dyn Cert], now: DateTime<Utc>) -> Vec<LinkResult> {
+    event!(
+        debug,
+        "Validating chain of {} certs for {} at {}",
+        chain.len(),
+        leaf_name(chain.iter().map(|cert| cert.fingerprint())),
+        now
+    );
+    let results: Vec<LinkResult> = chain
+        .iter()
+        .enumerate()
+        .map(|(i, cert)| {
+            let issuer = chain.get(i + 1).unwrap_or(cert);
+            LinkResult {
+                fingerprint: cert.fingerprint(),
+                signature_valid: cert.is_valid(*issuer),
+                time_valid: cert.is_valid_at(now),
+            }
+        })
+        .collect();
+    match results.iter().position(|link| !link.is_valid()) {
+        Some(failed) => event!(
+            warn,
+            "Validated chain for {}: link {} ({}) failed",
+            leaf_name(results.iter().map(|link| link.fingerprint)),
+            failed,
+            results[failed].fingerprint
+        ),
+        None => event!(
+            debug,
+            "Validated chain for {}: all links valid",
+            leaf_name(results.iter().map(|link| link.fingerprint))
+        ),
+    }
+    results
+}
+
+/// Name of the leaf of a chain in log events
+fn leaf_name(mut fingerprints: impl Iterator<Item = Fingerprint>) -> String {
+    fingerprints
+        .next()
+        .map(|fingerprint| fingerprint.to_string())
+        .unwrap_or_else(|| String::from("empty chain"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::{AlphaCert, AlphaSecret};
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn expired_identity_cert() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let now = start + Duration::days(50);
+        let isec = AlphaSecret::new();
+        let icert =
+            AlphaCert::new_with_validity(&isec, &isec, None, start, start + Duration::days(10))
+                .unwrap();
+        let dsec = AlphaSecret::new();
+        let dcert = AlphaCert::new_with_validity(
+            &dsec,
+            &isec,
+            Some(&icert),
+            start,
+            start + Duration::days(100),
+        )
+        .unwrap();
+
+        let results = validate_chain_verbose(&[&dcert, &icert], now);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].fingerprint, dcert.fingerprint());
+        assert!(results[0].is_valid());
+        assert_eq!(results[1].fingerprint, icert.fingerprint());
+        assert!(results[1].signature_valid);
+        assert!(!results[1].time_valid);
+
+        // Wrong order, nothing validates
+        let results = validate_chain_verbose(&[&icert, &dcert], start);
+        assert!(!results[0].signature_valid);
+        assert!(!results[1].signature_valid);
+        assert!(results.iter().all(|link| link.time_valid));
+        assert!(validate_chain_verbose(&[], now).is_empty());
+    }
+}
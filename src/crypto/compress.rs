@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::io::Read;
+use std::io::Write;
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::crypto::{DecryptError, MAX_PLAINTEXT};
+
+/// Compression algorithm applied to plaintext before encryption.
+/// The algorithm is stored in the header of the encrypted data, see
+/// `Encrypted::compression`, and authenticated as additional data.
+/// Only DEFLATE is supported, zstd is not.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Plaintext is stored as is
+    #[default]
+    None = 0,
+    /// Raw DEFLATE stream (RFC 1951)
+    Deflate = 1,
+}
+
+impl Compression {
+    /// Returns the algorithm stored as `id`, None if unknown
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            id if id == Compression::None as u8 => Some(Compression::None),
+            id if id == Compression::Deflate as u8 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// Compress `plain_bytes` with `compression`.
+pub fn compress(plain_bytes: &[u8], compression: Compression) -> Vec<u8> {
+    match compression {
+        Compression::None => Vec::from(plain_bytes),
+        Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(plain_bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+    }
+}
+
+/// Decompress `bytes` compressed with `compression`.
+/// Output larger than `MAX_PLAINTEXT` is rejected, see
+/// `decompress_with_limit`.
+pub fn decompress(bytes: &[u8], compression: Compression) -> Result<Vec<u8>, DecryptError> {
+    decompress_with_limit(bytes, compression, MAX_PLAINTEXT)
+}
+
+/// Like `decompress`, but rejects output larger than `max_len` bytes.
+/// A small input can inflate to a huge output, so the output is never
+/// read further than the limit.
+pub fn decompress_with_limit(
+    bytes: &[u8],
+    compression: Compression,
+    max_len: usize,
+) -> Result<Vec<u8>, DecryptError> {
+    let out = match compression {
+        Compression::None => Vec::from(bytes),
+        Compression::Deflate => {
+            let mut out = Vec::new();
+            DeflateDecoder::new(bytes)
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut out)
+                .map_err(|_| DecryptError::Decompression)?;
+            out
+        }
+    };
+    if out.len() > max_len {
+        return Err(DecryptError::DecompressedTooLarge(max_len));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_decompression_bomb() {
+        let plain = vec![0; 1024 * 1024];
+        let compressed = compress(&plain, Compression::Deflate);
+        assert!(compressed.len() < 16 * 1024);
+        assert_eq!(
+            decompress_with_limit(&compressed, Compression::Deflate, plain.len()).unwrap(),
+            plain
+        );
+        match decompress_with_limit(&compressed, Compression::Deflate, plain.len() - 1) {
+            Err(DecryptError::DecompressedTooLarge(_)) => {}
+            _ => panic!("limit exceeded"),
+        }
+        let stored = compress(&plain, Compression::None);
+        assert!(decompress_with_limit(&stored, Compression::None, 1024).is_err());
+
+        // Small input inflating beyond the default limit
+        let bomb = compress(&vec![0; MAX_PLAINTEXT + 1], Compression::Deflate);
+        assert!(bomb.len() < MAX_PLAINTEXT / 512);
+        assert!(decompress(&bomb, Compression::Deflate).is_err());
+    }
+
+    #[test]
+    fn reject_malformed_deflate() {
+        match decompress(&[0xff, 0xff, 0xff], Compression::Deflate) {
+            Err(DecryptError::Decompression) => {}
+            _ => panic!("malformed stream accepted"),
+        }
+        assert_eq!(Compression::from_id(1), Some(Compression::Deflate));
+        assert_eq!(Compression::from_id(2), None);
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Conversion of ED25519 keys to X25519 keys, so a single ED25519 key
+//! can be used for signing and encryption.
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ring::digest;
+
+use zeroize::Zeroizing;
+
+use crate::crypto::ParseError;
+
+/// Convert an ED25519 public key to the X25519 public key with the
+/// birational map from the Edwards curve to the Montgomery curve
+/// (u = (1 + y) / (1 - y)).
+/// Invalid points, non-canonical encodings and points of small order
+/// are rejected.
+pub fn ed25519_to_x25519_public(ed25519_pubkey: &[u8; 32]) -> Result<[u8; 32], ParseError> {
+    let compressed = CompressedEdwardsY(*ed25519_pubkey);
+    let point = compressed
+        .decompress()
+        .ok_or_else(|| ParseError::InvalidKey(String::from("Invalid ED25519 point")))?;
+    if point.compress() != compressed {
+        return Err(ParseError::InvalidKey(String::from(
+            "Non-canonical ED25519 point",
+        )));
+    }
+    if point.is_small_order() {
+        return Err(ParseError::InvalidKey(String::from(
+            "ED25519 point of small order",
+        )));
+    }
+    Ok(point.to_montgomery().to_bytes())
+}
+
+/// Convert an ED25519 seed to the X25519 secret: the clamped ED25519
+/// secret scalar, which is the first half of SHA512(seed).
+/// The X25519 public key of the result matches `ed25519_to_x25519_public`
+/// of the seed's ED25519 public key.
+pub fn ed25519_seed_to_x25519_secret(ed25519_seed: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+    let hash = digest::digest(&digest::SHA512, ed25519_seed);
+    let mut x25519_secret = Zeroizing::new([0; 32]);
+    x25519_secret.copy_from_slice(&hash.as_ref()[..32]);
+    x25519_secret[0] &= 248;
+    x25519_secret[31] &= 127;
+    x25519_secret[31] |= 64;
+    x25519_secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn unhex(s: &str) -> [u8; 32] {
+        let mut out = [0; 32];
+        out.copy_from_slice(&data_encoding::HEXLOWER.decode(s.as_bytes()).unwrap());
+        out
+    }
+
+    /// Vector from the libsodium ed25519_convert test
+    #[test]
+    fn known_conversion() {
+        let seed = unhex("421151a459faeade3d247115f94aedae42318124095afabe4d1451a559faedee");
+        let keypair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let mut ed25519_pubkey = [0; 32];
+        ed25519_pubkey.copy_from_slice(keypair.public_key().as_ref());
+        assert_eq!(
+            ed25519_to_x25519_public(&ed25519_pubkey).unwrap(),
+            unhex("f1814f0e8ff1043d8a44d25babff3cedcae6c22c3edaa48f857ae70de2baae50")
+        );
+        assert_eq!(
+            *ed25519_seed_to_x25519_secret(&seed),
+            unhex("8052030376d47112be7f73ed7a019293dd12ad910b654455798b4667d73de166")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_points() {
+        // y = 2 is not on the curve
+        let mut invalid = [0; 32];
+        invalid[0] = 2;
+        assert!(ed25519_to_x25519_public(&invalid).is_err());
+        // The identity has small order
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        assert!(ed25519_to_x25519_public(&identity).is_err());
+        // y = p + 1 is a non-canonical encoding of y = 1
+        let mut non_canonical = [0xff; 32];
+        non_canonical[0] = 0xee;
+        non_canonical[31] = 0x7f;
+        assert!(ed25519_to_x25519_public(&non_canonical).is_err());
+    }
+}
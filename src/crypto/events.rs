@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Log events for operations, emitted with the `log` crate when the
+//! `logging` feature is enabled. All events use the target `TARGET`.
+//! Events only name keys and certificates by their fingerprint or key id,
+//! secret material, key bytes and plaintext are never logged.
+
+/// Target of all log events of this crate
+pub const TARGET: &str = "stackment_crypto";
+
+/// Log an event at `$level` (`debug`, `warn`, ...) if the `logging`
+/// feature is enabled. The arguments are not evaluated otherwise, and
+/// without the feature the `log` crate is not a dependency.
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "logging")]
+        log::$level!(target: $crate::crypto::events::TARGET, $($arg)+);
+        // Type checks the arguments and keeps them used, never runs
+        #[cfg(not(feature = "logging"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+#[cfg(all(test, feature = "logging"))]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    use log::{Level, Log, Metadata, Record};
+
+    use crate::crypto::alpha::{AlphaCert, AlphaPublic, AlphaSecret};
+    use crate::crypto::{validate_chain_verbose, Cert, Public, Secret};
+
+    /// Records the events of all threads, each test only looks at its own
+    struct Capture {
+        events: Mutex<Vec<(ThreadId, Level, String)>>,
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == super::TARGET
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.events.lock().unwrap().push((
+                    thread::current().id(),
+                    record.level(),
+                    record.args().to_string(),
+                ));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture {
+        events: Mutex::new(Vec::new()),
+    };
+
+    /// Run `f` and return the events it logged
+    fn captured(f: impl FnOnce()) -> Vec<(Level, String)> {
+        // Fails if another test installed the logger already
+        let _ = log::set_logger(&CAPTURE);
+        log::set_max_level(log::LevelFilter::Trace);
+        let id = thread::current().id();
+        CAPTURE.events.lock().unwrap().retain(|event| event.0 != id);
+        f();
+        CAPTURE
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.0 == id)
+            .map(|event| (event.1, event.2.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn verification_failure_logged_without_key_bytes() {
+        let secret = AlphaSecret::new();
+        let other = AlphaSecret::new();
+        let signature = other.sign(b"message");
+        let events = captured(|| assert!(!secret.public_key().verify(b"message", &signature)));
+        let warnings: Vec<&String> = events
+            .iter()
+            .filter(|event| event.0 == Level::Warn)
+            .map(|event| &event.1)
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains(&secret.public_key().key_id().to_string()));
+
+        let mut serialized = Vec::new();
+        secret.serialize(&mut serialized);
+        let public = secret.public_key();
+        for bytes in &[
+            public.signing_public_key(),
+            public.encryption_public_key(),
+            &serialized[..],
+        ] {
+            let hex = data_encoding::HEXLOWER.encode(bytes);
+            assert!(events.iter().all(|event| !event.1.contains(&hex)));
+        }
+    }
+
+    #[test]
+    fn cert_validation_logged() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let cert = AlphaCert::new(&AlphaSecret::new(), &isec, Some(&icert));
+        let events = captured(|| {
+            assert!(cert.is_valid(&icert));
+            assert!(!icert.is_valid(&cert));
+        });
+        let fingerprint = cert.fingerprint().to_string();
+        assert!(events
+            .iter()
+            .any(|event| event.0 == Level::Debug && event.1.contains(&fingerprint)));
+        assert!(events
+            .iter()
+            .any(|event| event.0 == Level::Warn && event.1.contains(&fingerprint)));
+    }
+
+    #[test]
+    fn chain_validation_logged() {
+        let isec = AlphaSecret::new();
+        let icert = AlphaCert::new(&isec, &isec, None);
+        let cert = AlphaCert::new(&AlphaSecret::new(), &isec, Some(&icert));
+        let now = chrono::Utc::now();
+        let leaf = cert.fingerprint().to_string();
+        let events = captured(|| {
+            validate_chain_verbose(&[&cert, &icert], now);
+        });
+        let chain: Vec<&(Level, String)> = events
+            .iter()
+            .filter(|event| event.1.contains("chain"))
+            .collect();
+        assert_eq!(chain.len(), 2);
+        assert!(chain.iter().all(|event| event.1.contains(&leaf)));
+        assert_eq!(chain[1].0, Level::Debug);
+
+        let events = captured(|| {
+            validate_chain_verbose(&[&icert, &cert], now);
+        });
+        assert!(events
+            .iter()
+            .any(|event| event.0 == Level::Warn && event.1.contains("chain")));
+    }
+
+    #[test]
+    fn verify_any_warns_once() {
+        let secrets: Vec<AlphaSecret> = (0..3).map(|_| AlphaSecret::new()).collect();
+        let keys: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        let level_count = |events: &[(Level, String)], level| {
+            events.iter().filter(|event| event.0 == level).count()
+        };
+        let signature = AlphaSecret::new().sign(b"message");
+        let events =
+            captured(|| assert!(AlphaPublic::verify_any(&keys, b"message", &signature).is_none()));
+        assert_eq!(level_count(&events, Level::Warn), 1);
+        assert_eq!(level_count(&events, Level::Debug), 3);
+        let events = captured(|| {
+            assert!(AlphaPublic::verify_any_constant_time(&keys, b"message", &signature).is_none())
+        });
+        assert_eq!(level_count(&events, Level::Warn), 1);
+        assert_eq!(level_count(&events, Level::Debug), 3);
+        let signature = secrets[2].sign(b"message");
+        let events = captured(|| {
+            assert_eq!(
+                AlphaPublic::verify_any(&keys, b"message", &signature),
+                Some(2)
+            )
+        });
+        assert_eq!(level_count(&events, Level::Warn), 0);
+    }
+
+    #[test]
+    fn failed_trial_decryption_not_a_warning() {
+        let secret = AlphaSecret::new();
+        let other = AlphaSecret::new();
+        let crypted = secret.encrypt(b"data", other.public_key()).unwrap();
+        let events = captured(|| assert!(secret.decrypt_try(&crypted).is_none()));
+        assert!(events.iter().all(|event| event.0 != Level::Warn));
+        assert!(events.iter().any(|event| event.0 == Level::Debug));
+    }
+}
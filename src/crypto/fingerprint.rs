@@ -4,38 +4,119 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-use std::ops::Deref;
-
 use std::fmt;
-use std::io::Read;
-use std::io::Write;
-
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
-};
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
-
-/// Stores a fingerprint calculated by a SHA256 digest algorithm.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+
+use ring::digest;
+
+use crate::crypto::ParseError;
+
+/// Length of the tagged encoding from `Fingerprint::to_bytes`
+pub const FINGERPRINT_BYTES_LEN: usize = 33;
+
+/// Digest algorithm a fingerprint was calculated with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FingerprintAlgorithm {
+    Sha256 = 1,
+    Sha512_256 = 2,
+}
+
+impl FingerprintAlgorithm {
+    fn digest_algorithm(self) -> &'static digest::Algorithm {
+        match self {
+            FingerprintAlgorithm::Sha256 => &digest::SHA256,
+            FingerprintAlgorithm::Sha512_256 => &digest::SHA512_256,
+        }
+    }
+}
+
+/// Stores a fingerprint calculated by a digest algorithm, SHA256 unless
+/// noted otherwise. Fingerprints of different algorithms never compare
+/// equal, even with the same digest bytes.
+/// Fingerprints are ordered by algorithm and raw bytes, so lists of fingerprints
+/// can be sorted into a canonical order (e.g. before hashing recipients).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Fingerprint {
-    // SHA-256 checksum over raw certificate data
+    pub algorithm: FingerprintAlgorithm,
+    // Checksum over raw certificate data
     pub inner: [u8; 32],
 }
 
 impl<T: AsRef<[u8]>> From<&T> for Fingerprint {
     fn from(bytes: &T) -> Self {
-        let d = digest::digest(&digest::SHA256, bytes.as_ref());
+        Fingerprint::compute(FingerprintAlgorithm::Sha256, bytes.as_ref())
+    }
+}
+
+impl Fingerprint {
+    /// Constructs a fingerprint from a raw SHA256 digest
+    pub fn sha256(inner: [u8; 32]) -> Self {
+        Fingerprint {
+            algorithm: FingerprintAlgorithm::Sha256,
+            inner,
+        }
+    }
+
+    /// Calculate the fingerprint of `bytes` with `algorithm`
+    pub fn compute(algorithm: FingerprintAlgorithm, bytes: &[u8]) -> Self {
+        let d = digest::digest(algorithm.digest_algorithm(), bytes);
         let mut inner: [u8; 32] = [0; 32];
         inner.copy_from_slice(&d.as_ref()[0..32]);
-        Fingerprint { inner }
+        Fingerprint { algorithm, inner }
+    }
+
+    /// Calculate the SHA256 fingerprint of the public key `bytes` of the
+    /// key variant named `variant`. The length-prefixed variant name is
+    /// part of the digest input, so keys of different variants never have
+    /// the same fingerprint, even with the same bytes.
+    pub fn for_variant(variant: &str, bytes: &[u8]) -> Self {
+        let mut raw = Vec::from(&b"stackment public key"[..]);
+        raw.push(variant.len() as u8);
+        raw.extend(variant.as_bytes());
+        raw.extend(bytes);
+        Fingerprint::compute(FingerprintAlgorithm::Sha256, &raw)
+    }
+
+    /// Returns the self-describing encoding: the algorithm tag followed
+    /// by the digest
+    pub fn to_bytes(&self) -> [u8; FINGERPRINT_BYTES_LEN] {
+        let mut raw = [0; FINGERPRINT_BYTES_LEN];
+        raw[0] = self.algorithm as u8;
+        raw[1..].copy_from_slice(&self.inner);
+        raw
+    }
+
+    /// Parse the encoding from `to_bytes`, checking the algorithm tag
+    /// and length
+    pub fn from_bytes(raw: &[u8]) -> Result<Self, ParseError> {
+        if raw.len() != FINGERPRINT_BYTES_LEN {
+            return Err(ParseError::InvalidKey(String::from(
+                "Invalid fingerprint length",
+            )));
+        }
+        let algorithm = match raw[0] {
+            tag if tag == FingerprintAlgorithm::Sha256 as u8 => FingerprintAlgorithm::Sha256,
+            tag if tag == FingerprintAlgorithm::Sha512_256 as u8 => {
+                FingerprintAlgorithm::Sha512_256
+            }
+            tag => {
+                return Err(ParseError::InvalidKey(format!(
+                    "Unknown fingerprint algorithm {}",
+                    tag
+                )))
+            }
+        };
+        let mut inner = [0; 32];
+        inner.copy_from_slice(&raw[1..]);
+        Ok(Fingerprint { algorithm, inner })
     }
-}
 
-impl Fingerprint {}
+    /// Returns the compact key id for this fingerprint
+    pub fn key_id(&self) -> KeyId {
+        let mut inner: [u8; 8] = [0; 8];
+        inner.copy_from_slice(&self.inner[24..32]);
+        KeyId { inner }
+    }
+}
 
 impl fmt::Display for Fingerprint {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
@@ -54,3 +135,92 @@ impl fmt::Display for Fingerprint {
         Ok(())
     }
 }
+
+/// Compact 8 byte identifier made of the low bytes of a fingerprint.
+/// Useful for indexing keys, but collisions can be forced with moderate
+/// effort, so a KeyId must never be used for security decisions.
+/// Compare full fingerprints instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyId {
+    pub inner: [u8; 8],
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&data_encoding::HEXLOWER.encode(&self.inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+    use crate::crypto::{Public, Variant};
+
+    #[test]
+    fn variant_fingerprints() {
+        let bytes = [0x42; 64];
+        assert_ne!(
+            Fingerprint::for_variant("alpha", &bytes),
+            Fingerprint::for_variant("beta", &bytes)
+        );
+        // The length prefix keeps the name and key bytes apart
+        assert_ne!(
+            Fingerprint::for_variant("alpha", b"beta"),
+            Fingerprint::for_variant("alphab", b"eta")
+        );
+
+        let isec = AlphaSecret::new();
+        let public = isec.public_key();
+        let fingerprint = public.as_variant_ref().fingerprint();
+        assert_eq!(
+            fingerprint,
+            Fingerprint::for_variant(Variant::Alpha.name(), &public.to_bytes())
+        );
+        assert_ne!(fingerprint, public.fingerprint());
+    }
+
+    #[test]
+    fn sort_is_stable_for_shuffled_input() {
+        let fps: Vec<Fingerprint> = (0u8..16).map(|i| Fingerprint::from(&[i])).collect();
+        let mut sorted = fps.clone();
+        sorted.sort();
+        let mut shuffled = fps.clone();
+        shuffled.reverse();
+        shuffled.swap(2, 11);
+        shuffled.swap(5, 7);
+        shuffled.sort();
+        assert_eq!(shuffled, sorted);
+        assert!(sorted.windows(2).all(|w| w[0].inner < w[1].inner));
+    }
+
+    #[test]
+    fn tagged_bytes() {
+        let fp = Fingerprint::from(b"tagged");
+        let raw = fp.to_bytes();
+        assert_eq!(raw[0], 1);
+        assert_eq!(Fingerprint::from_bytes(&raw).unwrap(), fp);
+        let other = Fingerprint {
+            algorithm: FingerprintAlgorithm::Sha512_256,
+            inner: fp.inner,
+        };
+        assert_ne!(other, fp);
+        assert_eq!(Fingerprint::from_bytes(&other.to_bytes()).unwrap(), other);
+        assert_ne!(
+            Fingerprint::compute(FingerprintAlgorithm::Sha512_256, b"tagged").inner,
+            fp.inner
+        );
+        let mut unknown = raw;
+        unknown[0] = 0;
+        assert!(Fingerprint::from_bytes(&unknown).is_err());
+        assert!(Fingerprint::from_bytes(&raw[..32]).is_err());
+    }
+
+    #[test]
+    fn key_id_display() {
+        let id = KeyId {
+            inner: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+        };
+        assert_eq!(format!("{}", id), "0123456789abcdef");
+    }
+}
@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use ring::pbkdf2;
+
+use zeroize::Zeroizing;
+
+use crate::crypto::ParseError;
+
+/// Password based key derivation function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    /// PBKDF2 with HMAC-SHA256, only `iterations` is used
+    Pbkdf2HmacSha256 = 1,
+    /// Argon2id (RFC 9106)
+    Argon2id = 2,
+}
+
+/// Cost parameters for deriving a key from a passphrase.
+/// The parameters are stored along with the encrypted data, so decryption
+/// always uses the parameters used for encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    /// Memory in KiB, must be 0 for PBKDF2
+    pub memory: u32,
+    pub iterations: u32,
+    /// Number of lanes, must be 0 for PBKDF2
+    pub parallelism: u32,
+}
+
+/// Argon2id memory limit in KiB (1 GiB).
+/// Stored parameters are untrusted, decrypting must not take gigabytes
+/// of memory or minutes of CPU time.
+const MAX_MEMORY: u32 = 1024 * 1024;
+/// Argon2id iteration limit
+const MAX_ARGON2_ITERATIONS: u32 = 16;
+/// Argon2id lane limit
+const MAX_PARALLELISM: u32 = 16;
+/// PBKDF2 iteration limit
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+impl Default for KdfParams {
+    /// Argon2id with 64 MiB memory, suitable for production
+    fn default() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Cheap Argon2id parameters for tests and low-power devices
+    pub fn low() -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory: 64,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    /// Construct parameters from stored values and check they are plausible
+    pub fn from_raw(
+        algorithm: u8,
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> Result<Self, ParseError> {
+        let algorithm = match algorithm {
+            id if id == KdfAlgorithm::Pbkdf2HmacSha256 as u8 => KdfAlgorithm::Pbkdf2HmacSha256,
+            id if id == KdfAlgorithm::Argon2id as u8 => KdfAlgorithm::Argon2id,
+            id => {
+                return Err(ParseError::InvalidKdfParams(format!(
+                    "Unknown algorithm {}",
+                    id
+                )))
+            }
+        };
+        let params = Self {
+            algorithm,
+            memory,
+            iterations,
+            parallelism,
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    /// Reject parameters which are too weak or would exhaust resources
+    pub fn validate(&self) -> Result<(), ParseError> {
+        let valid = match self.algorithm {
+            KdfAlgorithm::Pbkdf2HmacSha256 => {
+                self.memory == 0
+                    && self.parallelism == 0
+                    && (1..=MAX_PBKDF2_ITERATIONS).contains(&self.iterations)
+            }
+            KdfAlgorithm::Argon2id => {
+                (1..=MAX_PARALLELISM).contains(&self.parallelism)
+                    && (8 * self.parallelism..=MAX_MEMORY).contains(&self.memory)
+                    && (1..=MAX_ARGON2_ITERATIONS).contains(&self.iterations)
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(ParseError::InvalidKdfParams(format!("{:?}", self)))
+        }
+    }
+
+    /// Derive a 32 byte key from `passphrase` and `salt`.
+    /// The salt must be at least 8 bytes.
+    pub fn derive(
+        &self,
+        passphrase: &[u8],
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, ParseError> {
+        self.validate()?;
+        let mut key = Zeroizing::new([0; 32]);
+        match self.algorithm {
+            KdfAlgorithm::Pbkdf2HmacSha256 => pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                std::num::NonZeroU32::new(self.iterations).unwrap(),
+                salt,
+                passphrase,
+                &mut key[..],
+            ),
+            KdfAlgorithm::Argon2id => {
+                let params =
+                    argon2::Params::new(self.memory, self.iterations, self.parallelism, Some(32))
+                        .map_err(|e| ParseError::InvalidKdfParams(format!("{}", e)))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase, salt, &mut key[..])
+                    .map_err(|e| ParseError::InvalidKdfParams(format!("{}", e)))?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_absurd_params() {
+        assert!(KdfParams::from_raw(2, 64, 1, 1).is_ok());
+        assert!(KdfParams::from_raw(1, 0, 1000, 0).is_ok());
+        // Unknown algorithm
+        assert!(KdfParams::from_raw(3, 64, 1, 1).is_err());
+        // Too much memory
+        assert!(KdfParams::from_raw(2, u32::MAX, 1, 1).is_err());
+        // No iterations
+        assert!(KdfParams::from_raw(2, 64, 0, 1).is_err());
+        assert!(KdfParams::from_raw(1, 0, 0, 0).is_err());
+        // Memory for PBKDF2
+        assert!(KdfParams::from_raw(1, 64, 1000, 0).is_err());
+    }
+
+    #[test]
+    fn argon2_limits() {
+        assert!(KdfParams::from_raw(2, 1024 * 1024, 1, 1).is_ok());
+        assert!(KdfParams::from_raw(2, 1024 * 1024 + 1, 1, 1).is_err());
+        assert!(KdfParams::from_raw(2, 4 * 1024 * 1024, 1, 1).is_err());
+        assert!(KdfParams::from_raw(2, 64, 16, 1).is_ok());
+        assert!(KdfParams::from_raw(2, 64, 17, 1).is_err());
+        assert!(KdfParams::from_raw(2, 64, 64, 1).is_err());
+        assert!(KdfParams::from_raw(2, 8 * 16, 1, 16).is_ok());
+        assert!(KdfParams::from_raw(2, 8 * 17, 1, 17).is_err());
+        // At least 8 KiB per lane
+        assert!(KdfParams::from_raw(2, 8 * 4 - 1, 1, 4).is_err());
+        let params = KdfParams {
+            iterations: 17,
+            ..KdfParams::low()
+        };
+        assert!(params.validate().is_err());
+        assert!(params.derive(b"passphrase", b"saltsalt").is_err());
+    }
+
+    #[test]
+    fn algorithms_derive_different_keys() {
+        let pbkdf2 = KdfParams::from_raw(1, 0, 1000, 0).unwrap();
+        let a = pbkdf2.derive(b"passphrase", b"saltsalt").unwrap();
+        let b = KdfParams::low().derive(b"passphrase", b"saltsalt").unwrap();
+        assert_ne!(*a, *b);
+        assert_eq!(
+            *b,
+            *KdfParams::low().derive(b"passphrase", b"saltsalt").unwrap()
+        );
+    }
+}
@@ -4,25 +4,27 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-use std::ops::Deref;
-
-use std::fmt;
-use std::io::Read;
 use std::io::Write;
 
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
-};
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
+use chrono::{DateTime, Utc};
+use yasna::{self, Tag};
 
-use crate::crypto::{SecretVariant, PublicVariant, CertVariant};
+use zeroize::Zeroizing;
 
+use crate::crypto::{PublicVariant, SecretVariant};
 
-use crate::crypto::SignatureBytes;
+use crate::crypto::attestation::{encode_claims, signed_claims};
+use crate::crypto::compress::Compression;
+use crate::crypto::manifest::signed_manifest;
+use crate::crypto::padding::{is_valid_block, pad, unpad};
+use crate::crypto::parse::parse_with_options;
+use crate::crypto::{
+    Attestation, AttestationError, Cert, DecryptError, EncryptError, KeyId, Manifest, ParseError,
+    ParseOptions, SignError, SignatureBytes, SignedManifest, Variant,
+};
+
+/// Default limit for the plaintext size in `Secret::encrypt` (64 MiB)
+pub const MAX_PLAINTEXT: usize = 64 * 1024 * 1024;
 
 
 /// Trait for public key information
@@ -37,7 +39,7 @@ pub trait Public {
     fn verify(&self, bytes: &dyn AsRef<[u8]>, signature: &SignatureBytes) -> bool;
 
     /// Returns the concrete variant reference
-    fn as_variant_ref(&self) -> PublicVariant;
+    fn as_variant_ref(&self) -> PublicVariant<'_>;
 }
 
 /// Trait for secret key information
@@ -45,23 +47,412 @@ pub trait Secret {
     /// Sign raw bytes and return the signature
     fn sign(&self, bytes: &dyn AsRef<[u8]>) -> SignatureBytes;
 
-    /// Decrypt raw bytes with this key and verify authenticity with `sender_pubkey`.
-    fn decrypt(&self, enc_bytes: &Encrypted, sender_pubkey: &dyn Public) -> Vec<u8>;
+    /// Like `sign`, but fails with `SignError::EmptyMessage` for
+    /// empty input, for protocols where an empty message is meaningless
+    fn sign_nonempty(&self, msg: &[u8]) -> std::result::Result<SignatureBytes, SignError> {
+        if msg.is_empty() {
+            return Err(SignError::EmptyMessage);
+        }
+        Ok(self.sign(&msg))
+    }
+
+    /// Sign a set of key-value claims, e.g. properties of a device.
+    /// The claims are encoded in canonical order, so the order of `claims`
+    /// does not change the result. Use `verify_attestation` to check it.
+    /// Fails if a key is used by more than one claim.
+    fn attest(
+        &self,
+        claims: &[(&str, &[u8])],
+    ) -> std::result::Result<Attestation, AttestationError> {
+        let claims = encode_claims(claims)?;
+        let signature = self.sign(&signed_claims(&claims));
+        Ok(Attestation {
+            claims,
+            signature: Vec::from(signature.as_bytes()),
+        })
+    }
+
+    /// Sign the file digests of `manifest`, e.g. for the artifacts of a
+    /// release. Use `verify_manifest` to check it.
+    fn sign_manifest(&self, manifest: &Manifest) -> SignedManifest {
+        let signature = self.sign(&signed_manifest(manifest));
+        SignedManifest {
+            manifest: manifest.clone(),
+            signature: Vec::from(signature.as_bytes()),
+        }
+    }
+
+    /// Decrypt raw bytes with this key.
+    /// The sender is not authenticated: anyone knowing our public key can
+    /// produce data which decrypts. Use `decrypt_and_verify` if the sender
+    /// has to be known.
+    fn decrypt(&self, enc_bytes: &Encrypted) -> std::result::Result<Vec<u8>, DecryptError>;
+
+    /// Like `decrypt`, but the plaintext is wiped from memory when it is
+    /// dropped. Use this for sensitive plaintext like unwrapped keys.
+    fn decrypt_zeroizing(
+        &self,
+        enc_bytes: &Encrypted,
+    ) -> std::result::Result<Zeroizing<Vec<u8>>, DecryptError> {
+        self.decrypt(enc_bytes).map(Zeroizing::new)
+    }
+
+    /// Decrypt raw bytes with this key and verify the signature of
+    /// `sender_pubkey` included in the plaintext.
+    /// The signature is removed from the returned plaintext.
+    fn decrypt_and_verify(
+        &self,
+        enc_bytes: &Encrypted,
+        sender_pubkey: &dyn Public,
+    ) -> std::result::Result<Vec<u8>, DecryptError>;
 
     /// Serialize the secret key into ASN.1
     /// The concrete format is up to the implementor.
     fn serialize(&self, stream: &mut dyn Write);
 
+    /// Returns the concrete variant reference
+    fn as_variant_ref(&self) -> SecretVariant<'_>;
+
+    /// Returns the canonical encoding of the public key for publishing.
+    /// The concrete format is up to the implementor.
+    fn public_bytes(&self) -> Vec<u8>;
+
     /// Encrypt and sign plaintext bytes
     /// Signing requires the secret key, so this is why encrypt() is not provided
     /// by the Public trait but by the Secret trait.
-    fn encrypt(&self, plain_bytes: &dyn AsRef<[u8]>, peer_public: &dyn Public) -> Encrypted;
+    /// Empty plaintext is allowed, the result then only consists of the
+    /// authentication tag and decrypts to an empty Vec.
+    /// Plaintext larger than `MAX_PLAINTEXT` is rejected, larger data
+    /// should be split up.
+    fn encrypt(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        peer_public: &dyn Public,
+    ) -> std::result::Result<Encrypted, EncryptError> {
+        self.encrypt_with_limit(plain_bytes, peer_public, MAX_PLAINTEXT)
+    }
+
+    /// Like `encrypt`, but for the subject of `cert`. Fails with
+    /// `EncryptError::CertNotValid` if `now` is outside the validity of
+    /// `cert`, the recipient should not use the key at that time.
+    /// The certificate signature has to be checked by the caller.
+    fn encrypt_to_cert(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        cert: &dyn Cert,
+        now: DateTime<Utc>,
+    ) -> std::result::Result<Encrypted, EncryptError> {
+        if !cert.is_valid_at(now) {
+            return Err(EncryptError::CertNotValid);
+        }
+        self.encrypt(plain_bytes, cert.public_key())
+    }
+
+    /// Like `encrypt`, but with a custom plaintext size limit of `max_len`
+    /// bytes. The whole plaintext is copied in memory during encryption.
+    fn encrypt_with_limit(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        peer_public: &dyn Public,
+        max_len: usize,
+    ) -> std::result::Result<Encrypted, EncryptError>;
+
+    /// Compress plaintext bytes with `compression`, then encrypt and sign them.
+    /// The chosen algorithm is recorded in `Encrypted::compression` and
+    /// authenticated with the data.
+    /// Warning: Compressing secret data together with attacker controlled data
+    /// leaks information about the secret through the ciphertext length
+    /// (see CRIME/BREACH). Only use this if the plaintext is not mixed.
+    fn encrypt_compressed(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        peer_public: &dyn Public,
+        compression: Compression,
+    ) -> std::result::Result<Encrypted, EncryptError>;
+
+    /// Decrypt data produced by `encrypt_compressed` and decompress it
+    /// with the algorithm recorded in the data.
+    fn decrypt_compressed(
+        &self,
+        enc_bytes: &Encrypted,
+    ) -> std::result::Result<Vec<u8>, DecryptError>;
+
+    /// Like `encrypt`, but pads the plaintext to a multiple of `block`
+    /// bytes first, so the ciphertext does not reveal the exact length.
+    /// The padding is encrypted and authenticated with the plaintext.
+    /// `block` must be between 1 and `MAX_PAD_BLOCK`.
+    fn encrypt_padded(
+        &self,
+        plain_bytes: &dyn AsRef<[u8]>,
+        peer_public: &dyn Public,
+        block: usize,
+    ) -> std::result::Result<Encrypted, EncryptError> {
+        if !is_valid_block(block) {
+            return Err(EncryptError::InvalidBlockSize(block));
+        }
+        self.encrypt(&pad(plain_bytes.as_ref(), block), peer_public)
+    }
+
+    /// Decrypt data produced by `encrypt_padded` with the same `block`
+    /// and strip the padding. Fails with `DecryptError::InvalidPadding`
+    /// if the padding is malformed.
+    fn decrypt_padded(
+        &self,
+        enc_bytes: &Encrypted,
+        block: usize,
+    ) -> std::result::Result<Vec<u8>, DecryptError> {
+        let padded = self.decrypt(enc_bytes)?;
+        unpad(&padded, block)
+            .map(Vec::from)
+            .ok_or(DecryptError::InvalidPadding)
+    }
 }
 
 
+/// Key derivation from the key agreement output to the AEAD key
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kdf {
+    /// PBKDF2-HMAC-SHA256 with a static salt, the original format.
+    /// Still the default, readers without HKDF support can not decrypt
+    /// `Hkdf` data. Moving to `Hkdf` is opt-in, with
+    /// `AlphaSecretBuilder::with_kdf` for new data and
+    /// `AlphaSecret::reencrypt_legacy` for existing data.
+    #[default]
+    Pbkdf2 = 0,
+    /// HKDF-SHA256
+    Hkdf = 1,
+}
+
+/// HKDF info of the default `EncryptContext`
+pub const DEFAULT_ENCRYPT_INFO: &[u8] = b"stackment alpha encryption";
+
+/// Domain separation between applications using the same keys.
+/// Data encrypted with a context only decrypts with an equal context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptContext {
+    /// HKDF info for deriving the AEAD key, not stored in the data.
+    /// Only `Kdf::Hkdf` takes an info, a custom info with `Kdf::Pbkdf2`
+    /// fails with `InfoNeedsHkdf`.
+    pub info: Vec<u8>,
+    /// Application id, stored in the data and authenticated.
+    /// 0 if not bound to an application.
+    pub app_id: u32,
+    /// Append a commitment to the derived key to the data.
+    /// ChaCha20-Poly1305 alone does not commit to the key, so data can be
+    /// crafted which decrypts under two different keys.
+    pub key_commitment: bool,
+    /// Compression of the plaintext, stored in the data and
+    /// authenticated. The data is only compressed by the caller.
+    pub compression: Compression,
+}
+
+impl Default for EncryptContext {
+    fn default() -> Self {
+        Self {
+            info: Vec::from(DEFAULT_ENCRYPT_INFO),
+            app_id: 0,
+            key_commitment: false,
+            compression: Compression::None,
+        }
+    }
+}
+
+impl EncryptContext {
+    /// Constructs a context with the HKDF `info` and no application id
+    pub fn new(info: &[u8]) -> Self {
+        Self {
+            info: Vec::from(info),
+            app_id: 0,
+            key_commitment: false,
+            compression: Compression::None,
+        }
+    }
+
+    /// Bind the context to the application `app_id`
+    pub fn with_app_id(mut self, app_id: u32) -> Self {
+        self.app_id = app_id;
+        self
+    }
+
+    /// Enable the key commitment, see `key_commitment`
+    pub fn with_key_commitment(mut self) -> Self {
+        self.key_commitment = true;
+        self
+    }
+
+    /// Record `compression` in the data, see `compression`
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+}
+
 /// Holds the encrypted data and peer's ephemeral public key.
 /// TODO: An ephemeral key is specific to the implemention of the alpha variant.
+///
+/// Equality and hashing cover the wire bytes, so identical blobs can be
+/// deduplicated. Because of the random ephemeral key, encrypting the same
+/// plaintext twice never gives equal values.
+///
+/// Without `recipient_key_id`, nothing in the data identifies the
+/// recipient: the ephemeral key is fresh for each message and the other
+/// fields do not depend on the recipient. Receivers find their data by
+/// trial decryption, see `AlphaSecret::decrypt_try`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Encrypted {
     pub ephemeral_pubkey: Vec<u8>,
     pub data: Vec<u8>,
+    /// Key id of the recipient, authenticated as additional data.
+    /// None for anonymous encryption.
+    pub recipient_key_id: Option<KeyId>,
+    /// KDF used by the sender to derive the AEAD key
+    pub kdf: Kdf,
+    /// Application the data was encrypted for, authenticated as
+    /// additional data. 0 if not bound to an application.
+    pub app_id: u32,
+    /// Length of the plaintext, authenticated as additional data so a
+    /// truncated frame is rejected. None for data of older versions.
+    pub plain_len: Option<u64>,
+    /// Variant which encrypted the data, see `Variant::id`. Secrets
+    /// reject data of other variants before any decryption.
+    pub variant_id: u8,
+    /// Compression of the plaintext, authenticated as additional data.
+    /// Decompressed by `Secret::decrypt_compressed`.
+    pub compression: Compression,
+}
+
+impl AsRef<[u8]> for Encrypted {
+    /// Returns the encrypted (still sealed) data, not the plaintext.
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+// Encrypted deliberately has no conversion into `Vec<u8>` or other byte
+// types, which could be mistaken for decryption. Plaintext is only
+// returned by `Secret::decrypt` and its variants.
+impl Encrypted {
+    /// Returns the sealed data including the authentication tag, e.g. to
+    /// store it next to the other fields. This is never the plaintext,
+    /// use `Secret::decrypt` for that.
+    pub fn ciphertext_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Serialize the encrypted data as ASN.1 to `stream`.
+    pub fn serialize(&self, stream: &mut dyn Write) {
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_bytes(&self.ephemeral_pubkey);
+                writer.next().write_bytes(&self.data);
+                if let Some(key_id) = &self.recipient_key_id {
+                    writer.next().write_bytes(&key_id.inner);
+                }
+                // Omitted for PBKDF2, so the original format stays unchanged
+                if self.kdf != Kdf::Pbkdf2 {
+                    writer.next().write_u8(self.kdf as u8);
+                }
+                if self.app_id != 0 {
+                    writer
+                        .next()
+                        .write_tagged(Tag::context(0), |writer| writer.write_u32(self.app_id));
+                }
+                if let Some(plain_len) = self.plain_len {
+                    writer
+                        .next()
+                        .write_tagged(Tag::context(1), |writer| writer.write_u64(plain_len));
+                }
+                // Omitted for alpha, which was the only variant before
+                if self.variant_id != Variant::Alpha.id() {
+                    writer
+                        .next()
+                        .write_tagged(Tag::context(2), |writer| writer.write_u8(self.variant_id));
+                }
+                if self.compression != Compression::None {
+                    writer.next().write_tagged(Tag::context(3), |writer| {
+                        writer.write_u8(self.compression as u8)
+                    });
+                }
+            });
+        });
+        stream.write_all(&raw_bytes).unwrap();
+    }
+
+    /// Restore encrypted data written by `serialize`.
+    pub fn deserialize(
+        bytes: &[u8],
+        options: ParseOptions,
+    ) -> std::result::Result<Self, ParseError> {
+        parse_with_options(bytes, options, |reader| {
+            reader.read_sequence(|reader| {
+                let ephemeral_pubkey = reader.next().read_bytes()?;
+                let data = reader.next().read_bytes()?;
+                let key_id = reader.read_optional(|reader| reader.read_bytes())?;
+                let kdf = reader.read_optional(|reader| reader.read_u8())?;
+                let app_id = reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(0), |reader| reader.read_u32())
+                })?;
+                let plain_len = reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(1), |reader| reader.read_u64())
+                })?;
+                let variant_id = reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(2), |reader| reader.read_u8())
+                })?;
+                let compression = reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(3), |reader| reader.read_u8())
+                })?;
+                Ok((
+                    ephemeral_pubkey,
+                    data,
+                    key_id,
+                    kdf,
+                    app_id,
+                    plain_len,
+                    variant_id,
+                    compression,
+                ))
+            })
+        })
+        .and_then(
+            |(ephemeral_pubkey, data, key_id, kdf, app_id, plain_len, variant_id, compression)| {
+                let recipient_key_id = match key_id {
+                    Some(raw) if raw.len() == 8 => {
+                        let mut inner = [0; 8];
+                        inner.copy_from_slice(&raw);
+                        Some(KeyId { inner })
+                    }
+                    Some(_) => return Err(ParseError::InvalidKey(String::from("Invalid key id"))),
+                    None => None,
+                };
+                let kdf = match kdf {
+                    None => Kdf::Pbkdf2,
+                    Some(id) if id == Kdf::Hkdf as u8 => Kdf::Hkdf,
+                    Some(id) => return Err(ParseError::InvalidKey(format!("Unknown KDF {}", id))),
+                };
+                let compression = match compression {
+                    None => Compression::None,
+                    Some(id) => Compression::from_id(id).ok_or_else(|| {
+                        ParseError::InvalidKey(format!("Unknown compression {}", id))
+                    })?,
+                };
+                Ok(Self {
+                    ephemeral_pubkey,
+                    data,
+                    recipient_key_id,
+                    kdf,
+                    app_id: app_id.unwrap_or(0),
+                    plain_len,
+                    variant_id: variant_id.unwrap_or_else(|| Variant::Alpha.id()),
+                    compression,
+                })
+            },
+        )
+    }
+
+    /// Returns false if the data is tagged for a different key than
+    /// `key_id`, so decryption can be skipped. Untagged data may be
+    /// for anyone.
+    pub fn is_addressed_to(&self, key_id: &KeyId) -> bool {
+        self.recipient_key_id.is_none_or(|tagged| tagged == *key_id)
+    }
 }
@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::crypto::alpha::AlphaSecret;
+use crate::crypto::{Error, Fingerprint, ParseOptions, Result, Secret, SecretVariant};
+
+/// Storage backend for secret keys, e.g. files, an OS keychain or a HSM.
+/// Secrets are addressed by the fingerprint of their public keys.
+pub trait KeyStore {
+    /// Load the secret with the public key fingerprint `fingerprint`
+    fn load(&self, fingerprint: &Fingerprint) -> Result<Box<dyn Secret>>;
+
+    /// Store `secret`, replacing any secret with the same fingerprint
+    fn store(&self, secret: SecretVariant<'_>) -> Result<()>;
+}
+
+/// Returns the public key fingerprint of a secret
+fn secret_fingerprint(secret: &SecretVariant<'_>) -> Fingerprint {
+    match secret {
+        SecretVariant::Alpha(s) => s.public_key().fingerprint(),
+    }
+}
+
+/// Key store keeping each secret in a file named by its fingerprint.
+/// The secrets are stored unencrypted, so the directory must be protected.
+pub struct FileKeyStore {
+    dir: PathBuf,
+}
+
+impl FileKeyStore {
+    /// Constructs a key store using the existing directory `dir`
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, fingerprint: &Fingerprint) -> PathBuf {
+        self.dir
+            .join(data_encoding::HEXLOWER.encode(&fingerprint.inner))
+            .with_extension("key")
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn load(&self, fingerprint: &Fingerprint) -> Result<Box<dyn Secret>> {
+        let raw = fs::read(self.path(fingerprint))?;
+        let secret = AlphaSecret::deserialize(&raw, ParseOptions::strict())?;
+        if secret.public_key().fingerprint() != *fingerprint {
+            return Err(Error::NotValid(format!(
+                "Stored key does not match fingerprint {}",
+                fingerprint
+            )));
+        }
+        Ok(Box::new(secret))
+    }
+
+    fn store(&self, secret: SecretVariant<'_>) -> Result<()> {
+        let mut raw = Vec::new();
+        match secret {
+            SecretVariant::Alpha(s) => s.serialize(&mut raw),
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(self.path(&secret_fingerprint(&secret)))?;
+        file.write_all(&raw)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Public;
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileKeyStore::new(dir.path().to_path_buf());
+        let secret = AlphaSecret::new();
+        let fingerprint = secret.public_key().fingerprint();
+        store.store(secret.as_variant_ref()).unwrap();
+
+        let loaded = store.load(&fingerprint).unwrap();
+        match loaded.as_variant_ref() {
+            SecretVariant::Alpha(s) => assert_eq!(s.public_key().fingerprint(), fingerprint),
+        }
+        let msg = b"signed by the loaded key";
+        assert!(secret.public_key().verify(&msg, &loaded.sign(&msg)));
+
+        let unknown = AlphaSecret::new().public_key().fingerprint();
+        assert!(store.load(&unknown).is_err());
+    }
+}
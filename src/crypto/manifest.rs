@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::Write;
+
+use ring::digest;
+
+use failure::Fail;
+
+use crate::crypto::parse::parse_with_options;
+use crate::crypto::{ParseError, ParseOptions, Public, SignatureBytes};
+
+/// Domain separation for manifest signatures
+const MANIFEST_LABEL: &[u8] = b"stackment manifest";
+
+/// Length of a SHA256 digest
+pub const DIGEST_LEN: usize = 32;
+
+/// Error type for manifest verification
+#[derive(Debug, Fail)]
+pub enum ManifestError {
+    #[fail(display = "Signature does not match")]
+    BadSignature,
+    #[fail(display = "Digest of {} does not match", _0)]
+    DigestMismatch(String),
+    #[fail(display = "File {} is not in the manifest", _0)]
+    UnknownFile(String),
+    #[fail(display = "File {} of the manifest is missing", _0)]
+    MissingFile(String),
+}
+
+/// SHA256 digests of a set of files, e.g. the artifacts of a release.
+/// Files are named by their path relative to the signed directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    entries: BTreeMap<String, [u8; DIGEST_LEN]>,
+}
+
+impl Manifest {
+    /// Constructs an empty manifest
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the file `path` with `contents`, replacing an earlier entry
+    pub fn add(&mut self, path: &str, contents: &[u8]) {
+        let mut digest = [0; DIGEST_LEN];
+        digest.copy_from_slice(digest::digest(&digest::SHA256, contents).as_ref());
+        self.entries.insert(String::from(path), digest);
+    }
+
+    /// Returns the digest of `path`
+    pub fn digest(&self, path: &str) -> Option<&[u8; DIGEST_LEN]> {
+        self.entries.get(path)
+    }
+
+    /// Returns all paths in sorted order
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Check `contents` of the file `path` against its digest
+    pub fn check_file(&self, path: &str, contents: &[u8]) -> Result<(), ManifestError> {
+        let expected = self
+            .digest(path)
+            .ok_or_else(|| ManifestError::UnknownFile(String::from(path)))?;
+        let digest = digest::digest(&digest::SHA256, contents);
+        if digest.as_ref() != &expected[..] {
+            return Err(ManifestError::DigestMismatch(String::from(path)));
+        }
+        Ok(())
+    }
+
+    /// Check that `files` are exactly the files of the manifest, each with
+    /// a matching digest
+    pub fn check_files(&self, files: &[(&str, &[u8])]) -> Result<(), ManifestError> {
+        for (path, contents) in files {
+            self.check_file(path, contents)?;
+        }
+        match self
+            .paths()
+            .find(|path| files.iter().all(|(file, _)| file != path))
+        {
+            Some(missing) => Err(ManifestError::MissingFile(String::from(missing))),
+            None => Ok(()),
+        }
+    }
+
+    /// Canonical DER encoding, entries are sorted by path
+    fn encode(&self) -> Vec<u8> {
+        yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                for (path, digest) in &self.entries {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_utf8_string(path);
+                        writer.next().write_bytes(digest);
+                    });
+                }
+            });
+        })
+    }
+
+    fn decode(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        let entries = parse_with_options(bytes, options, |reader| {
+            reader.collect_sequence_of(|reader| {
+                reader.read_sequence(|reader| {
+                    let path = reader.next().read_utf8string()?;
+                    let digest = reader.next().read_bytes()?;
+                    Ok((path, digest))
+                })
+            })
+        })?;
+        let mut manifest = Self::new();
+        for (path, raw) in entries {
+            let digest = <[u8; DIGEST_LEN]>::try_from(&raw[..])
+                .map_err(|_| ParseError::InvalidKey(format!("Invalid digest of {}", path)))?;
+            manifest.entries.insert(path, digest);
+        }
+        Ok(manifest)
+    }
+}
+
+/// Bytes covered by the signature of a manifest
+pub(crate) fn signed_manifest(manifest: &Manifest) -> Vec<u8> {
+    let mut signed = Vec::from(MANIFEST_LABEL);
+    signed.extend(manifest.encode());
+    signed
+}
+
+/// Manifest with a detached signature, see `Secret::sign_manifest`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedManifest {
+    pub manifest: Manifest,
+    pub signature: Vec<u8>,
+}
+
+impl SignedManifest {
+    /// Serialize the signed manifest as ASN.1 to `stream`
+    pub fn serialize(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_der(&self.manifest.encode());
+                writer.next().write_bytes(&self.signature);
+            });
+        });
+        stream.write_all(&raw_bytes)
+    }
+
+    /// Restore a signed manifest written by `serialize`.
+    /// The signature is only checked by `verify_manifest`.
+    pub fn deserialize(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        let (manifest, signature) = parse_with_options(bytes, options, |reader| {
+            reader.read_sequence(|reader| {
+                let manifest = reader.next().read_der()?;
+                let signature = reader.next().read_bytes()?;
+                Ok((manifest, signature))
+            })
+        })?;
+        Ok(Self {
+            manifest: Manifest::decode(&manifest, options)?,
+            signature,
+        })
+    }
+}
+
+/// Verify the signature of `signed` against `public` and return the
+/// manifest, whose files can then be checked with `Manifest::check_files`
+pub fn verify_manifest<'a>(
+    public: &dyn Public,
+    signed: &'a SignedManifest,
+) -> Result<&'a Manifest, ManifestError> {
+    let signature =
+        SignatureBytes::try_from(&signed.signature[..]).map_err(|_| ManifestError::BadSignature)?;
+    if !public.verify(&signed_manifest(&signed.manifest), &signature) {
+        return Err(ManifestError::BadSignature);
+    }
+    Ok(&signed.manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+    use crate::crypto::Secret;
+
+    fn release() -> Manifest {
+        let mut manifest = Manifest::new();
+        manifest.add("bin/tool", b"binary");
+        manifest.add("README", b"readme");
+        manifest
+    }
+
+    #[test]
+    fn verify_valid_manifest() {
+        let secret = AlphaSecret::new();
+        let signed = secret.sign_manifest(&release());
+        let mut raw = Vec::new();
+        signed.serialize(&mut raw).unwrap();
+        let restored = SignedManifest::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(restored, signed);
+
+        let manifest = verify_manifest(secret.public_key(), &restored).unwrap();
+        assert_eq!(manifest.paths().collect::<Vec<_>>(), ["README", "bin/tool"]);
+        manifest
+            .check_files(&[("README", b"readme"), ("bin/tool", b"binary")])
+            .unwrap();
+        let other = AlphaSecret::new();
+        assert!(verify_manifest(other.public_key(), &signed).is_err());
+    }
+
+    #[test]
+    fn tampered_digest() {
+        let secret = AlphaSecret::new();
+        let mut signed = secret.sign_manifest(&release());
+        match signed
+            .manifest
+            .check_files(&[("README", b"readme"), ("bin/tool", b"tampered")])
+        {
+            Err(ManifestError::DigestMismatch(path)) => assert_eq!(path, "bin/tool"),
+            _ => panic!("tampered file accepted"),
+        }
+        // Digest replaced after signing
+        signed.manifest.add("bin/tool", b"tampered");
+        match verify_manifest(secret.public_key(), &signed) {
+            Err(ManifestError::BadSignature) => {}
+            _ => panic!("tampered manifest accepted"),
+        }
+    }
+
+    #[test]
+    fn extra_and_missing_files() {
+        let manifest = release();
+        match manifest.check_files(&[("README", b"readme")]) {
+            Err(ManifestError::MissingFile(path)) => assert_eq!(path, "bin/tool"),
+            _ => panic!("missing file accepted"),
+        }
+        let files: [(&str, &[u8]); 3] = [
+            ("README", b"readme"),
+            ("bin/tool", b"binary"),
+            ("bin/extra", b"extra"),
+        ];
+        match manifest.check_files(&files) {
+            Err(ManifestError::UnknownFile(path)) => assert_eq!(path, "bin/extra"),
+            _ => panic!("extra file accepted"),
+        }
+    }
+}
@@ -4,37 +4,68 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
+#[macro_use]
+pub mod events;
+
 pub mod alpha;
 
+pub mod attestation;
+pub mod batch;
+pub mod cache;
+pub mod capabilities;
 pub mod cert;
+pub mod certstore;
+pub mod chain;
+pub mod clock;
+pub mod compress;
+pub mod convert;
 pub mod fingerprint;
+pub mod kdf;
 pub mod key;
+pub mod keystore;
+pub mod manifest;
+pub mod nonce;
+pub mod padding;
+pub mod parse;
+pub mod pem;
+pub mod ratchet;
+pub mod selftest;
+pub mod shared;
 pub mod sign;
-
+pub mod stream;
+pub mod symmetric;
+pub mod testvectors;
+pub mod tofu;
+pub mod truststore;
+
+pub use attestation::{verify_attestation, Attestation, AttestationError};
+pub use batch::{sign_batch, verify_batch_member, InclusionProof, MerkleRoot};
+pub use cache::ValidationCache;
+pub use capabilities::{capabilities, negotiate_variant, Capabilities, Transcript, Variant};
 pub use cert::Cert;
-pub use fingerprint::Fingerprint;
-pub use key::{Encrypted, Public, Secret};
-pub use sign::{validate_signature, SignatureBytes};
-
-use std::ops::Deref;
-
-use std::fmt;
-use std::io::Read;
-use std::io::Write;
-
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
+pub use certstore::{CertStore, FileCertStore, MemoryCertStore};
+pub use chain::{validate_chain_verbose, LinkResult};
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use compress::Compression;
+pub use fingerprint::{Fingerprint, FingerprintAlgorithm, KeyId};
+pub use kdf::{KdfAlgorithm, KdfParams};
+pub use key::{
+    EncryptContext, Encrypted, Kdf, Public, Secret, DEFAULT_ENCRYPT_INFO, MAX_PLAINTEXT,
 };
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
+pub use keystore::{FileKeyStore, KeyStore};
+pub use manifest::{verify_manifest, Manifest, ManifestError, SignedManifest};
+pub use nonce::{NonceExhausted, NonceSequence};
+pub use padding::MAX_PAD_BLOCK;
+pub use parse::{ParseError, ParseOptions};
+pub use ratchet::{Ratchet, RatchetError, RatchetMessage};
+pub use selftest::{self_test, SelfTestError};
+pub use shared::SharedSecret;
+pub use sign::{validate_signature, SignError, SignatureBytes, VerifyError};
+pub use stream::{DecryptReader, EncryptWriter, StreamError, StreamHeader};
+pub use symmetric::{SessionKey, SessionRole, SymmetricContext, SymmetricError};
+pub use tofu::{TofuStatus, TofuStore};
+pub use truststore::TrustStore;
 
-use log;
-use std::error::Error as StdError;
-use std::fs;
-use std::path::PathBuf;
 //use failure::Error;
 use failure::Fail;
 
@@ -49,6 +80,62 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Error type for encryption
+#[derive(Debug, Fail)]
+pub enum EncryptError {
+    #[fail(display = "Plaintext too large: {} bytes, limit is {}", _0, _1)]
+    TooLarge(usize, usize),
+    #[fail(display = "Encryption failed")]
+    Crypto,
+    #[fail(display = "Certificate is not valid at this time")]
+    CertNotValid,
+    #[fail(display = "Recipient {} is given more than once", _0)]
+    DuplicateRecipient(Fingerprint),
+    #[fail(display = "Invalid padding block size: {}", _0)]
+    InvalidBlockSize(usize),
+    #[fail(display = "A custom HKDF info requires Kdf::Hkdf")]
+    InfoNeedsHkdf,
+}
+
+impl From<EncryptError> for Error {
+    fn from(err: EncryptError) -> Error {
+        Error::General(format!("{}", err))
+    }
+}
+
+/// Error type for decryption
+#[derive(Debug, Fail)]
+pub enum DecryptError {
+    #[fail(display = "Invalid ephemeral key length: {}", _0)]
+    InvalidEphemeralKey(usize),
+    #[fail(display = "Decryption failed")]
+    Decryption,
+    #[fail(display = "Signature of the sender is invalid")]
+    BadSignature,
+    #[fail(display = "Data is for application {}", _0)]
+    AppMismatch(u32),
+    #[fail(display = "Key commitment does not match")]
+    KeyCommitment,
+    #[fail(display = "Data is encrypted by variant {}", _0)]
+    WrongVariant(u8),
+    #[fail(display = "Padding is malformed")]
+    InvalidPadding,
+    #[fail(display = "A custom HKDF info requires Kdf::Hkdf")]
+    InfoNeedsHkdf,
+    #[fail(display = "Data is compressed with {:?}", _0)]
+    CompressionMismatch(Compression),
+    #[fail(display = "Decompression failed")]
+    Decompression,
+    #[fail(display = "Decompressed data exceeds the limit of {} bytes", _0)]
+    DecompressedTooLarge(usize),
+}
+
+impl From<DecryptError> for Error {
+    fn from(err: DecryptError) -> Error {
+        Error::NotValid(format!("{}", err))
+    }
+}
+
 pub enum CertVariant<'a> {
     Alpha(&'a self::alpha::AlphaCert),
 }
@@ -61,6 +148,19 @@ pub enum PublicVariant<'a> {
     Alpha(&'a self::alpha::AlphaPublic),
 }
 
+impl PublicVariant<'_> {
+    /// Returns a fingerprint which is unique across all variants, see
+    /// `Fingerprint::for_variant`. It differs from the fingerprint of the
+    /// variant itself (e.g. `AlphaPublic::fingerprint`).
+    pub fn fingerprint(&self) -> Fingerprint {
+        match self {
+            PublicVariant::Alpha(public) => {
+                Fingerprint::for_variant(Variant::Alpha.name(), &public.to_bytes())
+            }
+        }
+    }
+}
+
 pub struct Trusted;
 pub struct Untrusted;
 
@@ -80,6 +180,19 @@ impl IdentCert<Untrusted> {
         }
     }
 
+    /// Parse a self-signed identity certificate written by
+    /// `Cert::serialize`. The self-signature is checked against the
+    /// embedded key, so tampered or not self-signed certificates fail with
+    /// `ParseError::BadSelfSignature`. Whether to trust the certificate is
+    /// still up to the caller, see `into_trusted`.
+    pub fn from_der(bytes: &[u8]) -> std::result::Result<Self, ParseError> {
+        let cert = alpha::AlphaCert::parse(bytes)?;
+        if !cert.is_valid(&cert) {
+            return Err(ParseError::BadSelfSignature);
+        }
+        Ok(Self::new(Box::new(cert)))
+    }
+
     /// Converts the untrusted certificate into a trusted one.
     /// For IdentCert, there are nor further checks because IdentCerts
     /// are the root certificates - you simply trust them or not.
@@ -137,6 +250,22 @@ impl DeviceCert<Untrusted> {
             unimplemented!();
         }
     }
+
+    /// Converts the untrusted certificate into a trusted one if it
+    /// validates against `issuer_cert`, else returns it unchanged.
+    pub fn try_into_trusted(
+        self,
+        issuer_cert: &dyn Cert,
+    ) -> std::result::Result<DeviceCert<Trusted>, Self> {
+        if self.is_valid(issuer_cert) {
+            Ok(DeviceCert::<Trusted> {
+                inner: self.inner,
+                phantom: std::marker::PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
 }
 
 impl std::ops::Deref for DeviceCert<Trusted> {
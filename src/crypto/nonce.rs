@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use failure::Fail;
+
+/// Length of the nonces of a `NonceSequence`
+pub const NONCE_LEN: usize = 12;
+
+/// Largest counter of a 96 bit nonce
+pub const MAX_COUNTER: u128 = (1 << 96) - 1;
+
+/// Error returned when a `NonceSequence` has used all its nonces.
+/// The key must be replaced before encrypting more messages.
+#[derive(Debug, Fail)]
+#[fail(display = "Nonce sequence exhausted, a new key is required")]
+pub struct NonceExhausted;
+
+/// Counter based nonces for one key.
+/// Each nonce is returned only once, when the counter would wrap around
+/// the sequence fails with `NonceExhausted` instead of reusing a nonce.
+pub struct NonceSequence {
+    next: Option<u128>,
+    max: u128,
+}
+
+impl Default for NonceSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NonceSequence {
+    /// Constructs a sequence over the full 96 bit counter, starting at zero
+    pub fn new() -> Self {
+        Self::with_max(MAX_COUNTER)
+    }
+
+    /// Constructs a sequence starting at zero and ending at `max`, for
+    /// formats using fewer bits of the nonce for the counter.
+    /// `max` is capped at `MAX_COUNTER`.
+    pub fn with_max(max: u128) -> Self {
+        Self {
+            next: Some(0),
+            max: max.min(MAX_COUNTER),
+        }
+    }
+
+    /// Continue the sequence at `counter`, e.g. for a restored session
+    pub fn starting_at(mut self, counter: u128) -> Self {
+        self.next = if counter <= self.max {
+            Some(counter)
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Counter of the next nonce, `None` if the sequence is exhausted
+    pub fn counter(&self) -> Option<u128> {
+        self.next
+    }
+
+    /// Returns the counter of the next nonce and advances the sequence
+    pub fn next_counter(&mut self) -> Result<u128, NonceExhausted> {
+        let counter = self.next.ok_or(NonceExhausted)?;
+        self.next = if counter < self.max {
+            Some(counter + 1)
+        } else {
+            None
+        };
+        Ok(counter)
+    }
+
+    /// Returns the next nonce, the counter as 96 bit big-endian integer
+    pub fn advance(&mut self) -> Result<[u8; NONCE_LEN], NonceExhausted> {
+        let counter = self.next_counter()?;
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&counter.to_be_bytes()[16 - NONCE_LEN..]);
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_up() {
+        let mut nonces = NonceSequence::new();
+        assert_eq!(nonces.advance().unwrap(), [0; NONCE_LEN]);
+        assert_eq!(nonces.advance().unwrap()[NONCE_LEN - 1], 1);
+        assert_eq!(nonces.counter(), Some(2));
+    }
+
+    #[test]
+    fn exhausted_past_maximum() {
+        let mut nonces = NonceSequence::new().starting_at(MAX_COUNTER - 1);
+        assert_eq!(nonces.advance().unwrap()[NONCE_LEN - 1], 0xfe);
+        assert_eq!(nonces.advance().unwrap(), [0xff; NONCE_LEN]);
+        assert!(nonces.advance().is_err());
+        assert!(nonces.advance().is_err());
+        assert_eq!(nonces.counter(), None);
+
+        let mut nonces = NonceSequence::with_max(1);
+        assert!(nonces.advance().is_ok());
+        assert!(nonces.advance().is_ok());
+        assert!(nonces.advance().is_err());
+        assert!(NonceSequence::with_max(1).starting_at(2).advance().is_err());
+    }
+}
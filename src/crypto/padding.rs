@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+/// Largest block size for `pad`, the padding length is stored in one byte
+pub const MAX_PAD_BLOCK: usize = 255;
+
+/// Returns true if `block` can be used with `pad`
+pub fn is_valid_block(block: usize) -> bool {
+    (1..=MAX_PAD_BLOCK).contains(&block)
+}
+
+/// Pad `plain_bytes` to a multiple of `block` bytes (PKCS#7).
+/// At least one byte is added, a plaintext of an exact multiple gets a
+/// full block of padding. `block` must be valid, see `is_valid_block`.
+pub fn pad(plain_bytes: &[u8], block: usize) -> Vec<u8> {
+    debug_assert!(is_valid_block(block));
+    let pad_len = block - plain_bytes.len() % block;
+    let mut out = Vec::with_capacity(plain_bytes.len() + pad_len);
+    out.extend_from_slice(plain_bytes);
+    out.resize(plain_bytes.len() + pad_len, pad_len as u8);
+    out
+}
+
+/// Strip the padding added by `pad` with the same `block`.
+/// Returns None if the padding is malformed. All padding bytes are
+/// checked, independent of where the first mismatch is.
+pub fn unpad(bytes: &[u8], block: usize) -> Option<&[u8]> {
+    if !is_valid_block(block) || bytes.is_empty() || !bytes.len().is_multiple_of(block) {
+        return None;
+    }
+    let pad_len = bytes[bytes.len() - 1];
+    let tail = &bytes[bytes.len() - block..];
+    let mut bad = (pad_len == 0) as u8 | (usize::from(pad_len) > block) as u8;
+    for (i, byte) in tail.iter().rev().enumerate() {
+        let in_padding = (i < usize::from(pad_len)) as u8;
+        bad |= in_padding & (*byte != pad_len) as u8;
+    }
+    if bad != 0 {
+        return None;
+    }
+    Some(&bytes[..bytes.len() - usize::from(pad_len)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_and_unpad() {
+        for len in 0..40 {
+            let plain = vec![0xa5; len];
+            let padded = pad(&plain, 16);
+            assert_eq!(padded.len() % 16, 0);
+            assert!(padded.len() > plain.len() && padded.len() <= plain.len() + 16);
+            assert_eq!(unpad(&padded, 16), Some(&plain[..]));
+        }
+        assert_eq!(pad(&[0; 16], 16).len(), 32);
+        assert_eq!(pad(b"abc", 1), b"abc\x01");
+        assert_eq!(pad(&[], MAX_PAD_BLOCK).len(), MAX_PAD_BLOCK);
+    }
+
+    #[test]
+    fn reject_malformed_padding() {
+        let padded = pad(b"abc", 8);
+        assert_eq!(unpad(&padded, 8), Some(&b"abc"[..]));
+        assert_eq!(unpad(&padded, 4), None);
+        assert_eq!(unpad(&padded[..7], 8), None);
+        assert_eq!(unpad(&[], 8), None);
+        assert_eq!(unpad(&padded, 0), None);
+        let mut bad = padded.clone();
+        bad[3] ^= 1;
+        assert_eq!(unpad(&bad, 8), None);
+        let mut bad = padded.clone();
+        bad[7] = 0;
+        assert_eq!(unpad(&bad, 8), None);
+        let mut bad = padded;
+        bad[7] = 9;
+        assert_eq!(unpad(&bad, 8), None);
+    }
+}
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use yasna::{self, BERMode, BERReader};
+
+use failure::Fail;
+
+/// Error type for parsing serialized data
+#[derive(Debug, Fail)]
+pub enum ParseError {
+    #[fail(display = "Invalid ASN.1 encoding: {:?}", _0)]
+    Asn1(yasna::ASN1Error),
+    #[fail(display = "Trailing data after serialized structure")]
+    TrailingData,
+    #[fail(display = "Invalid key material: {:?}", _0)]
+    InvalidKey(String),
+    #[fail(display = "Public key does not match the secret key")]
+    PublicKeyMismatch,
+    #[fail(display = "Invalid key derivation parameters: {}", _0)]
+    InvalidKdfParams(String),
+    #[fail(display = "Wrong passphrase or corrupted data")]
+    Decryption,
+    #[fail(display = "Certificate expires before it becomes valid")]
+    InvalidValidity,
+    #[fail(display = "Unsupported format version: {}", _0)]
+    UnsupportedVersion(i64),
+    #[fail(display = "Unknown file format identifier")]
+    BadMagic,
+    #[fail(display = "Checksum does not match, data is corrupted")]
+    ChecksumMismatch,
+    #[fail(display = "Invalid PEM encoding: {}", _0)]
+    Pem(String),
+    #[fail(display = "Self-signature of the certificate is invalid")]
+    BadSelfSignature,
+}
+
+impl From<yasna::ASN1Error> for ParseError {
+    fn from(err: yasna::ASN1Error) -> Self {
+        ParseError::Asn1(err)
+    }
+}
+
+impl From<ParseError> for super::Error {
+    fn from(err: ParseError) -> Self {
+        super::Error::NotValid(format!("{}", err))
+    }
+}
+
+/// Options for parsing serialized data.
+/// The default is strict parsing, lenient parsing should only be used
+/// for migrating legacy data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Ignore any bytes following the serialized structure
+    pub allow_trailing: bool,
+    /// Only accept canonical DER. If false, BER is accepted.
+    pub strict_der: bool,
+}
+
+impl ParseOptions {
+    /// Reject any deviation from canonical DER
+    pub fn strict() -> Self {
+        Self {
+            allow_trailing: false,
+            strict_der: true,
+        }
+    }
+
+    /// Accept trailing bytes and non-canonical BER
+    pub fn lenient() -> Self {
+        Self {
+            allow_trailing: true,
+            strict_der: false,
+        }
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self::strict()
+    }
+}
+
+/// Parse `buf` with `callback` according to `options`.
+pub fn parse_with_options<'a, T, F>(
+    buf: &'a [u8],
+    options: ParseOptions,
+    callback: F,
+) -> Result<T, ParseError>
+where
+    F: for<'b> FnOnce(BERReader<'a, 'b>) -> yasna::ASN1Result<T>,
+{
+    let mode = if options.strict_der {
+        BERMode::Der
+    } else {
+        BERMode::Ber
+    };
+    let buf = match element_len(buf) {
+        Some(len) if len < buf.len() => {
+            if options.allow_trailing {
+                &buf[..len]
+            } else {
+                return Err(ParseError::TrailingData);
+            }
+        }
+        _ => buf,
+    };
+    Ok(yasna::parse_ber_general(buf, mode, callback)?)
+}
+
+/// Returns the total length (header and content) of the first
+/// element in `buf`, if it has a definite length.
+fn element_len(buf: &[u8]) -> Option<usize> {
+    let first = *buf.get(1)?;
+    let (header, content) = if first & 0x80 == 0 {
+        (2, first as usize)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > std::mem::size_of::<usize>() {
+            return None; // indefinite or absurd length
+        }
+        let bytes = buf.get(2..2 + n)?;
+        let content = bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (2 + n, content)
+    };
+    header.checked_add(content)
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use crate::crypto::ParseError;
+
+/// PEM label for secrets from `Secret::serialize`
+pub const SECRET_KEY_LABEL: &str = "STACKMENT SECRET KEY";
+
+/// PEM label for public keys from `Secret::public_bytes`
+pub const PUBLIC_KEY_LABEL: &str = "STACKMENT PUBLIC KEY";
+
+/// Base64 characters per line, as in RFC 7468
+const LINE_LEN: usize = 64;
+
+/// Encode `bytes` as PEM with `label`, e.g. for storing keys in text files
+pub fn encode(label: &str, bytes: &[u8]) -> String {
+    let base64 = data_encoding::BASE64.encode(bytes);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in base64.as_bytes().chunks(LINE_LEN) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Decode the first PEM block with `label` in `text`.
+/// Text before and after the block is ignored.
+pub fn decode(label: &str, text: &str) -> Result<Vec<u8>, ParseError> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut lines = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != begin)
+        .skip(1);
+    let mut base64 = String::new();
+    loop {
+        match lines.next() {
+            Some(line) if line == end => break,
+            Some(line) => base64.push_str(line),
+            None => return Err(ParseError::Pem(format!("No {} block", label))),
+        }
+    }
+    data_encoding::BASE64
+        .decode(base64.as_bytes())
+        .map_err(|e| ParseError::Pem(format!("{}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let bytes: Vec<u8> = (0..100u8).collect();
+        let pem = encode(SECRET_KEY_LABEL, &bytes);
+        assert!(pem.starts_with("-----BEGIN STACKMENT SECRET KEY-----\n"));
+        assert!(pem.lines().all(|line| line.len() <= LINE_LEN));
+        let text = format!("comment\n{}\ntrailing", pem);
+        assert_eq!(decode(SECRET_KEY_LABEL, &text).unwrap(), bytes);
+        assert!(decode(PUBLIC_KEY_LABEL, &text).is_err());
+        let truncated = &pem[..pem.len() - 20];
+        assert!(decode(SECRET_KEY_LABEL, truncated).is_err());
+    }
+}
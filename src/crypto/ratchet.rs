@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::BTreeMap;
+
+use ring::aead;
+
+use failure::Fail;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::crypto::shared::chacha20_key;
+use crate::crypto::SharedSecret;
+
+/// Maximum number of skipped message keys kept for out-of-order delivery.
+/// If more keys are skipped, the oldest ones are dropped.
+pub const MAX_SKIPPED_KEYS: usize = 1000;
+
+/// Default for the maximum number of message keys skipped by a single
+/// message, see `Ratchet::with_max_skip`.
+pub const DEFAULT_MAX_SKIP: u64 = 1000;
+
+/// Error type for the symmetric ratchet
+#[derive(Debug, Fail)]
+pub enum RatchetError {
+    #[fail(display = "Message key for counter {} is not available", _0)]
+    KeyUnavailable(u64),
+    #[fail(display = "Decryption failed")]
+    Decryption,
+    #[fail(display = "Encryption failed")]
+    Encryption,
+    #[fail(display = "Message skips {} keys, more than allowed", _0)]
+    TooManySkipped(u64),
+}
+
+/// A message encrypted by the ratchet
+pub struct RatchetMessage {
+    /// Position of the message key in the chain
+    pub counter: u64,
+    /// Encrypted data including the authentication tag
+    pub data: Vec<u8>,
+}
+
+/// Symmetric ratchet for forward secure messages.
+/// Each message is encrypted with its own message key derived from a
+/// chain key, which is advanced after each message. Compromise of the
+/// current state does not reveal keys of earlier messages.
+/// A ratchet represents one direction, so each party needs one ratchet for
+/// sending and one for receiving, rooted at different shared secrets.
+/// The chain key and the skipped keys are zeroized when dropped.
+pub struct Ratchet {
+    chain_key: [u8; 32],
+    counter: u64,
+    skipped: BTreeMap<u64, Zeroizing<[u8; 32]>>,
+    max_skip: u64,
+}
+
+impl Zeroize for Ratchet {
+    /// Wipes the chain key and drops the skipped keys, which wipe
+    /// themselves
+    fn zeroize(&mut self) {
+        self.chain_key.zeroize();
+        self.skipped.clear();
+    }
+}
+
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl Ratchet {
+    /// Constructs a new ratchet rooted at `shared`
+    pub fn new(shared: SharedSecret) -> Self {
+        Self::from_chain_key(&shared.derive_subkey(b"stackment ratchet root", 32))
+    }
+
+    fn from_chain_key(chain_key: &[u8]) -> Self {
+        let mut root = [0; 32];
+        root.copy_from_slice(chain_key);
+        Self {
+            chain_key: root,
+            counter: 0,
+            skipped: BTreeMap::new(),
+            max_skip: DEFAULT_MAX_SKIP,
+        }
+    }
+
+    /// Limit the number of keys a single message may skip to `max_skip`.
+    /// Each skipped key has to be derived, so without a limit a message
+    /// with a huge counter costs unbounded time.
+    pub fn with_max_skip(mut self, max_skip: u64) -> Self {
+        self.max_skip = max_skip;
+        self
+    }
+
+    /// Derive the next message key and advance the chain key
+    fn advance(&mut self) -> Zeroizing<[u8; 32]> {
+        let chain = SharedSecret::from(self.chain_key);
+        let mut message_key = Zeroizing::new([0; 32]);
+        message_key.copy_from_slice(&chain.derive_subkey(b"message", 32));
+        self.chain_key
+            .copy_from_slice(&chain.derive_subkey(b"chain", 32));
+        self.counter += 1;
+        message_key
+    }
+
+    /// Encrypt `plain_bytes` with the next message key
+    pub fn encrypt_next(&mut self, plain_bytes: &[u8]) -> Result<RatchetMessage, RatchetError> {
+        let counter = self.counter;
+        let key = self.advance();
+        let mut in_out = Vec::from(plain_bytes);
+        chacha20_key(&key[..])
+            .and_then(|key| key.seal_in_place_append_tag(nonce(counter), aad(counter), &mut in_out))
+            .map_err(|_| RatchetError::Encryption)?;
+        Ok(RatchetMessage {
+            counter,
+            data: in_out,
+        })
+    }
+
+    /// Decrypt a message at position `counter` in the chain.
+    /// Messages may arrive out of order, the keys for skipped messages
+    /// are kept until they are used. Each key can only be used once, so
+    /// a replayed message is rejected.
+    /// Skipped keys are zeroized when they are used or dropped.
+    pub fn decrypt_at(&mut self, counter: u64, data: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        let key = if counter < self.counter {
+            self.skipped
+                .remove(&counter)
+                .ok_or(RatchetError::KeyUnavailable(counter))?
+        } else {
+            let gap = counter - self.counter;
+            if gap > self.max_skip {
+                return Err(RatchetError::TooManySkipped(gap));
+            }
+            while self.counter < counter {
+                let skipped_counter = self.counter;
+                let skipped_key = self.advance();
+                self.skipped.insert(skipped_counter, skipped_key);
+                if self.skipped.len() > MAX_SKIPPED_KEYS {
+                    let oldest = *self.skipped.keys().next().unwrap();
+                    self.skipped.remove(&oldest);
+                }
+            }
+            self.advance()
+        };
+        let mut in_out = Vec::from(data);
+        let opened = chacha20_key(&key[..])
+            .and_then(|key| key.open_in_place(nonce(counter), aad(counter), &mut in_out));
+        match opened {
+            Ok(plain) => Ok(Vec::from(plain)),
+            Err(_) => {
+                // Keep the key, a forged message must not block the real one
+                self.skipped.insert(counter, key);
+                Err(RatchetError::Decryption)
+            }
+        }
+    }
+}
+
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+fn aad(counter: u64) -> aead::Aad<[u8; 8]> {
+    aead::Aad::from(counter.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Ratchet, Ratchet) {
+        (
+            Ratchet::new(SharedSecret::from([0x42; 32])),
+            Ratchet::new(SharedSecret::from([0x42; 32])),
+        )
+    }
+
+    #[test]
+    fn zeroize_wipes_keys() {
+        let (mut sender, mut receiver) = pair();
+        sender.encrypt_next(b"skipped").unwrap();
+        let msg = sender.encrypt_next(b"advance the chain").unwrap();
+        receiver.decrypt_at(msg.counter, &msg.data).unwrap();
+        assert_ne!(receiver.chain_key, [0; 32]);
+        assert_eq!(receiver.skipped.len(), 1);
+        // Drop runs the same wipe
+        receiver.zeroize();
+        assert_eq!(receiver.chain_key, [0; 32]);
+        assert!(receiver.skipped.is_empty());
+    }
+
+    #[test]
+    fn in_order() {
+        let (mut sender, mut receiver) = pair();
+        for i in 0..10u8 {
+            let msg = sender.encrypt_next(&[i; 5]).unwrap();
+            assert_eq!(msg.counter, i as u64);
+            assert_eq!(
+                receiver.decrypt_at(msg.counter, &msg.data).unwrap(),
+                vec![i; 5]
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut sender, mut receiver) = pair();
+        let msgs: Vec<RatchetMessage> = (0..5u8)
+            .map(|i| sender.encrypt_next(&[i]).unwrap())
+            .collect();
+        for i in &[3usize, 0, 4, 1, 2] {
+            let msg = &msgs[*i];
+            assert_eq!(
+                receiver.decrypt_at(msg.counter, &msg.data).unwrap(),
+                vec![*i as u8]
+            );
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let (mut sender, mut receiver) = pair();
+        let first = sender.encrypt_next(b"first").unwrap();
+        let second = sender.encrypt_next(b"second").unwrap();
+        assert!(receiver.decrypt_at(second.counter, &second.data).is_ok());
+        assert!(receiver.decrypt_at(first.counter, &first.data).is_ok());
+        assert!(receiver.decrypt_at(first.counter, &first.data).is_err());
+        assert!(receiver.decrypt_at(second.counter, &second.data).is_err());
+    }
+
+    #[test]
+    fn forged_message_keeps_key() {
+        let (mut sender, mut receiver) = pair();
+        let msg = sender.encrypt_next(b"real").unwrap();
+        let mut forged = msg.data.clone();
+        forged[0] ^= 1;
+        assert!(receiver.decrypt_at(msg.counter, &forged).is_err());
+        assert_eq!(
+            receiver.decrypt_at(msg.counter, &msg.data).unwrap(),
+            b"real"
+        );
+    }
+
+    #[test]
+    fn skip_limit() {
+        let (mut sender, receiver) = pair();
+        let mut receiver = receiver.with_max_skip(10);
+        let msgs: Vec<RatchetMessage> = (0..30u8)
+            .map(|i| sender.encrypt_next(&[i]).unwrap())
+            .collect();
+        match receiver.decrypt_at(msgs[29].counter, &msgs[29].data) {
+            Err(RatchetError::TooManySkipped(29)) => {}
+            _ => panic!("large gap accepted"),
+        }
+        // Nothing was derived for the rejected message
+        assert_eq!(
+            receiver
+                .decrypt_at(msgs[10].counter, &msgs[10].data)
+                .unwrap(),
+            vec![10]
+        );
+        assert_eq!(
+            receiver.decrypt_at(msgs[3].counter, &msgs[3].data).unwrap(),
+            vec![3]
+        );
+        assert_eq!(
+            receiver
+                .decrypt_at(msgs[20].counter, &msgs[20].data)
+                .unwrap(),
+            vec![20]
+        );
+    }
+
+    #[test]
+    fn different_keys_per_message() {
+        let (mut sender, _) = pair();
+        let a = sender.encrypt_next(b"same").unwrap();
+        let b = sender.encrypt_next(b"same").unwrap();
+        assert_ne!(a.data, b.data);
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use ring::signature::{self, Ed25519KeyPair, UnparsedPublicKey};
+
+use failure::Fail;
+
+use crate::crypto::alpha::{derive_key, AlphaSecret};
+use crate::crypto::{Public, Secret};
+
+/// Error type for the self test
+#[derive(Debug, Fail)]
+pub enum SelfTestError {
+    #[fail(display = "Key generation failed")]
+    KeyGeneration,
+    #[fail(display = "Signature known answer test failed")]
+    Signature,
+    #[fail(display = "Signature verification failed")]
+    Verification,
+    #[fail(display = "Encryption round trip failed")]
+    Encryption,
+    #[fail(display = "KDF known answer test failed")]
+    Kdf,
+}
+
+// RFC 8032, 7.1 TEST 1
+const ED25519_SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+const ED25519_PUBKEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+const ED25519_SIGNATURE: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+
+// PBKDF2-HMAC-SHA256, salt [0], 1000 rounds over the bytes 0..96
+const KDF_OUTPUT: &str = "28faf56edd3eb89a290d2f7e452f4b697898ba699ad692f2e90c5d32a624c69c";
+
+fn hex(s: &str) -> Vec<u8> {
+    data_encoding::HEXLOWER.decode(s.as_bytes()).unwrap()
+}
+
+/// Run known answer tests for all crypto primitives used by this crate.
+/// A failure indicates a broken build or crypto backend, keys must not
+/// be used in this case.
+pub fn self_test() -> Result<(), SelfTestError> {
+    // Signature known answer test
+    let keypair =
+        Ed25519KeyPair::from_seed_and_public_key(&hex(ED25519_SEED), &hex(ED25519_PUBKEY))
+            .map_err(|_| SelfTestError::KeyGeneration)?;
+    let sig = keypair.sign(&[]);
+    if sig.as_ref() != hex(ED25519_SIGNATURE).as_slice() {
+        return Err(SelfTestError::Signature);
+    }
+    UnparsedPublicKey::new(&signature::ED25519, hex(ED25519_PUBKEY))
+        .verify(&[], sig.as_ref())
+        .map_err(|_| SelfTestError::Verification)?;
+
+    // Sign and encrypt round trip with a fresh key
+    let secret = AlphaSecret::new();
+    let data = b"stackment self test";
+    if !secret.public_key().verify(data, &secret.sign(data)) {
+        return Err(SelfTestError::Verification);
+    }
+    let crypted = secret
+        .encrypt(data, secret.public_key())
+        .map_err(|_| SelfTestError::Encryption)?;
+    if crypted.data.as_slice() == &data[..]
+        || secret
+            .decrypt(&crypted)
+            .map_err(|_| SelfTestError::Encryption)?
+            .as_slice()
+            != &data[..]
+    {
+        return Err(SelfTestError::Encryption);
+    }
+
+    // KDF known answer test
+    let kdf_input: Vec<u8> = (0..96).collect();
+    if derive_key(&[0], &kdf_input)[..] != hex(KDF_OUTPUT)[..] {
+        return Err(SelfTestError::Kdf);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_passes() {
+        assert!(self_test().is_ok());
+    }
+}
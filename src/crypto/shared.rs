@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use ring::{aead, hkdf};
+
+use zeroize::{Zeroize, Zeroizing};
+
+/// A secret agreed between two parties, e.g. by a static X25519
+/// key agreement. It should not be used as a key directly, instead
+/// derive a sub-key for each purpose with `derive_subkey`.
+/// The secret is zeroized when dropped.
+pub struct SharedSecret {
+    inner: [u8; 32],
+}
+
+/// Output length for HKDF-Expand
+struct OutLen(usize);
+
+impl hkdf::KeyType for OutLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+impl From<[u8; 32]> for SharedSecret {
+    fn from(inner: [u8; 32]) -> Self {
+        SharedSecret { inner }
+    }
+}
+
+impl Zeroize for SharedSecret {
+    fn zeroize(&mut self) {
+        self.inner.zeroize();
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl SharedSecret {
+    /// Derive a sub-key of `out_len` bytes for the purpose given by `label`.
+    /// The shared secret is run through HKDF-Extract (with an empty salt),
+    /// then HKDF-Expand with `label` as info, so different labels yield
+    /// independent keys.
+    /// The sub-key is zeroized when dropped.
+    /// Panics if `out_len` exceeds 255 * 32 bytes (the HKDF-SHA256 limit).
+    pub fn derive_subkey(&self, label: &[u8], out_len: usize) -> Zeroizing<Vec<u8>> {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&self.inner);
+        let mut out = Zeroizing::new(vec![0; out_len]);
+        prk.expand(&[label], OutLen(out_len))
+            .and_then(|okm| okm.fill(&mut out))
+            .expect("HKDF output too long");
+        out
+    }
+
+    /// Derive the key wrapping a content key for one recipient, bound to
+    /// the ephemeral public key of the sender and the public key of the
+    /// recipient. `domain` separates the formats using it.
+    pub(crate) fn wrapping_key(
+        &self,
+        domain: &[u8],
+        ephemeral_pubkey: &[u8; 32],
+        recipient_pubkey: &[u8],
+    ) -> Result<aead::LessSafeKey, ring::error::Unspecified> {
+        let mut label = Vec::from(domain);
+        label.extend(ephemeral_pubkey);
+        label.extend(recipient_pubkey);
+        chacha20_key(&self.derive_subkey(&label, 32))
+    }
+}
+
+/// ChaCha20-Poly1305 key from raw bytes, fails unless `key` is 32 bytes
+pub(crate) fn chacha20_key(key: &[u8]) -> Result<aead::LessSafeKey, ring::error::Unspecified> {
+    aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).map(aead::LessSafeKey::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_labeled_subkeys() {
+        let shared = SharedSecret::from([0x42; 32]);
+        let enc = shared.derive_subkey(b"encryption", 32);
+        let mac = shared.derive_subkey(b"mac", 32);
+        assert_ne!(enc, mac);
+        assert_eq!(enc, shared.derive_subkey(b"encryption", 32));
+        assert_eq!(shared.derive_subkey(b"mac", 64).len(), 64);
+        let other = SharedSecret::from([0x43; 32]);
+        assert_ne!(enc, other.derive_subkey(b"encryption", 32));
+    }
+
+    #[test]
+    fn zeroize_wipes_secret() {
+        let mut shared = SharedSecret::from([0x42; 32]);
+        // Drop runs the same wipe
+        shared.zeroize();
+        assert_eq!(shared.inner, [0; 32]);
+    }
+
+    #[test]
+    fn wrapping_key_bound_to_domain_and_keys() {
+        let shared = SharedSecret::from([0x42; 32]);
+        let seal = |domain: &[u8], ephemeral: [u8; 32], recipient: [u8; 32]| {
+            let mut in_out = Vec::from(&b"content key"[..]);
+            shared
+                .wrapping_key(domain, &ephemeral, &recipient)
+                .unwrap()
+                .seal_in_place_append_tag(
+                    aead::Nonce::assume_unique_for_key([0; 12]),
+                    aead::Aad::empty(),
+                    &mut in_out,
+                )
+                .unwrap();
+            in_out
+        };
+        let wrapped = seal(b"domain", [1; 32], [2; 32]);
+        assert_eq!(wrapped, seal(b"domain", [1; 32], [2; 32]));
+        assert_ne!(wrapped, seal(b"other domain", [1; 32], [2; 32]));
+        assert_ne!(wrapped, seal(b"domain", [3; 32], [2; 32]));
+        assert_ne!(wrapped, seal(b"domain", [1; 32], [3; 32]));
+        assert!(chacha20_key(&[0; 16]).is_err());
+    }
+}
@@ -4,35 +4,90 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-use std::ops::Deref;
+use std::convert::TryFrom;
 
-use std::fmt;
-use std::io::Read;
-use std::io::Write;
+use ring::digest;
+use ring::signature::{self, Signature, UnparsedPublicKey};
 
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
-};
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+
+use failure::Fail;
+
+/// Length of an ED25519 signature
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Length of an ED25519 public key
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Length of the length prefix of a framed message, see `frame`
+pub const FRAME_PREFIX_LEN: usize = 4;
+
+/// Error type for signature verification
+#[derive(Debug, Fail)]
+pub enum VerifyError {
+    #[fail(display = "Invalid signature length: {}", _0)]
+    InvalidLength(usize),
+    #[fail(display = "Signature does not match")]
+    BadSignature,
+    #[fail(display = "Key is not a signing key")]
+    KeyUsage,
+    #[fail(display = "Message too long: {} bytes, limit is {}", _0, _1)]
+    TooLong(usize, usize),
+    #[fail(display = "Malformed public key of length {}", _0)]
+    MalformedEmbeddedKey(usize),
+    #[fail(display = "Certificate is issued by another issuer")]
+    WrongIssuer,
+    #[fail(display = "Length prefix does not match frame of {} bytes", _0)]
+    InvalidFrame(usize),
+    #[fail(display = "Signature or public key is not canonical")]
+    NonCanonical,
+    #[fail(display = "Public key is not a curve point")]
+    InvalidKey,
+    #[fail(display = "Certificate is not valid ASN.1")]
+    MalformedCert,
+}
+
+/// Error type for signing
+#[derive(Debug, Fail)]
+pub enum SignError {
+    #[fail(display = "Refusing to sign an empty message")]
+    EmptyMessage,
+}
 
 /// Holds the raw signature data
 pub struct SignatureBytes {
     inner: Vec<u8>,
 }
 
-impl<T: AsRef<[u8]>> From<&T> for SignatureBytes {
-    fn from(bytes: &T) -> Self {
+impl From<&Signature> for SignatureBytes {
+    fn from(signature: &Signature) -> Self {
         SignatureBytes {
-            inner: Vec::from(bytes.as_ref()),
+            inner: Vec::from(signature.as_ref()),
+        }
+    }
+}
+
+/// Checks that `bytes` has the length of an ED25519 signature
+impl std::convert::TryFrom<&[u8]> for SignatureBytes {
+    type Error = VerifyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, VerifyError> {
+        if bytes.len() != SIGNATURE_LEN {
+            return Err(VerifyError::InvalidLength(bytes.len()));
         }
+        Ok(SignatureBytes {
+            inner: Vec::from(bytes),
+        })
     }
 }
 
-impl SignatureBytes {}
+impl SignatureBytes {
+    /// Returns the raw signature bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner
+    }
+}
 
 impl AsRef<[u8]> for SignatureBytes {
     fn as_ref(&self) -> &[u8] {
@@ -41,14 +96,156 @@ impl AsRef<[u8]> for SignatureBytes {
 }
 
 /// Validate a sigature against a given public key and message.
-pub fn validate_signature<T>(
-    public_key: &T,
-    message: &T,
-    signature: &T,
-) -> Result<(), ring::error::Unspecified>
+/// A public key of the wrong length, e.g. from a corrupt certificate,
+/// is reported as `VerifyError::MalformedEmbeddedKey`, not as a bad
+/// signature.
+pub fn validate_signature<T>(public_key: &T, message: &T, signature: &T) -> Result<(), VerifyError>
 where
     T: AsRef<[u8]> + ?Sized,
 {
-    let public_key = UnparsedPublicKey::new(&signature::ED25519, &public_key);
-    public_key.verify(message.as_ref(), signature.as_ref())
+    let raw_key = public_key.as_ref();
+    if raw_key.len() != PUBLIC_KEY_LEN {
+        return Err(VerifyError::MalformedEmbeddedKey(raw_key.len()));
+    }
+    let signature = signature.as_ref();
+    if signature.len() != SIGNATURE_LEN {
+        return Err(VerifyError::InvalidLength(signature.len()));
+    }
+    let public_key = UnparsedPublicKey::new(&signature::ED25519, raw_key);
+    public_key
+        .verify(message.as_ref(), signature)
+        .map_err(|_| VerifyError::BadSignature)
+}
+
+/// Returns true if `encoded` is the canonical encoding of a point, which
+/// is not of small order
+fn is_canonical_point(encoded: &[u8]) -> bool {
+    let mut raw = [0; 32];
+    raw.copy_from_slice(encoded);
+    let compressed = CompressedEdwardsY(raw);
+    compressed
+        .decompress()
+        .is_some_and(|point| point.compress() == compressed && !point.is_small_order())
+}
+
+/// Checks rejecting malleable signatures, see `AlphaPublic::verify_strict`:
+/// `s` must be reduced modulo the group order, `R` and `public_key` must
+/// be canonically encoded points not of small order.
+/// The signature itself is not verified.
+pub(crate) fn check_canonical(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    signature: &SignatureBytes,
+) -> Result<(), VerifyError> {
+    let (r, s) = signature.as_bytes().split_at(32);
+    let mut raw_s = [0; 32];
+    raw_s.copy_from_slice(s);
+    if Scalar::from_canonical_bytes(raw_s).is_none()
+        || !is_canonical_point(r)
+        || !is_canonical_point(public_key)
+    {
+        return Err(VerifyError::NonCanonical);
+    }
+    Ok(())
+}
+
+/// ED25519 public key decompressed once, for verifying many signatures
+/// without parsing the key again, see `AlphaPublic::verifier`
+#[derive(Clone, Copy)]
+pub(crate) struct PreparedKey {
+    encoded: [u8; PUBLIC_KEY_LEN],
+    /// The negated key, so verifying is one double scalar multiplication
+    minus_a: EdwardsPoint,
+}
+
+impl PreparedKey {
+    /// Returns None if `encoded` is not a point on the curve
+    pub(crate) fn new(encoded: [u8; PUBLIC_KEY_LEN]) -> Option<Self> {
+        let point = CompressedEdwardsY(encoded).decompress()?;
+        Some(Self {
+            encoded,
+            minus_a: -point,
+        })
+    }
+
+    /// Checks the same equation as ring: `R == [s]B - [k]A` with
+    /// `k = SHA512(R || A || msg)`, compared in encoded form. `s` must be
+    /// reduced modulo the group order.
+    pub(crate) fn verify(&self, msg: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+        let (r, s) = signature.split_at(32);
+        let mut raw_s = [0; 32];
+        raw_s.copy_from_slice(s);
+        let s = match Scalar::from_canonical_bytes(raw_s) {
+            Some(s) => s,
+            None => return false,
+        };
+        let mut ctx = digest::Context::new(&digest::SHA512);
+        ctx.update(r);
+        ctx.update(&self.encoded);
+        ctx.update(msg);
+        let mut wide = [0; 64];
+        wide.copy_from_slice(ctx.finish().as_ref());
+        let k = Scalar::from_bytes_mod_order_wide(&wide);
+        let computed = EdwardsPoint::vartime_double_scalar_mul_basepoint(&k, &self.minus_a, &s);
+        computed.compress().as_bytes()[..] == *r
+    }
+}
+
+/// Prefix `payload` with its length as u32 big-endian, for signing
+/// messages of framed protocols, see `AlphaPublic::verify_framed`.
+/// Panics if `payload` is longer than `u32::MAX` bytes.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(payload.len()).expect("Payload too long for frame");
+    let mut framed = Vec::with_capacity(FRAME_PREFIX_LEN + payload.len());
+    framed.extend(&len.to_be_bytes());
+    framed.extend(payload);
+    framed
+}
+
+/// Returns the payload of a message from `frame`, the length prefix must
+/// match the rest of the frame exactly
+pub(crate) fn unframe(framed: &[u8]) -> Result<&[u8], VerifyError> {
+    if framed.len() < FRAME_PREFIX_LEN {
+        return Err(VerifyError::InvalidFrame(framed.len()));
+    }
+    let mut len = [0; FRAME_PREFIX_LEN];
+    len.copy_from_slice(&framed[..FRAME_PREFIX_LEN]);
+    let payload = &framed[FRAME_PREFIX_LEN..];
+    if u32::from_be_bytes(len) as usize != payload.len() {
+        return Err(VerifyError::InvalidFrame(framed.len()));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+    use crate::crypto::{Public, Secret};
+
+    #[test]
+    fn signature_from_slice() {
+        let raw = [0x42; SIGNATURE_LEN];
+        let signature = SignatureBytes::try_from(&raw[..]).unwrap();
+        assert_eq!(signature.as_bytes(), &raw[..]);
+        match SignatureBytes::try_from(&raw[..SIGNATURE_LEN - 1]) {
+            Err(VerifyError::InvalidLength(63)) => {}
+            _ => panic!("short signature accepted"),
+        }
+        let long = [0x42; SIGNATURE_LEN + 1];
+        assert!(SignatureBytes::try_from(&long[..]).is_err());
+        assert!(SignatureBytes::try_from(&[][..]).is_err());
+    }
+
+    #[test]
+    fn sign_empty_message() {
+        let isec = AlphaSecret::new();
+        match isec.sign_nonempty(b"") {
+            Err(SignError::EmptyMessage) => {}
+            _ => panic!("empty message signed"),
+        }
+        let signature = isec.sign(b"");
+        assert!(isec.public_key().verify(b"", &signature));
+        let signature = isec.sign_nonempty(b"content").unwrap();
+        assert!(isec.public_key().verify(b"content", &signature));
+    }
 }
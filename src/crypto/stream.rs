@@ -0,0 +1,641 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Streaming encryption format.
+//! A stream starts with a fixed header, followed by the recipient block
+//! and the encrypted chunks.
+//!
+//! Header layout:
+//! - magic (4 bytes, `STREAM_MAGIC`)
+//! - version (1 byte)
+//! - algorithm id (1 byte)
+//! - chunk size (u32, little-endian)
+//! - ephemeral public key (32 bytes)
+//!
+//! Recipient block:
+//! - number of recipients (u32, little-endian)
+//! - the content key wrapped for each recipient (`WRAPPED_KEY_LEN` bytes)
+//!
+//! The chunks are sealed with the content key. The first chunk
+//! authenticates the header and the recipient block as additional data,
+//! so recipients or slots can not be added, removed or changed.
+//! All chunks except the last
+//! one hold exactly `chunk_size` bytes of plaintext, the last one is
+//! shorter (possibly empty) and flagged in its nonce, so truncation of the
+//! stream is detected. Data appended to the stream is read as part of the
+//! last chunk, which then fails to decrypt.
+
+use std::io::Read;
+use std::io::Write;
+
+use ring::aead;
+
+use rand_core::{OsRng, RngCore};
+
+use x25519_dalek as x25519;
+
+use zeroize::Zeroizing;
+
+use failure::{Compat, Fail};
+
+use crate::crypto::alpha::{AlphaPublic, AlphaSecret};
+use crate::crypto::nonce::{NonceExhausted, NonceSequence};
+use crate::crypto::shared::chacha20_key;
+use crate::crypto::{Public, SharedSecret};
+
+/// Magic bytes at the start of each stream
+pub const STREAM_MAGIC: [u8; 4] = *b"stmS";
+
+/// Current version of the stream format
+/// Version 2 authenticates the recipient block with the first chunk.
+pub const STREAM_VERSION: u8 = 2;
+
+/// Largest chunk size accepted when reading a header
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Chunk size used for encryption
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Largest number of recipients of a stream
+pub const MAX_RECIPIENTS: u32 = 1024;
+
+/// Length of a content key wrapped for one recipient (key and tag)
+pub const WRAPPED_KEY_LEN: usize = 32 + 16;
+
+/// Error type for the streaming format
+#[derive(Debug, Fail)]
+pub enum StreamError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(std::io::Error),
+    #[fail(display = "Not a stream, invalid magic")]
+    InvalidMagic,
+    #[fail(display = "Unsupported stream version: {}", _0)]
+    UnsupportedVersion(u8),
+    #[fail(display = "Unknown stream algorithm: {}", _0)]
+    UnknownAlgorithm(u8),
+    #[fail(display = "Invalid chunk size: {}", _0)]
+    InvalidChunkSize(u32),
+    #[fail(display = "Invalid number of recipients: {}", _0)]
+    InvalidRecipients(u32),
+    #[fail(display = "Stream is not encrypted to this key")]
+    NotARecipient,
+    #[fail(display = "Chunk {} is corrupted or truncated", _0)]
+    Decryption(u64),
+    #[fail(display = "Encryption failed")]
+    Encryption,
+    #[fail(display = "Too many chunks, nonces exhausted")]
+    NonceExhausted,
+}
+
+impl From<std::io::Error> for StreamError {
+    /// Stream errors passed through `std::io`, e.g. by `DecryptReader`,
+    /// are unwrapped again
+    fn from(err: std::io::Error) -> StreamError {
+        match err.downcast::<Compat<StreamError>>() {
+            Ok(err) => err.into_inner(),
+            Err(err) => StreamError::Io(err),
+        }
+    }
+}
+
+impl From<NonceExhausted> for StreamError {
+    fn from(_: NonceExhausted) -> StreamError {
+        StreamError::NonceExhausted
+    }
+}
+
+/// Algorithm used to encrypt the chunks of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamAlgorithm {
+    /// X25519 key agreement with ChaCha20-Poly1305 chunks
+    Alpha = 1,
+}
+
+/// Header at the start of an encrypted stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamHeader {
+    pub algorithm: StreamAlgorithm,
+    /// Size of the plaintext in each chunk, except for the last one
+    pub chunk_size: u32,
+    pub ephemeral_pubkey: [u8; 32],
+}
+
+impl StreamHeader {
+    /// Length of the encoded header
+    pub const LEN: usize = 4 + 1 + 1 + 4 + 32;
+
+    /// Write the header to `stream`
+    pub fn write(&self, stream: &mut dyn Write) -> Result<(), StreamError> {
+        stream.write_all(&STREAM_MAGIC)?;
+        stream.write_all(&[STREAM_VERSION, self.algorithm as u8])?;
+        stream.write_all(&self.chunk_size.to_le_bytes())?;
+        stream.write_all(&self.ephemeral_pubkey)?;
+        Ok(())
+    }
+
+    /// Read and check a header from `stream`.
+    /// Chunk sizes of zero or above `MAX_CHUNK_SIZE` are rejected, so the
+    /// reader can safely allocate its chunk buffers from the header.
+    pub fn read(stream: &mut dyn Read) -> Result<Self, StreamError> {
+        let mut raw = [0; Self::LEN];
+        stream.read_exact(&mut raw)?;
+        if raw[0..4] != STREAM_MAGIC {
+            return Err(StreamError::InvalidMagic);
+        }
+        if raw[4] != STREAM_VERSION {
+            return Err(StreamError::UnsupportedVersion(raw[4]));
+        }
+        let algorithm = match raw[5] {
+            id if id == StreamAlgorithm::Alpha as u8 => StreamAlgorithm::Alpha,
+            id => return Err(StreamError::UnknownAlgorithm(id)),
+        };
+        let mut chunk_size = [0; 4];
+        chunk_size.copy_from_slice(&raw[6..10]);
+        let chunk_size = u32::from_le_bytes(chunk_size);
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(StreamError::InvalidChunkSize(chunk_size));
+        }
+        let mut ephemeral_pubkey = [0; 32];
+        ephemeral_pubkey.copy_from_slice(&raw[10..]);
+        Ok(Self {
+            algorithm,
+            chunk_size,
+            ephemeral_pubkey,
+        })
+    }
+}
+
+/// HKDF label prefix of the key wrapping the content key
+const STREAM_WRAP_DOMAIN: &[u8] = b"stackment stream wrap";
+
+fn content_key(key: &[u8]) -> Result<aead::LessSafeKey, StreamError> {
+    chacha20_key(key).map_err(|_| StreamError::Encryption)
+}
+
+/// Nonces of the chunks of one stream, the chunk counter fills the
+/// lower 64 bits of the nonce
+fn chunk_nonces() -> NonceSequence {
+    NonceSequence::with_max(u128::from(u64::MAX))
+}
+
+/// Nonce for chunk `counter`, the first byte flags the final chunk
+fn chunk_nonce(counter: u64, last: bool) -> aead::Nonce {
+    let mut nonce = [0; 12];
+    nonce[0] = last as u8;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+/// Read until `buf` is full or the end of the stream, returns the length read
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, StreamError> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(StreamError::Io(e)),
+        }
+    }
+    Ok(len)
+}
+
+/// Header and recipient block of a stream with the content key.
+/// `raw` holds the encoded header and recipient block, authenticated
+/// with the first chunk.
+struct Preamble {
+    header: StreamHeader,
+    key: aead::LessSafeKey,
+    raw: Vec<u8>,
+}
+
+/// Write the header and the recipient block
+fn write_preamble(
+    recipients: &[&AlphaPublic],
+    writer: &mut dyn Write,
+) -> Result<Preamble, StreamError> {
+    let count = recipients.len() as u32;
+    if recipients.is_empty() || count > MAX_RECIPIENTS {
+        return Err(StreamError::InvalidRecipients(count));
+    }
+    let ephemeral_secret = x25519::StaticSecret::new(&mut OsRng);
+    let header = StreamHeader {
+        algorithm: StreamAlgorithm::Alpha,
+        chunk_size: DEFAULT_CHUNK_SIZE,
+        ephemeral_pubkey: *x25519::PublicKey::from(&ephemeral_secret).as_bytes(),
+    };
+    let mut raw_header = Vec::new();
+    header.write(&mut raw_header)?;
+    let mut raw = raw_header.clone();
+    raw.extend(&count.to_le_bytes());
+
+    let mut key = Zeroizing::new([0; 32]);
+    OsRng.fill_bytes(&mut key[..]);
+    for recipient in recipients {
+        let peer = *recipient.encryption_public_key_array();
+        let shared = ephemeral_secret.diffie_hellman(&x25519::PublicKey::from(peer));
+        let mut wrapped = Vec::from(&key[..]);
+        SharedSecret::from(*shared.as_bytes())
+            .wrapping_key(STREAM_WRAP_DOMAIN, &header.ephemeral_pubkey, &peer)
+            .map_err(|_| StreamError::Encryption)?
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key([0; 12]),
+                aead::Aad::from(&raw_header),
+                &mut wrapped,
+            )
+            .map_err(|_| StreamError::Encryption)?;
+        raw.extend(&wrapped);
+    }
+    writer.write_all(&raw)?;
+    Ok(Preamble {
+        header,
+        key: content_key(&key[..])?,
+        raw,
+    })
+}
+
+/// Read the header and the recipient block, fails if the stream is not
+/// encrypted to `secret`
+fn read_preamble(secret: &AlphaSecret, reader: &mut dyn Read) -> Result<Preamble, StreamError> {
+    let header = StreamHeader::read(reader)?;
+    let mut raw_header = Vec::new();
+    header.write(&mut raw_header)?;
+    let mut count = [0; 4];
+    reader.read_exact(&mut count)?;
+    let count = u32::from_le_bytes(count);
+    if count == 0 || count > MAX_RECIPIENTS {
+        return Err(StreamError::InvalidRecipients(count));
+    }
+    let mut raw = raw_header.clone();
+    raw.extend(&count.to_le_bytes());
+
+    let wrapping = secret
+        .agree_raw(header.ephemeral_pubkey)
+        .wrapping_key(
+            STREAM_WRAP_DOMAIN,
+            &header.ephemeral_pubkey,
+            secret.public_key().encryption_public_key(),
+        )
+        .map_err(|_| StreamError::Encryption)?;
+    let mut key = None;
+    for _ in 0..count {
+        let mut wrapped = Zeroizing::new([0; WRAPPED_KEY_LEN]);
+        reader.read_exact(&mut wrapped[..])?;
+        raw.extend(&wrapped[..]);
+        if key.is_some() {
+            continue;
+        }
+        if let Ok(raw_key) = wrapping.open_in_place(
+            aead::Nonce::assume_unique_for_key([0; 12]),
+            aead::Aad::from(&raw_header),
+            &mut wrapped[..],
+        ) {
+            key = Some(content_key(raw_key)?);
+        }
+    }
+    let key = key.ok_or(StreamError::NotARecipient)?;
+    Ok(Preamble { header, key, raw })
+}
+
+/// Pass `err` through `std::io`, `From<std::io::Error>` restores it
+fn io_error(err: StreamError) -> std::io::Error {
+    match err {
+        StreamError::Io(err) => err,
+        err => std::io::Error::new(std::io::ErrorKind::InvalidData, err.compat()),
+    }
+}
+
+/// Encrypts everything written to it into a stream on the inner writer.
+/// Plaintext is buffered until a chunk is full. `finish` must be called
+/// to write the final chunk, a stream without it is detected as truncated.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    key: aead::LessSafeKey,
+    chunk_size: usize,
+    buffer: Zeroizing<Vec<u8>>,
+    nonces: NonceSequence,
+    /// Additional data of the first chunk, taken when it is written
+    preamble: Option<Vec<u8>>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Write the stream header for `recipients` to `inner`
+    pub fn new(recipients: &[&AlphaPublic], mut inner: W) -> Result<Self, StreamError> {
+        let preamble = write_preamble(recipients, &mut inner)?;
+        let chunk_size = preamble.header.chunk_size as usize;
+        Ok(Self {
+            inner,
+            key: preamble.key,
+            chunk_size,
+            buffer: Zeroizing::new(Vec::with_capacity(chunk_size)),
+            nonces: chunk_nonces(),
+            preamble: Some(preamble.raw),
+        })
+    }
+
+    fn write_chunk(&mut self, len: usize, last: bool) -> Result<(), StreamError> {
+        let mut nonce = self.nonces.advance()?;
+        nonce[0] = last as u8;
+        let mut in_out: Vec<u8> = self.buffer.drain(..len).collect();
+        let aad = self.preamble.take().unwrap_or_default();
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(aad),
+                &mut in_out,
+            )
+            .map_err(|_| StreamError::Encryption)?;
+        self.inner.write_all(&in_out)?;
+        Ok(())
+    }
+
+    /// Write the final chunk and return the inner writer
+    pub fn finish(mut self) -> Result<W, StreamError> {
+        let len = self.buffer.len();
+        self.write_chunk(len, true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        // The final chunk must be shorter than a full chunk, so full
+        // chunks can always be written right away
+        if self.buffer.len() == self.chunk_size {
+            self.write_chunk(self.chunk_size, false).map_err(io_error)?;
+        }
+        Ok(len)
+    }
+
+    /// Flushes the inner writer, buffered plaintext is kept until its
+    /// chunk is full
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream from the inner reader.
+/// Plaintext is returned chunk by chunk as soon as each chunk is
+/// authenticated. Reading fails with `InvalidData` if a chunk is corrupted
+/// or the stream is truncated.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    key: aead::LessSafeKey,
+    chunk: Zeroizing<Vec<u8>>,
+    start: usize,
+    end: usize,
+    counter: u64,
+    done: bool,
+    /// Additional data of the first chunk, taken when it is read
+    preamble: Option<Vec<u8>>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Read the stream header from `inner` and unwrap the key for `secret`
+    pub fn new(secret: &AlphaSecret, mut inner: R) -> Result<Self, StreamError> {
+        let preamble = read_preamble(secret, &mut inner)?;
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        Ok(Self {
+            inner,
+            key: preamble.key,
+            chunk: Zeroizing::new(vec![0; preamble.header.chunk_size as usize + tag_len]),
+            start: 0,
+            end: 0,
+            counter: 0,
+            done: false,
+            preamble: Some(preamble.raw),
+        })
+    }
+
+    fn read_chunk(&mut self) -> Result<(), StreamError> {
+        let len = read_full(&mut self.inner, &mut self.chunk)?;
+        let last = len < self.chunk.len();
+        let counter = self.counter;
+        let aad = self.preamble.take().unwrap_or_default();
+        let plain = self
+            .key
+            .open_in_place(
+                chunk_nonce(counter, last),
+                aead::Aad::from(aad),
+                &mut self.chunk[..len],
+            )
+            .map_err(|_| StreamError::Decryption(counter))?;
+        self.start = 0;
+        self.end = plain.len();
+        self.counter += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.start == self.end && !self.done {
+            self.read_chunk().map_err(io_error)?;
+        }
+        let len = buf.len().min(self.end - self.start);
+        buf[..len].copy_from_slice(&self.chunk[self.start..self.start + len]);
+        self.start += len;
+        Ok(len)
+    }
+}
+
+/// Encrypt everything from `reader` once with a random content key, which
+/// is wrapped for each of `recipients`, and write the stream to `writer`.
+/// The sender is anonymous, recipients can not tell who encrypted the stream.
+pub fn encrypt_stream_to_many(
+    recipients: &[&AlphaPublic],
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+) -> Result<(), StreamError> {
+    let mut encrypt = EncryptWriter::new(recipients, writer)?;
+    std::io::copy(reader, &mut encrypt)?;
+    encrypt.finish()?;
+    Ok(())
+}
+
+/// Decrypt a stream written by `encrypt_stream_to_many` with `secret`
+/// and write the plaintext to `writer`.
+/// Plaintext is written chunk by chunk as soon as each chunk is
+/// authenticated, so on error `writer` may already hold a prefix.
+pub fn decrypt_stream_from_many(
+    secret: &AlphaSecret,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+) -> Result<(), StreamError> {
+    let mut decrypt = DecryptReader::new(secret, reader)?;
+    std::io::copy(&mut decrypt, writer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> StreamHeader {
+        StreamHeader {
+            algorithm: StreamAlgorithm::Alpha,
+            chunk_size: 64 * 1024,
+            ephemeral_pubkey: [0x42; 32],
+        }
+    }
+
+    #[test]
+    fn write_and_read_header() {
+        let mut raw = Vec::new();
+        header().write(&mut raw).unwrap();
+        assert_eq!(raw.len(), StreamHeader::LEN);
+        assert_eq!(&raw[6..10], &[0x00, 0x00, 0x01, 0x00]);
+        let parsed = StreamHeader::read(&mut raw.as_slice()).unwrap();
+        assert_eq!(parsed, header());
+    }
+
+    #[test]
+    fn reject_oversized_chunk_size() {
+        let mut raw = Vec::new();
+        header().write(&mut raw).unwrap();
+        raw[6..10].copy_from_slice(&(MAX_CHUNK_SIZE + 1).to_le_bytes());
+        match StreamHeader::read(&mut raw.as_slice()) {
+            Err(StreamError::InvalidChunkSize(size)) => assert_eq!(size, MAX_CHUNK_SIZE + 1),
+            _ => panic!("oversized chunk size accepted"),
+        }
+    }
+
+    #[test]
+    fn reject_bad_magic_and_truncated() {
+        let mut raw = Vec::new();
+        header().write(&mut raw).unwrap();
+        assert!(StreamHeader::read(&mut &raw[..StreamHeader::LEN - 1]).is_err());
+        raw[0] ^= 1;
+        assert!(StreamHeader::read(&mut raw.as_slice()).is_err());
+    }
+
+    fn recipients() -> Vec<AlphaSecret> {
+        (0..3).map(|_| AlphaSecret::new()).collect()
+    }
+
+    #[test]
+    fn detect_truncated_stream() {
+        let secrets = recipients();
+        let publics: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        // Exactly one full chunk, followed by an empty final chunk
+        let plain = vec![0x42; DEFAULT_CHUNK_SIZE as usize];
+        let mut crypted = Vec::new();
+        encrypt_stream_to_many(&publics, &mut plain.as_slice(), &mut crypted).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_stream_from_many(&secrets[0], &mut crypted.as_slice(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, plain);
+        // Cut off the final chunk
+        let truncated = &crypted[..crypted.len() - 16];
+        assert!(
+            decrypt_stream_from_many(&secrets[0], &mut &truncated[..], &mut Vec::new()).is_err()
+        );
+    }
+
+    #[test]
+    fn detect_appended_data() {
+        let secrets = recipients();
+        let publics: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        let plain = vec![0x42; DEFAULT_CHUNK_SIZE as usize + 1000];
+        let mut crypted = Vec::new();
+        encrypt_stream_to_many(&publics, &mut plain.as_slice(), &mut crypted).unwrap();
+        crypted.push(0);
+        match decrypt_stream_from_many(&secrets[0], &mut crypted.as_slice(), &mut Vec::new()) {
+            Err(StreamError::Decryption(1)) => {}
+            _ => panic!("appended data accepted"),
+        }
+        let mut reader = DecryptReader::new(&secrets[0], crypted.as_slice()).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn detect_changed_recipients() {
+        let secrets = recipients();
+        let publics: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        let plain = vec![0x42; 1000];
+        let mut crypted = Vec::new();
+        encrypt_stream_to_many(&publics, &mut plain.as_slice(), &mut crypted).unwrap();
+        let slots = StreamHeader::LEN + 4;
+        let decrypt = |crypted: &[u8]| {
+            decrypt_stream_from_many(&secrets[0], &mut &crypted[..], &mut Vec::new())
+        };
+
+        // Last recipient removed, with the count adjusted
+        let mut removed = crypted.clone();
+        removed[StreamHeader::LEN..slots].copy_from_slice(&2u32.to_le_bytes());
+        removed.drain(slots + 2 * WRAPPED_KEY_LEN..slots + 3 * WRAPPED_KEY_LEN);
+        match decrypt(&removed) {
+            Err(StreamError::Decryption(0)) => {}
+            _ => panic!("removed recipient accepted"),
+        }
+
+        // Slot of another recipient replaced
+        let mut replaced = crypted.clone();
+        replaced[slots + WRAPPED_KEY_LEN] ^= 1;
+        match decrypt(&replaced) {
+            Err(StreamError::Decryption(0)) => {}
+            _ => panic!("changed slot accepted"),
+        }
+
+        // Slot appended, with the count adjusted
+        let mut added = crypted.clone();
+        added[StreamHeader::LEN..slots].copy_from_slice(&4u32.to_le_bytes());
+        added.splice(
+            slots + 3 * WRAPPED_KEY_LEN..slots + 3 * WRAPPED_KEY_LEN,
+            vec![0; WRAPPED_KEY_LEN],
+        );
+        match decrypt(&added) {
+            Err(StreamError::Decryption(0)) => {}
+            _ => panic!("added recipient accepted"),
+        }
+        assert!(decrypt(&crypted).is_ok());
+    }
+
+    #[test]
+    fn pipe_through_writer_and_reader() {
+        let secrets = recipients();
+        let publics: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+        let mut encrypt = EncryptWriter::new(&publics, Vec::new()).unwrap();
+        let mut plain: Vec<u8> = Vec::new();
+        for i in 0..20_000 {
+            let line = format!("line {}\n", i);
+            encrypt.write_all(line.as_bytes()).unwrap();
+            plain.extend(line.as_bytes());
+        }
+        assert!(plain.len() > DEFAULT_CHUNK_SIZE as usize * 2);
+        let crypted = encrypt.finish().unwrap();
+
+        let mut decrypt = DecryptReader::new(&secrets[1], crypted.as_slice()).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plain);
+
+        // Without the final chunk, reading fails
+        let mut unfinished = EncryptWriter::new(&publics, Vec::new()).unwrap();
+        unfinished
+            .write_all(&vec![0x42; DEFAULT_CHUNK_SIZE as usize])
+            .unwrap();
+        let mut decrypt = DecryptReader::new(&secrets[0], unfinished.inner.as_slice()).unwrap();
+        assert!(decrypt.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn chunk_nonces_exhausted() {
+        let secret = AlphaSecret::new();
+        let mut encrypt = EncryptWriter::new(&[secret.public_key()], Vec::new()).unwrap();
+        encrypt.nonces = chunk_nonces().starting_at(u128::from(u64::MAX));
+        encrypt
+            .write_all(&vec![0x42; DEFAULT_CHUNK_SIZE as usize])
+            .unwrap();
+        match encrypt.finish() {
+            Err(StreamError::NonceExhausted) => {}
+            _ => panic!("chunk nonce reused"),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use ring::aead;
+
+use rand_core::{OsRng, RngCore};
+
+use failure::Fail;
+
+use crate::crypto::nonce::NonceSequence;
+use crate::crypto::shared::chacha20_key;
+use crate::crypto::SharedSecret;
+
+/// Length of the random nonce in front of each message
+pub const NONCE_LEN: usize = 12;
+
+/// Error type for symmetric encryption
+#[derive(Debug, Fail)]
+pub enum SymmetricError {
+    #[fail(display = "Message too short")]
+    TooShort,
+    #[fail(display = "Decryption failed")]
+    Decryption,
+    #[fail(display = "Encryption failed")]
+    Encryption,
+    #[fail(display = "Sequence numbers exhausted, a new key is required")]
+    NonceExhausted,
+}
+
+/// Encryption with a pre-shared symmetric key, without any key agreement.
+/// Only for systems which already did their own key exchange.
+/// Each message gets a random nonce, which is prepended to the ciphertext.
+/// Random 96 bit nonces should not be used for more than 2^32 messages
+/// with the same key.
+pub struct SymmetricContext {
+    key: aead::LessSafeKey,
+}
+
+impl SymmetricContext {
+    /// Constructs a context for the shared `key`
+    pub fn from_shared(key: [u8; 32]) -> Result<Self, SymmetricError> {
+        Ok(Self {
+            key: chacha20_key(&key).map_err(|_| SymmetricError::Encryption)?,
+        })
+    }
+
+    /// Encrypt `plain_bytes` with a fresh random nonce
+    pub fn encrypt(&self, plain_bytes: &[u8]) -> Result<Vec<u8>, SymmetricError> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut in_out = Vec::from(plain_bytes);
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut in_out,
+            )
+            .map_err(|_| SymmetricError::Encryption)?;
+        let mut out = Vec::from(&nonce[..]);
+        out.extend(in_out);
+        Ok(out)
+    }
+
+    /// Decrypt a message produced by `encrypt`
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, SymmetricError> {
+        if bytes.len() < NONCE_LEN + aead::CHACHA20_POLY1305.tag_len() {
+            return Err(SymmetricError::TooShort);
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&bytes[..NONCE_LEN]);
+        let mut in_out = Vec::from(&bytes[NONCE_LEN..]);
+        let plain = self
+            .key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut in_out,
+            )
+            .map_err(|_| SymmetricError::Decryption)?;
+        Ok(Vec::from(plain))
+    }
+}
+
+/// Role of a party in a session, each role sends with its own key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionRole {
+    /// The party which opened the session
+    Initiator,
+    /// The party which accepted the session
+    Responder,
+}
+
+impl SessionRole {
+    /// HKDF label of the key this role sends with
+    fn send_label(self) -> &'static [u8] {
+        match self {
+            SessionRole::Initiator => b"stackment session initiator",
+            SessionRole::Responder => b"stackment session responder",
+        }
+    }
+
+    /// Role of the other party
+    fn peer(self) -> Self {
+        match self {
+            SessionRole::Initiator => SessionRole::Responder,
+            SessionRole::Responder => SessionRole::Initiator,
+        }
+    }
+}
+
+/// Keys for an ordered session over a shared secret.
+/// Each direction has its own key, so both parties can send message
+/// number N without reusing a nonce. Each message is bound to its
+/// sequence number, which is used as nonce and authenticated as
+/// additional data, so reordered or replayed messages fail to decrypt.
+/// Messages are only encrypted with `encrypt_next`, which takes the
+/// sequence numbers from a counter, so none is used twice.
+pub struct SessionKey {
+    send: aead::LessSafeKey,
+    receive: aead::LessSafeKey,
+    /// Sequence numbers of the sending direction
+    sent: NonceSequence,
+}
+
+impl SessionKey {
+    /// Derive the session keys from `shared` for the party in `role`.
+    /// The other party must use the opposite role.
+    pub fn from_shared(shared: &SharedSecret, role: SessionRole) -> Result<Self, SymmetricError> {
+        Ok(Self {
+            send: session_key(shared, role)?,
+            receive: session_key(shared, role.peer())?,
+            sent: NonceSequence::with_max(u128::from(u64::MAX)),
+        })
+    }
+
+    /// Encrypt `plain_bytes` as the next message to the other party,
+    /// returns its sequence number and the ciphertext.
+    /// The sequence counts only the messages sent by this party, it is
+    /// unique because the sending key is not shared with the other
+    /// direction. Fails with `NonceExhausted` after 2^64 messages, instead
+    /// of reusing a sequence number.
+    pub fn encrypt_next(&mut self, plain_bytes: &[u8]) -> Result<(u64, Vec<u8>), SymmetricError> {
+        let seq = self
+            .sent
+            .next_counter()
+            .map_err(|_| SymmetricError::NonceExhausted)? as u64;
+        Ok((seq, self.encrypt_seq(plain_bytes, seq)?))
+    }
+
+    /// Encrypt `plain_bytes` as message number `seq` to the other party.
+    /// Private, a sequence number must only be taken from `sent`.
+    fn encrypt_seq(&self, plain_bytes: &[u8], seq: u64) -> Result<Vec<u8>, SymmetricError> {
+        let mut in_out = Vec::from(plain_bytes);
+        self.send
+            .seal_in_place_append_tag(
+                seq_nonce(seq),
+                aead::Aad::from(seq.to_be_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| SymmetricError::Encryption)?;
+        Ok(in_out)
+    }
+
+    /// Decrypt a message from the other party, which must be message
+    /// number `expected_seq`
+    pub fn decrypt_seq(&self, bytes: &[u8], expected_seq: u64) -> Result<Vec<u8>, SymmetricError> {
+        let mut in_out = Vec::from(bytes);
+        let plain = self
+            .receive
+            .open_in_place(
+                seq_nonce(expected_seq),
+                aead::Aad::from(expected_seq.to_be_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| SymmetricError::Decryption)?;
+        Ok(Vec::from(plain))
+    }
+}
+
+/// Derive the key `role` sends with
+fn session_key(
+    shared: &SharedSecret,
+    role: SessionRole,
+) -> Result<aead::LessSafeKey, SymmetricError> {
+    let key = shared.derive_subkey(role.send_label(), 32);
+    chacha20_key(&key).map_err(|_| SymmetricError::Encryption)
+}
+
+fn seq_nonce(seq: u64) -> aead::Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[4..].copy_from_slice(&seq.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let ctx = SymmetricContext::from_shared([0x42; 32]).unwrap();
+        let crypted = ctx.encrypt(b"pre-shared").unwrap();
+        assert_eq!(ctx.decrypt(&crypted).unwrap(), b"pre-shared");
+        assert!(SymmetricContext::from_shared([0x43; 32])
+            .unwrap()
+            .decrypt(&crypted)
+            .is_err());
+        assert!(ctx.decrypt(&crypted[..NONCE_LEN]).is_err());
+    }
+
+    /// Keys of both parties of a session over the same shared secret
+    fn session_pair() -> (SessionKey, SessionKey) {
+        let shared = SharedSecret::from([0x42; 32]);
+        (
+            SessionKey::from_shared(&shared, SessionRole::Initiator).unwrap(),
+            SessionKey::from_shared(&shared, SessionRole::Responder).unwrap(),
+        )
+    }
+
+    #[test]
+    fn session_in_order() {
+        let (mut sender, mut receiver) = session_pair();
+        for expected in 0..3 {
+            let (seq, crypted) = sender.encrypt_next(&[expected as u8; 4]).unwrap();
+            assert_eq!(seq, expected);
+            assert_eq!(receiver.decrypt_seq(&crypted, seq).unwrap(), [seq as u8; 4]);
+            let (seq, reply) = receiver.encrypt_next(&[expected as u8; 4]).unwrap();
+            assert_eq!(seq, expected);
+            assert_eq!(sender.decrypt_seq(&reply, seq).unwrap(), [seq as u8; 4]);
+        }
+    }
+
+    #[test]
+    fn session_directions_use_different_keys() {
+        let (mut initiator, mut responder) = session_pair();
+        let (seq_a, from_a) = initiator.encrypt_next(b"same message").unwrap();
+        let (seq_b, from_b) = responder.encrypt_next(b"same message").unwrap();
+        assert_eq!(seq_a, 0);
+        assert_eq!(seq_b, 0);
+        assert_ne!(from_a, from_b);
+        // A message is only accepted by the other party, not reflected
+        assert!(initiator.decrypt_seq(&from_a, 0).is_err());
+        assert_eq!(responder.decrypt_seq(&from_a, 0).unwrap(), b"same message");
+        assert_eq!(initiator.decrypt_seq(&from_b, 0).unwrap(), b"same message");
+    }
+
+    #[test]
+    fn session_rejects_reorder_and_replay() {
+        let (mut key, peer) = session_pair();
+        let (_, first) = key.encrypt_next(b"first").unwrap();
+        let (_, second) = key.encrypt_next(b"second").unwrap();
+        // Reordered
+        assert!(peer.decrypt_seq(&second, 0).is_err());
+        assert!(peer.decrypt_seq(&first, 0).is_ok());
+        // Replayed
+        assert!(peer.decrypt_seq(&first, 1).is_err());
+        assert!(peer.decrypt_seq(&second, 1).is_ok());
+    }
+
+    #[test]
+    fn session_sequence_exhausted() {
+        let (mut sender, receiver) = session_pair();
+        for expected in 0..2 {
+            let (seq, crypted) = sender.encrypt_next(b"next").unwrap();
+            assert_eq!(seq, expected);
+            assert_eq!(receiver.decrypt_seq(&crypted, seq).unwrap(), b"next");
+        }
+        sender.sent =
+            NonceSequence::with_max(u128::from(u64::MAX)).starting_at(u128::from(u64::MAX));
+        let (seq, _) = sender.encrypt_next(b"last").unwrap();
+        assert_eq!(seq, u64::MAX);
+        match sender.encrypt_next(b"wrapped") {
+            Err(SymmetricError::NonceExhausted) => {}
+            _ => panic!("sequence number reused"),
+        }
+    }
+
+    #[test]
+    fn unique_nonces() {
+        let ctx = SymmetricContext::from_shared([0x42; 32]).unwrap();
+        let nonces: std::collections::HashSet<Vec<u8>> = (0..100)
+            .map(|_| ctx.encrypt(b"same").unwrap()[..NONCE_LEN].to_vec())
+            .collect();
+        assert_eq!(nonces.len(), 100);
+    }
+}
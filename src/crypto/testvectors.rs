@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Interoperability test vectors for the alpha variant.
+//! Reimplementations can use these to verify they are compatible with
+//! the wire formats of this crate. The vectors are shipped in
+//! `tests/vectors.json`.
+
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::alpha::AlphaSecret;
+use crate::crypto::{Encrypted, Error, ParseOptions, Result, Secret, MAX_PLAINTEXT};
+
+/// The raw JSON test vectors
+pub const VECTORS_JSON: &str = include_str!("../../tests/vectors.json");
+
+/// A single test vector, all binary values are lowercase hex.
+/// The ciphertext is encrypted to the key of the vector itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVector {
+    pub name: String,
+    pub ed25519_seed: String,
+    pub x25519_secret: String,
+    pub serialized_secret: String,
+    pub message: String,
+    pub signature: String,
+    pub ephemeral_secret: String,
+    pub ciphertext: String,
+}
+
+/// RNG returning fixed bytes, so the ephemeral key of an encryption
+/// can be chosen. Never use this outside of test vectors.
+pub(crate) struct FixedRng {
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl RngCore for FixedRng {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        assert!(dest.len() <= self.bytes.len(), "FixedRng exhausted");
+        let rest = self.bytes.split_off(dest.len());
+        dest.copy_from_slice(&self.bytes);
+        self.bytes = rest;
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for FixedRng {}
+
+fn hex(bytes: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(bytes)
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    data_encoding::HEXLOWER
+        .decode(s.as_bytes())
+        .map_err(|e| Error::NotValid(format!("{}", e)))
+}
+
+fn key32(s: &str) -> Result<[u8; 32]> {
+    let raw = unhex(s)?;
+    if raw.len() != 32 {
+        return Err(Error::NotValid(String::from("Expected 32 bytes")));
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&raw);
+    Ok(key)
+}
+
+/// Returns all shipped test vectors
+pub fn vectors() -> Vec<TestVector> {
+    serde_json::from_str(VECTORS_JSON).expect("Invalid test vector file")
+}
+
+/// Compute a test vector from the given inputs
+pub fn generate_vector(
+    name: &str,
+    ed25519_seed: [u8; 32],
+    x25519_secret: [u8; 32],
+    ephemeral_secret: [u8; 32],
+    message: &[u8],
+) -> TestVector {
+    let secret = AlphaSecret::from_parts(ed25519_seed, x25519_secret);
+    let mut serialized_secret = Vec::new();
+    secret.serialize(&mut serialized_secret);
+    let mut rng = FixedRng {
+        bytes: Vec::from(&ephemeral_secret[..]),
+    };
+    let mut ciphertext = Vec::new();
+    secret
+        .encrypt_with_rng(message, secret.public_key(), None, MAX_PLAINTEXT, &mut rng)
+        .unwrap()
+        .serialize(&mut ciphertext);
+    TestVector {
+        name: String::from(name),
+        ed25519_seed: hex(&ed25519_seed),
+        x25519_secret: hex(&x25519_secret),
+        serialized_secret: hex(&serialized_secret),
+        message: hex(message),
+        signature: hex(secret.sign(&message).as_ref()),
+        ephemeral_secret: hex(&ephemeral_secret),
+        ciphertext: hex(&ciphertext),
+    }
+}
+
+/// Check that the current code reproduces `vector` exactly and that
+/// the shipped ciphertext decrypts to the message.
+pub fn check_vector(vector: &TestVector) -> Result<()> {
+    let expected = generate_vector(
+        &vector.name,
+        key32(&vector.ed25519_seed)?,
+        key32(&vector.x25519_secret)?,
+        key32(&vector.ephemeral_secret)?,
+        &unhex(&vector.message)?,
+    );
+    let mismatch = |field: &str| Error::NotValid(format!("{}: {} differs", vector.name, field));
+    if expected.serialized_secret != vector.serialized_secret {
+        return Err(mismatch("serialized_secret"));
+    }
+    if expected.signature != vector.signature {
+        return Err(mismatch("signature"));
+    }
+    if expected.ciphertext != vector.ciphertext {
+        return Err(mismatch("ciphertext"));
+    }
+    let secret =
+        AlphaSecret::deserialize(&unhex(&vector.serialized_secret)?, ParseOptions::strict())?;
+    let encrypted = Encrypted::deserialize(&unhex(&vector.ciphertext)?, ParseOptions::strict())?;
+    if secret.decrypt(&encrypted)? != unhex(&vector.message)? {
+        return Err(mismatch("decrypted message"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproduce_all_vectors() {
+        let vectors = vectors();
+        assert!(!vectors.is_empty());
+        for vector in &vectors {
+            check_vector(vector).unwrap();
+        }
+    }
+
+    #[test]
+    fn detect_modified_vector() {
+        let mut vector = vectors().remove(0);
+        vector.message = String::from("00");
+        assert!(check_vector(&vector).is_err());
+    }
+
+    /// `serialized_secret` of the first vector before version 3 added the
+    /// checksum, secrets stored back then must still load
+    const SERIALIZED_SECRET_V1: &str = "308196020600fe73ba20030201010201010420010101010101010101010101010101010101010101010101010101010101010104208a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c042000020202020202020202020202020202020202020202020202020202020202420420ce8d3ad1ccb633ec7b70c17814a5c76ecd029685050d344745ba05870e587d59";
+
+    #[test]
+    fn restore_version_1_vector() {
+        let vector = vectors().remove(0);
+        let v1 = AlphaSecret::deserialize(
+            &unhex(SERIALIZED_SECRET_V1).unwrap(),
+            ParseOptions::strict(),
+        )
+        .unwrap();
+        let v3 = AlphaSecret::deserialize(
+            &unhex(&vector.serialized_secret).unwrap(),
+            ParseOptions::strict(),
+        )
+        .unwrap();
+        assert_eq!(v1.public_key().fingerprint(), v3.public_key().fingerprint());
+        let mut raw = Vec::new();
+        v1.serialize(&mut raw);
+        assert_eq!(hex(&raw), vector.serialized_secret);
+    }
+}
@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::crypto::alpha::AlphaPublic;
+use crate::crypto::parse::parse_with_options;
+use crate::crypto::{Fingerprint, ParseError, ParseOptions};
+
+/// Result of observing a key for an identity in a `TofuStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TofuStatus {
+    /// The identity was not known, the key is now recorded
+    New,
+    /// The identity is known with the same key
+    Seen,
+    /// The identity is known with another key, possibly a MITM attack.
+    /// The recorded key is kept until the change is accepted.
+    Changed { old: Fingerprint, new: Fingerprint },
+}
+
+/// Trust on first use: records the first key seen for each identity
+/// and detects later changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TofuStore {
+    keys: BTreeMap<String, Fingerprint>,
+}
+
+impl TofuStore {
+    /// Constructs an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check `key` for `identity`. The key is recorded if the identity
+    /// is new, a changed key is reported but not recorded.
+    pub fn observe(&mut self, identity: &str, key: &AlphaPublic) -> TofuStatus {
+        let new = key.fingerprint();
+        match self.keys.get(identity) {
+            None => {
+                self.keys.insert(String::from(identity), new);
+                TofuStatus::New
+            }
+            Some(old) if *old == new => TofuStatus::Seen,
+            Some(old) => TofuStatus::Changed { old: *old, new },
+        }
+    }
+
+    /// Record `key` for `identity`, e.g. after the user confirmed a change
+    pub fn accept(&mut self, identity: &str, key: &AlphaPublic) {
+        self.keys.insert(String::from(identity), key.fingerprint());
+    }
+
+    /// Returns the recorded key fingerprint for `identity`
+    pub fn get(&self, identity: &str) -> Option<&Fingerprint> {
+        self.keys.get(identity)
+    }
+
+    /// Serialize the store as ASN.1 to `stream`:
+    /// SEQUENCE OF SEQUENCE { identity UTF8String, fingerprint OCTET STRING }
+    /// The fingerprint is stored with its algorithm tag, see
+    /// `Fingerprint::to_bytes`.
+    pub fn serialize(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let raw_bytes = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                for (identity, fingerprint) in &self.keys {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_utf8string(identity);
+                        writer.next().write_bytes(&fingerprint.to_bytes());
+                    });
+                }
+            });
+        });
+        stream.write_all(&raw_bytes)
+    }
+
+    /// Restore a store written by `serialize`.
+    /// Untagged 32 byte fingerprints of older stores are read as SHA256.
+    pub fn deserialize(bytes: &[u8], options: ParseOptions) -> Result<Self, ParseError> {
+        let entries = parse_with_options(bytes, options, |reader| {
+            reader.read_sequence(|reader| {
+                let mut entries = Vec::new();
+                while let Some(entry) = reader.read_optional(|reader| {
+                    reader.read_sequence(|reader| {
+                        let identity = reader.next().read_utf8string()?;
+                        let fingerprint = reader.next().read_bytes()?;
+                        Ok((identity, fingerprint))
+                    })
+                })? {
+                    entries.push(entry);
+                }
+                Ok(entries)
+            })
+        })?;
+        let mut keys = BTreeMap::new();
+        for (identity, raw) in entries {
+            let fingerprint = if raw.len() == 32 {
+                let mut inner = [0; 32];
+                inner.copy_from_slice(&raw);
+                Fingerprint::sha256(inner)
+            } else {
+                Fingerprint::from_bytes(&raw)?
+            };
+            keys.insert(identity, fingerprint);
+        }
+        Ok(Self { keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::AlphaSecret;
+    use crate::crypto::FingerprintAlgorithm;
+
+    #[test]
+    fn first_use_and_reuse() {
+        let mut store = TofuStore::new();
+        let alice = AlphaSecret::new();
+        assert_eq!(store.observe("alice", alice.public_key()), TofuStatus::New);
+        assert_eq!(store.observe("alice", alice.public_key()), TofuStatus::Seen);
+        let bob = AlphaSecret::new();
+        assert_eq!(store.observe("bob", bob.public_key()), TofuStatus::New);
+    }
+
+    #[test]
+    fn alert_on_changed_key() {
+        let mut store = TofuStore::new();
+        let alice = AlphaSecret::new();
+        let mallory = AlphaSecret::new();
+        store.observe("alice", alice.public_key());
+        let changed = TofuStatus::Changed {
+            old: alice.public_key().fingerprint(),
+            new: mallory.public_key().fingerprint(),
+        };
+        assert_eq!(store.observe("alice", mallory.public_key()), changed);
+        // Still reported until the change is accepted
+        assert_eq!(store.observe("alice", mallory.public_key()), changed);
+        store.accept("alice", mallory.public_key());
+        assert_eq!(
+            store.observe("alice", mallory.public_key()),
+            TofuStatus::Seen
+        );
+    }
+
+    #[test]
+    fn persist_store() {
+        let mut store = TofuStore::new();
+        store.observe("alice", AlphaSecret::new().public_key());
+        store.observe("bob", AlphaSecret::new().public_key());
+        let mut raw = Vec::new();
+        store.serialize(&mut raw).unwrap();
+        let restored = TofuStore::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(restored, store);
+        assert!(TofuStore::deserialize(&raw[..raw.len() - 1], ParseOptions::strict()).is_err());
+    }
+
+    #[test]
+    fn persist_fingerprint_algorithm() {
+        let mut store = TofuStore::new();
+        let fingerprint = Fingerprint::compute(FingerprintAlgorithm::Sha512_256, b"key");
+        store.keys.insert(String::from("alice"), fingerprint);
+        let mut raw = Vec::new();
+        store.serialize(&mut raw).unwrap();
+        let restored = TofuStore::deserialize(&raw, ParseOptions::strict()).unwrap();
+        assert_eq!(restored.get("alice"), Some(&fingerprint));
+
+        // Stores written before the algorithm tag was kept
+        let legacy = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_utf8string("bob");
+                    writer.next().write_bytes(&[0x42; 32]);
+                });
+            });
+        });
+        let restored = TofuStore::deserialize(&legacy, ParseOptions::strict()).unwrap();
+        assert_eq!(restored.get("bob"), Some(&Fingerprint::sha256([0x42; 32])));
+
+        let mut unknown = fingerprint.to_bytes();
+        unknown[0] = 0xff;
+        let invalid = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_utf8string("carol");
+                    writer.next().write_bytes(&unknown);
+                });
+            });
+        });
+        assert!(TofuStore::deserialize(&invalid, ParseOptions::strict()).is_err());
+    }
+}
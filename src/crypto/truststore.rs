@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+use std::collections::HashMap;
+
+use ring::constant_time::verify_slices_are_equal;
+
+use crate::crypto::{Fingerprint, IdentCert, Trusted};
+
+/// Trusted identity certificates, looked up by fingerprint.
+pub struct TrustStore {
+    certs: HashMap<Fingerprint, IdentCert<Trusted>>,
+    constant_time: bool,
+}
+
+impl Default for TrustStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrustStore {
+    /// Constructs an empty trust store using hash lookups
+    pub fn new() -> Self {
+        Self {
+            certs: HashMap::new(),
+            constant_time: false,
+        }
+    }
+
+    /// Enable or disable constant-time lookups.
+    /// A hash lookup leaks through its timing whether a fingerprint is
+    /// present. In constant-time mode, `get` compares the fingerprint
+    /// against all entries instead, which is O(n) instead of O(1).
+    pub fn set_constant_time(&mut self, enabled: bool) {
+        self.constant_time = enabled;
+    }
+
+    /// Add a trusted certificate, replacing one with the same fingerprint
+    pub fn add(&mut self, cert: IdentCert<Trusted>) {
+        self.certs.insert(cert.fingerprint(), cert);
+    }
+
+    /// Number of trusted certificates
+    pub fn len(&self) -> usize {
+        self.certs.len()
+    }
+
+    /// Returns true if no certificate is trusted
+    pub fn is_empty(&self) -> bool {
+        self.certs.is_empty()
+    }
+
+    /// Returns the trusted certificate with `fingerprint`
+    pub fn get(&self, fingerprint: &Fingerprint) -> Option<&IdentCert<Trusted>> {
+        if !self.constant_time {
+            return self.certs.get(fingerprint);
+        }
+        let mut found = None;
+        for (candidate, cert) in &self.certs {
+            let equal = verify_slices_are_equal(&candidate.inner, &fingerprint.inner).is_ok();
+            found = found.or(if equal { Some(cert) } else { None });
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::alpha::{AlphaCert, AlphaSecret};
+
+    fn ident_cert() -> IdentCert<Trusted> {
+        let isec = AlphaSecret::new();
+        IdentCert::new(Box::new(AlphaCert::new(&isec, &isec, None))).into_trusted()
+    }
+
+    #[test]
+    fn lookup_modes_agree() {
+        let mut store = TrustStore::new();
+        let certs: Vec<IdentCert<Trusted>> = (0..4).map(|_| ident_cert()).collect();
+        let fingerprints: Vec<Fingerprint> = certs.iter().map(|c| c.fingerprint()).collect();
+        for cert in certs {
+            store.add(cert);
+        }
+        let unknown = ident_cert().fingerprint();
+        let lookup = |store: &TrustStore| -> Vec<Option<Fingerprint>> {
+            fingerprints
+                .iter()
+                .chain(Some(&unknown))
+                .map(|fp| store.get(fp).map(|cert| cert.fingerprint()))
+                .collect()
+        };
+        let hashed = lookup(&store);
+        store.set_constant_time(true);
+        assert_eq!(hashed, lookup(&store));
+        assert_eq!(hashed[0], Some(fingerprints[0]));
+        assert_eq!(hashed[4], None);
+    }
+}
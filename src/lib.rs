@@ -0,0 +1,10 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+// failure_derive emits its impls inside anonymous consts
+#![allow(non_local_definitions)]
+
+pub mod crypto;
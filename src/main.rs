@@ -4,31 +4,4 @@
  * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
  */
 
-//#![cfg_attr(debug_assertions, allow(dead_code))]
-#![cfg_attr(debug_assertions, allow(unused))]
-//#![cfg_attr(debug_assertions, allow(non_camel_case_types))]
-
-
-
-mod crypto;
-
-use std::ops::Deref;
-
-use std::fmt;
-use std::io::Read;
-use std::io::Write;
-
-use chrono::Utc;
-use ring::{self, signature::UnparsedPublicKey};
-use ring::{
-    digest, rand, signature,
-    signature::{Ed25519KeyPair, KeyPair, Signature},
-};
-use snow;
-use yasna::{self, models::GeneralizedTime, models::ObjectIdentifier, Tag};
-
-
-
-fn main() {
-
-}
+fn main() {}
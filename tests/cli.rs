@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Runs the `stkmnt` example as a separate process.
+//! `cargo test` builds the examples next to the test binaries.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn stkmnt() -> PathBuf {
+    let mut path = std::env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join("examples").join("stkmnt")
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(stkmnt())
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(dir: &Path, args: &[&str]) -> String {
+    let output = run(dir, args);
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn keygen_sign_verify_encrypt_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    stdout(dir, &["keygen", "alice.pem"]);
+    stdout(dir, &["keygen", "bob.pem"]);
+    assert!(fs::read_to_string(dir.join("alice.pem"))
+        .unwrap()
+        .starts_with("-----BEGIN STACKMENT SECRET KEY-----"));
+    fs::write(dir.join("alice.pub"), stdout(dir, &["pubkey", "alice.pem"])).unwrap();
+    fs::write(dir.join("bob.pub"), stdout(dir, &["pubkey", "bob.pem"])).unwrap();
+    fs::write(dir.join("message"), b"hello bob").unwrap();
+
+    let signature = stdout(dir, &["sign", "alice.pem", "message"]);
+    let signature = signature.trim();
+    assert_eq!(
+        stdout(dir, &["verify", "alice.pub", "message", signature]),
+        "OK\n"
+    );
+    assert!(!run(dir, &["verify", "bob.pub", "message", signature])
+        .status
+        .success());
+
+    let ciphertext = stdout(dir, &["encrypt", "alice.pem", "bob.pub", "message"]);
+    fs::write(dir.join("message.enc"), ciphertext).unwrap();
+    assert_eq!(
+        stdout(dir, &["decrypt", "bob.pem", "message.enc"]),
+        "hello bob"
+    );
+    assert!(!run(dir, &["decrypt", "alice.pem", "message.enc"])
+        .status
+        .success());
+    assert!(!run(dir, &["unknown"]).status.success());
+}
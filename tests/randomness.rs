@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Regression tests against deterministic ephemeral keys or nonces.
+//! Encrypting the same plaintext twice to the same recipient must never
+//! give the same result.
+
+use stackment_crypto_mod::crypto::alpha::AlphaSecret;
+use stackment_crypto_mod::crypto::Secret;
+
+#[test]
+fn same_plaintext_encrypts_differently() {
+    let sender = AlphaSecret::new();
+    let recipient = AlphaSecret::new();
+    let plain = b"the same plaintext";
+    let first = sender.encrypt(&plain, recipient.public_key()).unwrap();
+    let second = sender.encrypt(&plain, recipient.public_key()).unwrap();
+    assert_ne!(first.ephemeral_pubkey, second.ephemeral_pubkey);
+    assert_ne!(first.data, second.data);
+    assert_eq!(recipient.decrypt(&first).unwrap(), &plain[..]);
+    assert_eq!(recipient.decrypt(&second).unwrap(), &plain[..]);
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*
+ * Copyright (C) 2020 Daniel Vogelbacher
+ * Written by: Daniel Vogelbacher <daniel@chaospixel.com>
+ */
+
+//! Streams encrypted to several recipients through the public API.
+
+use std::io::Read;
+
+use stackment_crypto_mod::crypto::alpha::{AlphaPublic, AlphaSecret};
+use stackment_crypto_mod::crypto::stream::{
+    decrypt_stream_from_many, encrypt_stream_to_many, DEFAULT_CHUNK_SIZE,
+};
+use stackment_crypto_mod::crypto::{DecryptReader, StreamError};
+
+#[test]
+fn stream_to_many_recipients() {
+    let secrets: Vec<AlphaSecret> = (0..3).map(|_| AlphaSecret::new()).collect();
+    let publics: Vec<&AlphaPublic> = secrets.iter().map(|s| s.public_key()).collect();
+    // More than two chunks, the last one is partial
+    let plain: Vec<u8> = (0..DEFAULT_CHUNK_SIZE as usize * 2 + 1000)
+        .map(|i| i as u8)
+        .collect();
+    let mut crypted = Vec::new();
+    encrypt_stream_to_many(&publics, &mut plain.as_slice(), &mut crypted).unwrap();
+    for secret in &secrets {
+        let mut decrypted = Vec::new();
+        decrypt_stream_from_many(secret, &mut crypted.as_slice(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, plain);
+        let mut decrypted = Vec::new();
+        DecryptReader::new(secret, crypted.as_slice())
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plain);
+    }
+
+    let outsider = AlphaSecret::new();
+    match decrypt_stream_from_many(&outsider, &mut crypted.as_slice(), &mut Vec::new()) {
+        Err(StreamError::NotARecipient) => {}
+        _ => panic!("non-recipient decrypted the stream"),
+    }
+    assert!(DecryptReader::new(&outsider, crypted.as_slice()).is_err());
+}